@@ -0,0 +1,580 @@
+//! Authenticated HTTP API for inspecting & operating a running world, under `/admin`.
+//!
+//! Routes act on the default room, or on the named room given as a `room` query parameter, which
+//! gets loaded if it isn't; the tick rate is shared by every room. Edits made through here are
+//! journaled like brush strokes, so replaying the journal reproduces them.
+
+use anyhow::{Context, Result};
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, watch};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::journal::AdminEdit;
+use crate::message::{LatLong, Layer, Rect, Region, Resolution, Role, SnapshotEncoding};
+use crate::persistence::FreshWorld;
+use crate::room::{Membership, Rooms};
+use crate::scenario::Scenario;
+use crate::state::{self, units, WorldSnapshot, Zone, ZonePolicy};
+use crate::{auth, export, metrics};
+
+/// Largest request body accepted, in bytes.
+const MAX_BODY_BYTES: u64 = 16 * 1024;
+
+/// A request to save the world now, answered once the save is done.
+pub type SaveRequest = oneshot::Sender<Result<()>>;
+
+/// Handles onto the running server the admin API works with.
+pub struct Admin {
+    /// Rooms requests pick from.
+    pub rooms: Arc<Rooms>,
+
+    /// Checks the bearer token every request needs; the API is off without an admin token or key.
+    pub auth: Arc<auth::Auth>,
+
+    /// What reset regions are restored to.
+    pub fresh_world: FreshWorld,
+
+    pub metrics: metrics::Metrics,
+
+    /// Time between ticks, watched by the tick task.
+    pub tick_interval: watch::Sender<Duration>,
+}
+
+/// Rejection of a request without an admin token.
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Rejection of a request for a room that doesn't exist.
+#[derive(Debug)]
+struct UnknownRoom(String);
+
+impl warp::reject::Reject for UnknownRoom {}
+
+/// Rejection of a request for a room that couldn't be loaded.
+#[derive(Debug)]
+struct UnavailableRoom(String);
+
+impl warp::reject::Reject for UnavailableRoom {}
+
+/// Room a request acts on.
+#[derive(Deserialize)]
+struct RoomQuery {
+    /// Name of the room; the default room if missing.
+    room: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ClientInfo {
+    client_id: u64,
+    peer: Option<String>,
+    role: Role,
+    subject: Option<String>,
+    encoding: SnapshotEncoding,
+    viewports: Vec<ViewportInfo>,
+}
+
+#[derive(Serialize)]
+struct ViewportInfo {
+    viewport_id: u32,
+    area: Rect,
+    resolution: Option<Resolution>,
+    layers: Vec<Layer>,
+}
+
+#[derive(Deserialize)]
+struct LoadRequest {
+    /// Snapshot file on the server.
+    path: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct RegionRequest {
+    /// `north,west,south,east` in degrees.
+    area: String,
+
+    /// Layer names to change. Resets change all of them if empty, but fills need them since each
+    /// layer has its own unit.
+    #[serde(default)]
+    layers: Vec<String>,
+
+    #[serde(flatten)]
+    action: RegionAction,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum RegionAction {
+    /// Sets the layers to a value in their physical unit, see [`units`].
+    ///
+    /// Like in exports, `wind_y` is northward.
+    Fill { value: f64 },
+
+    /// Restores the layers to how they are in a fresh world.
+    Reset,
+}
+
+#[derive(Deserialize)]
+struct ZoneRequest {
+    /// `north,west,south,east` in degrees, for a rectangular zone.
+    area: Option<String>,
+
+    /// Corners of a polygonal zone, instead of `area`.
+    polygon: Option<Vec<LatLong>>,
+    policy: ZonePolicy,
+}
+
+#[derive(Deserialize)]
+struct ScenarioRequest {
+    /// Scenario file on the server.
+    path: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct TickRateRequest {
+    interval_ms: u64,
+}
+
+/// All admin routes.
+pub fn routes(admin: Admin) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let admin = Arc::new(admin);
+    let authorized = warp::header::optional::<String>("authorization")
+        .and(warp::any().map(move || admin.clone()))
+        .and_then(
+            |authorization: Option<String>, admin: Arc<Admin>| async move {
+                if admin.auth.is_admin(authorization.as_deref()) {
+                    Ok(admin)
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            },
+        );
+    let in_room = authorized
+        .clone()
+        .and(warp::query::<RoomQuery>())
+        .and_then(|admin: Arc<Admin>, query: RoomQuery| async move {
+            match admin.rooms.get(query.room.as_deref()).await {
+                Ok(Some(room)) => Ok((admin, room)),
+                Ok(None) => Err(warp::reject::custom(UnknownRoom(
+                    query.room.unwrap_or_default(),
+                ))),
+                Err(e) => Err(warp::reject::custom(UnavailableRoom(format!("{e:#}")))),
+            }
+        })
+        .untuple_one();
+    let room = in_room
+        .clone()
+        .map(|_admin: Arc<Admin>, room: Membership| room);
+
+    let list = warp::path!("clients")
+        .and(warp::get())
+        .and(room.clone())
+        .then(list_clients);
+    let kick = warp::path!("clients" / u64 / "kick")
+        .and(warp::post())
+        .and(in_room.clone())
+        .then(kick_client);
+    let save = warp::path!("save")
+        .and(warp::post())
+        .and(room.clone())
+        .then(save_now);
+    let load = warp::path!("load")
+        .and(warp::post())
+        .and(room.clone())
+        .and(json_body())
+        .then(load_snapshot);
+    let region = warp::path!("region")
+        .and(warp::post())
+        .and(in_room.clone())
+        .and(json_body())
+        .then(edit_region);
+    let list_zones = warp::path!("zones")
+        .and(warp::get())
+        .and(room.clone())
+        .then(list_zones);
+    let set_zone = warp::path!("zones" / String)
+        .and(warp::put())
+        .and(room.clone())
+        .and(json_body())
+        .then(set_zone);
+    let remove_zone = warp::path!("zones" / String)
+        .and(warp::delete())
+        .and(room.clone())
+        .then(remove_zone);
+    let play_scenario = warp::path!("scenario")
+        .and(warp::post())
+        .and(room.clone())
+        .and(json_body())
+        .then(play_scenario);
+    let stop_scenario = warp::path!("scenario")
+        .and(warp::delete())
+        .and(room.clone())
+        .then(stop_scenario);
+    let tick_rate = warp::path!("tick-rate")
+        .and(warp::put())
+        .and(authorized)
+        .and(json_body())
+        .then(set_tick_rate);
+
+    warp::path("admin")
+        .and(
+            list.or(kick)
+                .unify()
+                .or(save)
+                .unify()
+                .or(load)
+                .unify()
+                .or(region)
+                .unify()
+                .or(list_zones)
+                .unify()
+                .or(set_zone)
+                .unify()
+                .or(remove_zone)
+                .unify()
+                .or(play_scenario)
+                .unify()
+                .or(stop_scenario)
+                .unify()
+                .or(tick_rate)
+                .unify(),
+        )
+        .recover(unauthorized)
+        .unify()
+}
+
+/// JSON request body of at most [`MAX_BODY_BYTES`].
+fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+{
+    warp::body::content_length_limit(MAX_BODY_BYTES).and(warp::body::json())
+}
+
+/// Answers requests rejected for lacking a token or naming a missing room, passing other
+/// rejections on.
+async fn unauthorized(rejection: Rejection) -> Result<Response, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(warp::reply::with_status("unauthorized", StatusCode::UNAUTHORIZED).into_response())
+    } else if let Some(UnknownRoom(name)) = rejection.find() {
+        Ok(error(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("no room {name}"),
+        ))
+    } else if let Some(UnavailableRoom(reason)) = rejection.find() {
+        Ok(error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            anyhow::anyhow!("{reason}"),
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
+fn error(status: StatusCode, e: anyhow::Error) -> Response {
+    warp::reply::with_status(format!("{e:#}"), status).into_response()
+}
+
+async fn list_clients(room: Membership) -> Response {
+    let locked_state = room.state_shard.lock().await;
+
+    let clients: Vec<ClientInfo> = locked_state
+        .clients
+        .iter()
+        .map(|(client_id, client)| ClientInfo {
+            client_id: *client_id,
+            peer: client.peer.map(|peer| peer.to_string()),
+            role: client.role,
+            subject: client.subject.clone(),
+            encoding: client.encoding.clone(),
+            viewports: client
+                .viewports
+                .iter()
+                .map(|(viewport_id, view)| ViewportInfo {
+                    viewport_id: *viewport_id,
+                    area: view.area,
+                    resolution: view.resolution,
+                    layers: view.layers.clone(),
+                })
+                .collect(),
+        })
+        .collect();
+
+    warp::reply::json(&clients).into_response()
+}
+
+async fn kick_client(client_id: u64, admin: Arc<Admin>, room: Membership) -> Response {
+    let mut locked_state = room.state_shard.lock().await;
+
+    let Some(client) = locked_state.clients.remove(&client_id) else {
+        return error(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("no client {client_id}"),
+        );
+    };
+    locked_state.map.forget_client(client_id);
+    admin.metrics.client_disconnected();
+    // stop reading before the client can queue anything else, whether or not it heeds the close
+    let closing = client.close(1008, "kicked by an admin");
+
+    drop(locked_state);
+
+    closing.await;
+    info!("Kicked client {client_id} from room {}", room.name);
+
+    warp::reply().into_response()
+}
+
+async fn save_now(room: Membership) -> Response {
+    let (reply, saved) = oneshot::channel();
+    if room.save_requests.send(reply).await.is_err() {
+        return error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            anyhow::anyhow!("the save task isn't running"),
+        );
+    }
+
+    match saved.await {
+        Ok(Ok(())) => {
+            info!("Saved room {} on admin request", room.name);
+            warp::reply().into_response()
+        }
+        Ok(Err(e)) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(_) => error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            anyhow::anyhow!("the save task stopped before saving"),
+        ),
+    }
+}
+
+async fn load_snapshot(room: Membership, request: LoadRequest) -> Response {
+    let path = request.path;
+    let loaded = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || {
+            // replay may run from another directory
+            let path = path
+                .canonicalize()
+                .with_context(|| format!("finding {}", path.display()))?;
+            let snapshot = WorldSnapshot::load(&path)
+                .with_context(|| format!("loading {}", path.display()))?;
+            Ok((path, snapshot))
+        })
+        .await
+        .expect("snapshot loading task panicked")
+    };
+    let (path, snapshot) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+
+    let tick = snapshot.metadata.tick;
+    let mut locked_state = room.state_shard.lock().await;
+    locked_state.map.replace(snapshot);
+    locked_state.journal.record_admin(
+        tick,
+        AdminEdit::Load {
+            path: path.clone(),
+            snapshot_tick: tick,
+        },
+    );
+    drop(locked_state);
+    info!(
+        "Loaded snapshot {} at tick {tick} into room {}",
+        path.display(),
+        room.name
+    );
+
+    warp::reply::json(&tick).into_response()
+}
+
+async fn edit_region(admin: Arc<Admin>, room: Membership, request: RegionRequest) -> Response {
+    let parsed = export::parse_area(&request.area).and_then(|area| {
+        let layers = if request.layers.is_empty() {
+            if let RegionAction::Fill { .. } = request.action {
+                anyhow::bail!("fills need layers, since the value is in the unit of each layer");
+            }
+            vec![Layer::Temperature, Layer::WindX, Layer::WindY, Layer::Haze]
+        } else {
+            request
+                .layers
+                .iter()
+                .map(|name| export::parse_layer(name))
+                .collect::<Result<_>>()?
+        };
+        Ok((area, layers))
+    });
+    let (area, layers) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+
+    let written = match request.action {
+        RegionAction::Fill { value } => {
+            let raw = |layer: Layer| {
+                let sign = if layer == Layer::WindY { -1. } else { 1. };
+                units::to_raw(layer, sign * value)
+            };
+
+            let mut locked_state = room.state_shard.lock().await;
+            let written = locked_state
+                .map
+                .write_region(area, &layers, |layer, _| raw(layer));
+            if written.is_ok() {
+                let tick = locked_state.map.tick();
+                let values = layers.iter().map(|&layer| (layer, raw(layer))).collect();
+                locked_state
+                    .journal
+                    .record_admin(tick, AdminEdit::Fill { area, values });
+            }
+            written
+        }
+        RegionAction::Reset => {
+            let fresh_world = admin.fresh_world.clone();
+            let fresh = match tokio::task::spawn_blocking(move || fresh_world.create())
+                .await
+                .expect("fresh world task panicked")
+            {
+                Ok(fresh) => fresh,
+                Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e),
+            };
+
+            let mut locked_state = room.state_shard.lock().await;
+            let written = locked_state
+                .map
+                .write_region(area, &layers, |layer, pixel| {
+                    fresh.data[pixel * state::BYTES_PER_PIXEL + state::layer_offset(layer)]
+                });
+            if written.is_ok() {
+                let tick = locked_state.map.tick();
+                let edit = AdminEdit::Reset {
+                    area,
+                    layers,
+                    fresh_world: admin.fresh_world.clone(),
+                };
+                locked_state.journal.record_admin(tick, edit);
+            }
+            written
+        }
+    };
+
+    match written {
+        Ok(cells) => {
+            info!(
+                "Admin edit of {cells} cells in {area:?} of room {}",
+                room.name
+            );
+            warp::reply::json(&cells).into_response()
+        }
+        Err(e) => error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+async fn list_zones(room: Membership) -> Response {
+    let locked_state = room.state_shard.lock().await;
+    warp::reply::json(&locked_state.map.zones()).into_response()
+}
+
+async fn set_zone(name: String, room: Membership, request: ZoneRequest) -> Response {
+    let region = match (request.area, request.polygon) {
+        (Some(area), None) => match export::parse_area(&area) {
+            Ok(rect) => Region::Rect(rect),
+            Err(e) => return error(StatusCode::BAD_REQUEST, e),
+        },
+        (None, Some(corners)) => Region::Polygon(corners),
+        _ => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                anyhow::anyhow!("give exactly one of area or polygon"),
+            )
+        }
+    };
+    if let Err(e) = state::validate_region(&region) {
+        return error(StatusCode::BAD_REQUEST, e);
+    }
+
+    let zone = Zone {
+        name: name.clone(),
+        region,
+        policy: request.policy,
+    };
+    let mut locked_state = room.state_shard.lock().await;
+    let replaced = locked_state.map.set_zone(zone.clone());
+    let tick = locked_state.map.tick();
+    locked_state
+        .journal
+        .record_admin(tick, AdminEdit::SetZone(zone));
+    drop(locked_state);
+    info!(
+        "{} zone {name} in room {}",
+        if replaced { "Replaced" } else { "Added" },
+        room.name
+    );
+
+    warp::reply().into_response()
+}
+
+async fn remove_zone(name: String, room: Membership) -> Response {
+    let mut locked_state = room.state_shard.lock().await;
+    if !locked_state.map.remove_zone(&name) {
+        return error(StatusCode::NOT_FOUND, anyhow::anyhow!("no zone {name}"));
+    }
+    let tick = locked_state.map.tick();
+    locked_state
+        .journal
+        .record_admin(tick, AdminEdit::RemoveZone { name: name.clone() });
+    drop(locked_state);
+    info!("Removed zone {name} from room {}", room.name);
+
+    warp::reply().into_response()
+}
+
+async fn play_scenario(room: Membership, request: ScenarioRequest) -> Response {
+    let scenario = match Scenario::load(&request.path).await {
+        Ok(scenario) => scenario,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+
+    info!(
+        "Playing scenario {} in room {}",
+        request.path.display(),
+        room.name
+    );
+    room.play_scenario(scenario);
+
+    warp::reply().into_response()
+}
+
+async fn stop_scenario(room: Membership) -> Response {
+    if !room.stop_scenario() {
+        return error(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("no scenario is playing"),
+        );
+    }
+    info!("Stopped scenario in room {}", room.name);
+
+    warp::reply().into_response()
+}
+
+async fn set_tick_rate(admin: Arc<Admin>, request: TickRateRequest) -> Response {
+    if request.interval_ms == 0 {
+        return error(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("interval_ms must be above 0"),
+        );
+    }
+
+    let interval = Duration::from_millis(request.interval_ms);
+    admin.tick_interval.send_replace(interval);
+    admin.metrics.set_tick_interval(interval);
+    info!("Tick interval changed to {interval:?}");
+
+    warp::reply().into_response()
+}
@@ -0,0 +1,328 @@
+//! Checks credentials of websocket clients & privileged HTTP requests.
+//!
+//! Besides the static admin token, clients can present signed tokens carrying a [`Role`]. A token
+//! is `payload.signature`, both base64url without padding: the payload is JSON [`Claims`] and the
+//! signature is its HMAC-SHA256 under the server's key, kept in a local key file.
+
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::Path;
+
+use crate::message::Role;
+
+/// Bytes of key generated for new key files.
+const KEY_BYTES: usize = 32;
+
+/// Keys shorter than this are refused, as they'd be easy to guess.
+const MIN_KEY_BYTES: usize = 16;
+
+/// What a signed token grants.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Claims {
+    /// Who the token was issued to, for logs.
+    pub sub: String,
+    pub role: Role,
+
+    /// Unix time in seconds after which the token is refused; never if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+}
+
+/// Key that tokens are signed with.
+pub struct Key(Vec<u8>);
+
+/// Everything credentials are checked against.
+pub struct Auth {
+    /// Bearer token that unlocks privileged HTTP endpoints.
+    pub admin_token: Option<String>,
+
+    /// Key signed tokens are checked with; every client may paint without one.
+    pub key: Option<Key>,
+
+    /// Whether clients without a token may connect as viewers while tokens are required.
+    pub allow_anonymous: bool,
+}
+
+impl Key {
+    /// Reads a base64 key written by [`Key::generate`].
+    pub fn load(path: &Path) -> Result<Key> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading key file {}", path.display()))?;
+        let key = STANDARD
+            .decode(text.trim())
+            .with_context(|| format!("key file {} isn't base64", path.display()))?;
+        if key.len() < MIN_KEY_BYTES {
+            anyhow::bail!(
+                "key in {} is {} bytes, expected at least {MIN_KEY_BYTES}",
+                path.display(),
+                key.len()
+            );
+        }
+
+        Ok(Key(key))
+    }
+
+    /// Writes a new random key to `path`, refusing to replace an existing one since that would
+    /// invalidate every token issued with it.
+    pub fn generate(path: &Path) -> Result<()> {
+        let key: [u8; KEY_BYTES] = rand::random();
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        // only the server's user should be able to read it, or anyone could issue tokens
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(path)
+            .with_context(|| format!("creating key file {}", path.display()))?;
+        std::io::Write::write_all(&mut file, format!("{}\n", STANDARD.encode(key)).as_bytes())
+            .with_context(|| format!("writing key file {}", path.display()))?;
+
+        Ok(())
+    }
+
+    pub fn issue(&self, claims: &Claims) -> String {
+        let payload = URL_SAFE_NO_PAD
+            .encode(serde_json::to_vec(claims).expect("claims should serialize to JSON"));
+        let signature =
+            URL_SAFE_NO_PAD.encode(self.mac(payload.as_bytes()).finalize().into_bytes());
+
+        format!("{payload}.{signature}")
+    }
+
+    /// Checks a token's signature & expiry, returning what it grants.
+    pub fn verify(&self, token: &str) -> Result<Claims> {
+        let (payload, signature) = token
+            .trim()
+            .split_once('.')
+            .context("token isn't in payload.signature form")?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .context("token signature isn't base64url")?;
+        self.mac(payload.as_bytes())
+            .verify_slice(&signature)
+            .map_err(|_| anyhow::anyhow!("token signature doesn't match"))?;
+
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .context("token payload isn't base64url")?;
+        let claims: Claims =
+            serde_json::from_slice(&payload).context("token payload isn't valid claims")?;
+        if claims
+            .exp
+            .is_some_and(|exp| exp.saturating_mul(1000) < crate::state::now_ms())
+        {
+            anyhow::bail!("token of {} has expired", claims.sub);
+        }
+
+        Ok(claims)
+    }
+
+    /// HMAC-SHA256 of `message`, ready to finalize or verify.
+    fn mac(&self, message: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC should take keys of any length");
+        mac.update(message);
+        mac
+    }
+}
+
+impl Auth {
+    /// Whether an `Authorization` header carries the admin token or a signed token with the admin
+    /// role as a bearer token.
+    ///
+    /// Always false when neither is configured, so privileged endpoints are off by default.
+    pub fn is_admin(&self, authorization: Option<&str>) -> bool {
+        let Some(given) = authorization.and_then(|header| header.strip_prefix("Bearer ")) else {
+            return false;
+        };
+        let given = given.trim();
+
+        let static_token = self
+            .admin_token
+            .as_ref()
+            .is_some_and(|token| constant_time_eq(given.as_bytes(), token.as_bytes()));
+        let signed_token = self
+            .key
+            .as_ref()
+            .and_then(|key| key.verify(given).ok())
+            .is_some_and(|claims| claims.role == Role::Admin);
+
+        static_token || signed_token
+    }
+
+    /// Role a websocket client connecting with a token in its query or cookie gets, along with who
+    /// it was issued to. The query's token wins if there are both.
+    ///
+    /// Without a configured key there's nothing to check, so everyone may paint.
+    pub fn connection_role(
+        &self,
+        query_token: Option<&str>,
+        cookie_token: Option<&str>,
+    ) -> Result<(Role, Option<String>)> {
+        let Some(key) = &self.key else {
+            return Ok((Role::Painter, None));
+        };
+
+        match query_token.or(cookie_token) {
+            Some(token) => {
+                let claims = key.verify(token)?;
+                Ok((claims.role, Some(claims.sub)))
+            }
+            None if self.allow_anonymous => Ok((Role::Viewer, None)),
+            None => anyhow::bail!("a token is required"),
+        }
+    }
+}
+
+/// Parses a role name as given on the command line.
+pub fn parse_role(name: &str) -> Result<Role> {
+    match name.trim() {
+        "viewer" => Ok(Role::Viewer),
+        "painter" => Ok(Role::Painter),
+        "admin" => Ok(Role::Admin),
+        _ => anyhow::bail!("unknown role {name:?}; expected viewer, painter or admin"),
+    }
+}
+
+/// Compares without bailing at the first difference, so timing doesn't leak the token.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> Key {
+        Key(vec![7; KEY_BYTES])
+    }
+
+    fn claims(role: Role, exp: Option<u64>) -> Claims {
+        Claims {
+            sub: format!("{role:?}"),
+            role,
+            exp,
+        }
+    }
+
+    /// Auth checking tokens against [`key`], with an admin token if given.
+    fn auth(allow_anonymous: bool, admin_token: Option<&str>) -> Auth {
+        Auth {
+            admin_token: admin_token.map(str::to_owned),
+            key: Some(key()),
+            allow_anonymous,
+        }
+    }
+
+    #[test]
+    fn issued_tokens_verify_with_their_role() {
+        let key = key();
+        for role in [Role::Viewer, Role::Painter, Role::Admin] {
+            let verified = key.verify(&key.issue(&claims(role, None))).unwrap();
+            assert_eq!(verified.role, role);
+            assert_eq!(verified.sub, format!("{role:?}"));
+        }
+    }
+
+    #[test]
+    fn tampered_tokens_are_refused() {
+        let key = key();
+        let token = key.issue(&claims(Role::Viewer, None));
+        let (payload, signature) = token.split_once('.').unwrap();
+
+        // a viewer's signature on a payload granting admin
+        let forged =
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims(Role::Admin, None)).unwrap());
+        assert!(key.verify(&format!("{forged}.{signature}")).is_err());
+
+        let mut flipped = URL_SAFE_NO_PAD.decode(signature).unwrap();
+        flipped[0] ^= 1;
+        let flipped = URL_SAFE_NO_PAD.encode(flipped);
+        assert!(key.verify(&format!("{payload}.{flipped}")).is_err());
+
+        assert!(key.verify(payload).is_err());
+        assert!(Key(vec![8; KEY_BYTES]).verify(&token).is_err());
+    }
+
+    #[test]
+    fn expired_tokens_are_refused() {
+        let key = key();
+        let now = crate::state::now_ms() / 1000;
+
+        assert!(key
+            .verify(&key.issue(&claims(Role::Painter, Some(now - 60))))
+            .is_err());
+        assert!(key
+            .verify(&key.issue(&claims(Role::Painter, Some(now + 60))))
+            .is_ok());
+    }
+
+    #[test]
+    fn everyone_paints_without_a_key() {
+        let auth = Auth {
+            admin_token: None,
+            key: None,
+            allow_anonymous: false,
+        };
+        assert_eq!(auth.connection_role(None, None).unwrap().0, Role::Painter);
+        assert_eq!(
+            auth.connection_role(Some("junk"), None).unwrap().0,
+            Role::Painter
+        );
+    }
+
+    #[test]
+    fn connections_get_the_role_of_their_token() {
+        let key = key();
+        let viewer = key.issue(&claims(Role::Viewer, None));
+        let painter = key.issue(&claims(Role::Painter, None));
+
+        let required = auth(false, None);
+        assert!(required.connection_role(None, None).is_err());
+        assert!(required.connection_role(Some("junk"), None).is_err());
+        assert_eq!(
+            required.connection_role(Some(&painter), None).unwrap(),
+            (Role::Painter, Some("Painter".to_owned()))
+        );
+        assert_eq!(
+            required.connection_role(None, Some(&painter)).unwrap().0,
+            Role::Painter
+        );
+        // the query's token wins over the cookie's
+        assert_eq!(
+            required
+                .connection_role(Some(&viewer), Some(&painter))
+                .unwrap()
+                .0,
+            Role::Viewer
+        );
+
+        let anonymous = auth(true, None);
+        assert_eq!(
+            anonymous.connection_role(None, None).unwrap(),
+            (Role::Viewer, None)
+        );
+        // a bad token isn't taken as no token
+        assert!(anonymous.connection_role(None, Some("junk")).is_err());
+    }
+
+    #[test]
+    fn only_admin_tokens_are_admins() {
+        let key = key();
+        let bearer = |role| format!("Bearer {}", key.issue(&claims(role, None)));
+        let auth = auth(false, Some("secret"));
+
+        assert!(auth.is_admin(Some(&bearer(Role::Admin))));
+        assert!(auth.is_admin(Some("Bearer secret")));
+        assert!(!auth.is_admin(Some(&bearer(Role::Painter))));
+        assert!(!auth.is_admin(Some(&bearer(Role::Viewer))));
+        assert!(!auth.is_admin(Some("Bearer wrong")));
+        assert!(!auth.is_admin(Some("secret")));
+        assert!(!auth.is_admin(None));
+    }
+}
@@ -0,0 +1,641 @@
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::auth;
+use crate::export;
+use crate::message::{Layer, Rect, Role};
+use crate::persistence;
+use crate::room;
+use crate::state;
+
+/// Space Paint backend server.
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    /// TOML config file. Relative paths in it are resolved against the file's directory.
+    #[arg(short, long, env = "SPACEPAINT_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to serve on.
+    #[arg(long, env = "SPACEPAINT_ADDR")]
+    pub bind: Option<SocketAddr>,
+
+    /// Directory the frontend is served from.
+    #[arg(long)]
+    pub frontend_dir: Option<PathBuf>,
+
+    /// Page served at `/`. Defaults to `index.html` in the frontend directory.
+    #[arg(long)]
+    pub index: Option<PathBuf>,
+
+    /// File the world is saved to and loaded from.
+    #[arg(long)]
+    pub state_file: Option<PathBuf>,
+
+    /// Image the world starts from when there's no saved state, instead of generating one.
+    #[arg(long)]
+    pub seed_image: Option<PathBuf>,
+
+    /// Seed fresh worlds are generated from when there's no saved state or seed image.
+    #[arg(long)]
+    pub world_seed: Option<u32>,
+
+    /// Directory rotating backups of the state file go in.
+    #[arg(long)]
+    pub backup_dir: Option<PathBuf>,
+
+    /// Log applied modifications are appended to.
+    #[arg(long)]
+    pub journal: Option<PathBuf>,
+
+    /// Milliseconds between simulation ticks.
+    #[arg(long)]
+    pub tick_interval_ms: Option<u64>,
+
+    /// Seconds between saves of the state file.
+    #[arg(long)]
+    pub save_interval_secs: Option<u64>,
+
+    /// Bearer token that unlocks privileged HTTP endpoints like downloads. They're off without one.
+    #[arg(long, env = "SPACEPAINT_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// Modifications that can be waiting to be applied before clients are held up.
+    #[arg(long)]
+    pub modification_queue_size: Option<usize>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Rebuilds a world offline by applying a journal to a base snapshot.
+    Replay {
+        base: PathBuf,
+        journal: PathBuf,
+        output: PathBuf,
+
+        /// Keep simulating until this tick, even past the last journal entry.
+        #[arg(long)]
+        until_tick: Option<u64>,
+    },
+
+    /// Builds a world snapshot from gridded weather data in NetCDF, GRIB2 or CSV files.
+    ///
+    /// NetCDF files must be in the classic or 64-bit offset format. NetCDF-4 (HDF5) files, which
+    /// most current ERA5 & GFS downloads are, need converting first with
+    /// `nccopy -k classic input.nc output.nc`.
+    Import {
+        output: PathBuf,
+
+        /// Files to read; later files override fields read from earlier ones.
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+
+        /// Snapshot providing whatever the inputs don't cover, instead of a calm 15 °C world.
+        #[arg(long)]
+        base: Option<PathBuf>,
+    },
+
+    /// Exports layers of a snapshot as a GeoTIFF or CF NetCDF file.
+    Export {
+        output: PathBuf,
+
+        /// Snapshot to export. Defaults to the configured state file.
+        #[arg(long)]
+        snapshot: Option<PathBuf>,
+
+        /// Layers to export: temperature, wind_x, wind_y or haze. GeoTIFFs take exactly one.
+        #[arg(long = "layer", required = true, value_parser = export::parse_layer)]
+        layers: Vec<Layer>,
+
+        /// Area to export as north,west,south,east in degrees. Defaults to the whole map.
+        #[arg(long, value_parser = export::parse_area)]
+        area: Option<Rect>,
+
+        /// Defaults to the one matching the output's extension.
+        #[arg(long, value_enum)]
+        format: Option<export::Format>,
+    },
+
+    /// Generates a fresh world and saves it as a snapshot.
+    Generate {
+        output: PathBuf,
+
+        /// Defaults to the configured world seed.
+        #[arg(long)]
+        seed: Option<u32>,
+    },
+
+    /// Creates a key file for signing client tokens.
+    Keygen {
+        /// Defaults to the configured key file.
+        output: Option<PathBuf>,
+    },
+
+    /// Issues a token signed with the configured key file.
+    Token {
+        /// viewer, painter or admin.
+        #[arg(value_parser = auth::parse_role)]
+        role: Role,
+
+        /// Who the token is for, shown in logs.
+        #[arg(long)]
+        subject: String,
+
+        /// Hours until the token expires. It never does if unset.
+        #[arg(long)]
+        valid_hours: Option<u64>,
+    },
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub paths: PathsConfig,
+    pub storage: StorageConfig,
+    pub history: HistoryConfig,
+    pub world: WorldConfig,
+    pub admin: AdminConfig,
+    pub auth: AuthConfig,
+    pub rooms: RoomsConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: SocketAddr,
+    pub tick_interval_ms: u64,
+    pub modification_queue_size: usize,
+
+    /// Packets each client can have waiting to be written to its socket.
+    pub client_queue_size: usize,
+
+    /// Largest snapshot (width * height) rendered; at least 880, the size of default snapshots.
+    pub max_snapshot_pixels: u32,
+
+    /// Most websocket connections open at once; more are turned away.
+    pub max_connections: usize,
+
+    /// Time between pings sent to each client.
+    pub heartbeat_interval_secs: u64,
+
+    /// Clients that send nothing, not even a pong, for this long are dropped.
+    pub heartbeat_timeout_secs: u64,
+
+    /// Widest brush clients may paint with; wider strokes are ignored.
+    pub max_brush_degrees: f64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    pub frontend_dir: PathBuf,
+
+    /// Defaults to `index.html` in `frontend_dir`.
+    pub index: Option<PathBuf>,
+
+    pub state_file: PathBuf,
+
+    /// Image fresh worlds start from; they're generated from `world.seed` if unset.
+    pub seed_image: Option<PathBuf>,
+
+    pub backup_dir: PathBuf,
+    pub journal: PathBuf,
+
+    /// Directory holding a subdirectory per named room.
+    pub rooms_dir: PathBuf,
+
+    /// Scenario the default room plays from startup.
+    pub scenario: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub save_interval_secs: u64,
+    pub backup_retention: usize,
+    pub backup_interval_secs: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    pub depth: usize,
+    pub downsample: u32,
+    pub interval_ticks: u64,
+    pub undo_depth: usize,
+
+    /// Most bytes of strokes kept per client for undo & redo, on top of `undo_depth`.
+    pub undo_max_bytes: usize,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorldConfig {
+    /// Seed fresh worlds are generated from.
+    pub seed: u32,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token that unlocks privileged HTTP endpoints; they're disabled if unset.
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomsConfig {
+    /// Most named rooms loaded at once; 0 allows only the default room.
+    pub max_loaded: usize,
+
+    /// Whether painters & admins joining a room that doesn't exist yet create it. If not, rooms
+    /// are only the directories already in `paths.rooms_dir`.
+    pub create_on_join: bool,
+
+    /// Most named rooms kept in `paths.rooms_dir`; joining creates no more once there are this many.
+    pub max_rooms: usize,
+
+    /// Named rooms nobody has been in for this long are saved & unloaded.
+    pub idle_unload_secs: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Key client tokens are signed with. Without one every client may paint.
+    pub key_file: Option<PathBuf>,
+
+    /// Whether clients without a token may connect as viewers.
+    pub allow_anonymous: bool,
+}
+
+impl std::fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // keep the token out of logs
+        f.debug_struct("AdminConfig")
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind_address: ([0, 0, 0, 0], 5000).into(),
+            tick_interval_ms: 500,
+            modification_queue_size: 50,
+            client_queue_size: 16,
+            max_snapshot_pixels: state::MAX_SNAPSHOT_PIXELS,
+            max_connections: 256,
+            heartbeat_interval_secs: 15,
+            heartbeat_timeout_secs: 45,
+            max_brush_degrees: 20.,
+        }
+    }
+}
+
+impl Default for RoomsConfig {
+    fn default() -> RoomsConfig {
+        RoomsConfig {
+            max_loaded: 8,
+            create_on_join: true,
+            max_rooms: 64,
+            idle_unload_secs: 300,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> AuthConfig {
+        AuthConfig {
+            key_file: None,
+            allow_anonymous: true,
+        }
+    }
+}
+
+impl Default for PathsConfig {
+    fn default() -> PathsConfig {
+        PathsConfig {
+            frontend_dir: "../frontend".into(),
+            index: None,
+            state_file: "state.png".into(),
+            seed_image: None,
+            backup_dir: "backups".into(),
+            journal: "journal.jsonl".into(),
+            rooms_dir: "rooms".into(),
+            scenario: None,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> StorageConfig {
+        StorageConfig {
+            save_interval_secs: 10,
+            backup_retention: 30,
+            backup_interval_secs: 60,
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> HistoryConfig {
+        HistoryConfig {
+            depth: state::HISTORY_DEPTH,
+            downsample: state::HISTORY_DOWNSAMPLE,
+            interval_ticks: state::HISTORY_INTERVAL,
+            undo_depth: state::UNDO_DEPTH,
+            undo_max_bytes: state::UNDO_MAX_BYTES,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file named on the command line (if any) and applies the flags on top.
+    pub fn load(cli: &Cli) -> Result<Config> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading config file {}", path.display()))?;
+                let mut config: Config = toml::from_str(&text)
+                    .with_context(|| format!("parsing config file {}", path.display()))?;
+
+                let base = path.parent().unwrap_or(Path::new(""));
+                config.paths.resolve_against(base);
+                if let Some(key_file) = &mut config.auth.key_file {
+                    if key_file.is_relative() {
+                        *key_file = base.join(&*key_file);
+                    }
+                }
+                config
+            }
+            None => Config::default(),
+        };
+
+        config.apply_overrides(cli);
+
+        Ok(config)
+    }
+
+    /// Flags take precedence over the file.
+    fn apply_overrides(&mut self, cli: &Cli) {
+        let paths = &mut self.paths;
+        for (flag, field) in [
+            (&cli.frontend_dir, &mut paths.frontend_dir),
+            (&cli.state_file, &mut paths.state_file),
+            (&cli.backup_dir, &mut paths.backup_dir),
+            (&cli.journal, &mut paths.journal),
+        ] {
+            if let Some(value) = flag {
+                *field = value.clone();
+            }
+        }
+        if let Some(index) = &cli.index {
+            paths.index = Some(index.clone());
+        }
+        if let Some(seed_image) = &cli.seed_image {
+            paths.seed_image = Some(seed_image.clone());
+        }
+        if let Some(token) = &cli.admin_token {
+            self.admin.token = Some(token.clone());
+        }
+        if let Some(seed) = cli.world_seed {
+            self.world.seed = seed;
+        }
+
+        if let Some(bind) = cli.bind {
+            self.server.bind_address = bind;
+        }
+        if let Some(ms) = cli.tick_interval_ms {
+            self.server.tick_interval_ms = ms;
+        }
+        if let Some(size) = cli.modification_queue_size {
+            self.server.modification_queue_size = size;
+        }
+        if let Some(secs) = cli.save_interval_secs {
+            self.storage.save_interval_secs = secs;
+        }
+    }
+
+    /// Checks everything the server needs, reporting all problems at once rather than just the
+    /// first.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        if self.server.tick_interval_ms == 0 {
+            problems.push("server.tick_interval_ms must be at least 1".to_owned());
+        }
+        if self.server.modification_queue_size == 0 {
+            problems.push("server.modification_queue_size must be at least 1".to_owned());
+        }
+        if self.server.client_queue_size == 0 {
+            problems.push("server.client_queue_size must be at least 1".to_owned());
+        }
+        if self.server.max_snapshot_pixels < state::DEFAULT_SNAPSHOT_PIXELS {
+            problems.push(format!(
+                "server.max_snapshot_pixels must be at least {}, the size of default snapshots",
+                state::DEFAULT_SNAPSHOT_PIXELS
+            ));
+        }
+        if self.server.max_connections == 0 {
+            problems.push("server.max_connections must be at least 1".to_owned());
+        }
+        if self.server.heartbeat_interval_secs == 0 {
+            problems.push("server.heartbeat_interval_secs must be at least 1".to_owned());
+        }
+        if self.server.heartbeat_timeout_secs <= self.server.heartbeat_interval_secs {
+            problems.push(
+                "server.heartbeat_timeout_secs must be longer than server.heartbeat_interval_secs"
+                    .to_owned(),
+            );
+        }
+        let max_brush = self.server.max_brush_degrees;
+        if !max_brush.is_finite() || max_brush <= 0. || max_brush > 180. {
+            problems.push(format!(
+                "server.max_brush_degrees must be above 0 & at most 180, got {max_brush}"
+            ));
+        }
+        if self.storage.save_interval_secs == 0 {
+            problems.push("storage.save_interval_secs must be at least 1".to_owned());
+        }
+        if self
+            .admin
+            .token
+            .as_ref()
+            .is_some_and(|token| token.len() < 16)
+        {
+            problems.push("admin.token must be at least 16 characters".to_owned());
+        }
+        if self.rooms.idle_unload_secs == 0 {
+            problems.push("rooms.idle_unload_secs must be at least 1".to_owned());
+        }
+        if self.history.interval_ticks == 0 {
+            problems.push("history.interval_ticks must be at least 1".to_owned());
+        }
+
+        let downsample = self.history.downsample as usize;
+        if downsample == 0
+            || !state::MAP_WIDTH.is_multiple_of(downsample)
+            || !state::MAP_HEIGHT.is_multiple_of(downsample)
+        {
+            problems.push(format!(
+                "history.downsample must evenly divide the {}x{} map, got {downsample}",
+                state::MAP_WIDTH,
+                state::MAP_HEIGHT
+            ));
+        }
+
+        if !self.paths.frontend_dir.is_dir() {
+            problems.push(format!(
+                "frontend directory {} doesn't exist",
+                self.paths.frontend_dir.display()
+            ));
+        }
+        if !self.index_path().is_file() {
+            problems.push(format!(
+                "index page {} doesn't exist",
+                self.index_path().display()
+            ));
+        }
+        if let Some(key_file) = &self.auth.key_file {
+            if !key_file.is_file() {
+                problems.push(format!(
+                    "key file {} doesn't exist; create one with `keygen`",
+                    key_file.display()
+                ));
+            }
+        }
+        if let Some(seed_image) = &self.paths.seed_image {
+            if !seed_image.is_file() {
+                problems.push(format!("seed image {} doesn't exist", seed_image.display()));
+            }
+        }
+        for (name, path) in [
+            ("state file", &self.paths.state_file),
+            ("journal", &self.paths.journal),
+        ] {
+            let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+            if parent.is_some_and(|dir| !dir.is_dir()) {
+                problems.push(format!(
+                    "directory for {name} {} doesn't exist",
+                    path.display()
+                ));
+            }
+        }
+
+        if !problems.is_empty() {
+            anyhow::bail!("invalid configuration:\n  {}", problems.join("\n  "));
+        }
+
+        Ok(())
+    }
+
+    pub fn index_path(&self) -> PathBuf {
+        self.paths
+            .index
+            .clone()
+            .unwrap_or_else(|| self.paths.frontend_dir.join("index.html"))
+    }
+
+    pub fn tick_interval(&self) -> Duration {
+        Duration::from_millis(self.server.tick_interval_ms)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.server.heartbeat_interval_secs)
+    }
+
+    pub fn heartbeat_timeout(&self) -> Duration {
+        Duration::from_secs(self.server.heartbeat_timeout_secs)
+    }
+
+    pub fn save_interval(&self) -> Duration {
+        Duration::from_secs(self.storage.save_interval_secs)
+    }
+
+    pub fn backup_interval(&self) -> Duration {
+        Duration::from_secs(self.storage.backup_interval_secs)
+    }
+
+    /// Where named rooms live & how they're loaded.
+    pub fn room_settings(&self) -> room::Settings {
+        room::Settings {
+            dir: self.paths.rooms_dir.clone(),
+            max_loaded: self.rooms.max_loaded,
+            create_on_join: self.rooms.create_on_join,
+            max_rooms: self.rooms.max_rooms,
+            idle_timeout: Duration::from_secs(self.rooms.idle_unload_secs),
+            fresh_world: self.fresh_world(),
+            state: self.state_settings(),
+            backup_retention: self.storage.backup_retention,
+            backup_interval: self.backup_interval(),
+        }
+    }
+
+    /// Credentials clients are checked against, reading the key file if there is one.
+    pub fn auth(&self) -> Result<auth::Auth> {
+        Ok(auth::Auth {
+            admin_token: self.admin.token.clone(),
+            key: self
+                .auth
+                .key_file
+                .as_deref()
+                .map(auth::Key::load)
+                .transpose()?,
+            allow_anonymous: self.auth.allow_anonymous,
+        })
+    }
+
+    /// What to start from when there's no saved state.
+    pub fn fresh_world(&self) -> persistence::FreshWorld {
+        match &self.paths.seed_image {
+            Some(path) => persistence::FreshWorld::Image(path.clone()),
+            None => persistence::FreshWorld::Generated {
+                seed: self.world.seed,
+            },
+        }
+    }
+
+    pub fn state_settings(&self) -> state::Settings {
+        state::Settings {
+            max_snapshot_pixels: self.server.max_snapshot_pixels,
+            history_depth: self.history.depth,
+            history_downsample: self.history.downsample,
+            history_interval: self.history.interval_ticks,
+            undo_depth: self.history.undo_depth,
+            undo_max_bytes: self.history.undo_max_bytes,
+        }
+    }
+}
+
+impl PathsConfig {
+    /// Makes relative paths from a config file relative to the file rather than the working
+    /// directory.
+    fn resolve_against(&mut self, base: &Path) {
+        for path in [
+            &mut self.frontend_dir,
+            &mut self.state_file,
+            &mut self.backup_dir,
+            &mut self.journal,
+            &mut self.rooms_dir,
+        ]
+        .into_iter()
+        .chain(self.index.as_mut())
+        .chain(self.seed_image.as_mut())
+        .chain(self.scenario.as_mut())
+        {
+            if path.is_relative() {
+                *path = base.join(&*path);
+            }
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! Exports fields of the world as georeferenced files for GIS tools.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::io::Cursor;
+use std::path::Path;
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::tags::Tag;
+
+use crate::message::{LatLong, Layer, Rect};
+use crate::netcdf::{self, Attribute, Data, Dimension, NewVariable};
+use crate::state::{self, units, WorldSnapshot};
+
+/// GeoTIFF tags, see the GeoTIFF 1.1 spec.
+const MODEL_PIXEL_SCALE_TAG: u16 = 33550;
+const MODEL_TIEPOINT_TAG: u16 = 33922;
+const GEO_KEY_DIRECTORY_TAG: u16 = 34735;
+
+/// GeoKey directory: version 1.1.0 with 3 keys saying the raster is an area-per-pixel grid of
+/// WGS 84 geographic coordinates (EPSG:4326).
+const GEO_KEYS: [u16; 16] = [
+    1, 1, 0, 3, // header
+    1024, 0, 1, 2, // GTModelTypeGeoKey = ModelTypeGeographic
+    1025, 0, 1, 1, // GTRasterTypeGeoKey = RasterPixelIsArea
+    2048, 0, 1, 4326, // GeographicTypeGeoKey = EPSG:4326
+];
+
+#[derive(Clone, Copy, Deserialize, clap::ValueEnum, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[value(name = "geotiff")]
+    GeoTiff,
+    #[value(name = "netcdf")]
+    NetCdf,
+}
+
+impl Format {
+    /// Guesses the format from a file name.
+    pub fn from_path(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "tif" | "tiff" => Some(Format::GeoTiff),
+            "nc" => Some(Format::NetCdf),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::GeoTiff => "tif",
+            Format::NetCdf => "nc",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::GeoTiff => "image/tiff",
+            Format::NetCdf => "application/x-netcdf",
+        }
+    }
+}
+
+/// Name of a layer in exports and on the command line.
+pub fn layer_name(layer: Layer) -> &'static str {
+    match layer {
+        Layer::Temperature => "temperature",
+        Layer::WindX => "wind_x",
+        Layer::WindY => "wind_y",
+        Layer::Haze => "haze",
+    }
+}
+
+pub fn parse_layer(name: &str) -> Result<Layer> {
+    [Layer::Temperature, Layer::WindX, Layer::WindY, Layer::Haze]
+        .into_iter()
+        .find(|layer| layer_name(*layer) == name.trim())
+        .with_context(|| {
+            format!("unknown layer {name:?}; expected temperature, wind_x, wind_y or haze")
+        })
+}
+
+/// Parses an area given as `north,west,south,east` in degrees.
+pub fn parse_area(area: &str) -> Result<Rect> {
+    let bounds: Vec<f64> = area
+        .split(',')
+        .map(|bound| bound.trim().parse())
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("area {area:?} isn't a list of numbers"))?;
+    let [north, west, south, east] = bounds[..] else {
+        anyhow::bail!("area {area:?} should be north,west,south,east");
+    };
+
+    Ok(Rect {
+        top_left: LatLong {
+            lat: north,
+            long: west,
+        },
+        bottom_right: LatLong {
+            lat: south,
+            long: east,
+        },
+    })
+}
+
+/// Cells of the map covered by an export.
+struct Region {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Region {
+    fn new(area: Option<Rect>) -> Result<Region> {
+        let Some(area) = area else {
+            return Ok(Region {
+                x: 0,
+                y: 0,
+                width: state::MAP_WIDTH as u32,
+                height: state::MAP_HEIGHT as u32,
+            });
+        };
+
+        let (columns, rows) = state::area_cells(&area);
+        if columns.is_empty() || rows.is_empty() {
+            anyhow::bail!("area {area:?} is empty or crosses the antimeridian");
+        }
+
+        Ok(Region {
+            x: columns.start,
+            y: rows.start,
+            width: columns.len() as u32,
+            height: rows.len() as u32,
+        })
+    }
+
+    /// Latitudes of the centers of the rows, north to south.
+    fn lats(&self) -> Vec<f64> {
+        (self.y..self.y + self.height)
+            .map(|y| state::cell_center(0, y as usize).lat)
+            .collect()
+    }
+
+    /// Longitudes of the centers of the columns, west to east.
+    fn lons(&self) -> Vec<f64> {
+        (self.x..self.x + self.width)
+            .map(|x| state::cell_center(x as usize, 0).long)
+            .collect()
+    }
+}
+
+/// Physical values of a layer within the region, row by row from the north.
+///
+/// The state's Y axis points south, so `wind_y` is flipped to be northward like in CF.
+fn field(snapshot: &WorldSnapshot, layer: Layer, region: &Region) -> Vec<f32> {
+    let sign = if layer == Layer::WindY { -1. } else { 1. };
+    let offset = state::layer_offset(layer);
+
+    let mut values = Vec::with_capacity((region.width * region.height) as usize);
+    for y in region.y..region.y + region.height {
+        for x in region.x..region.x + region.width {
+            let index =
+                (y as usize * state::MAP_WIDTH + x as usize) * state::BYTES_PER_PIXEL + offset;
+            values.push((sign * units::from_raw(layer, snapshot.data[index])) as f32);
+        }
+    }
+
+    values
+}
+
+/// Exports the layers of a snapshot within `area` (or everywhere) in the given format.
+pub fn export(
+    snapshot: &WorldSnapshot,
+    layers: &[Layer],
+    area: Option<Rect>,
+    format: Format,
+) -> Result<Vec<u8>> {
+    if layers.is_empty() {
+        anyhow::bail!("nothing to export; pick at least one layer");
+    }
+    let region = Region::new(area)?;
+
+    match format {
+        Format::GeoTiff => {
+            let [layer] = layers else {
+                anyhow::bail!("GeoTIFFs hold a single layer; export one at a time");
+            };
+            geotiff(snapshot, *layer, &region)
+        }
+        Format::NetCdf => cf_netcdf(snapshot, layers, &region),
+    }
+}
+
+/// Single-band 32-bit float GeoTIFF in EPSG:4326.
+fn geotiff(snapshot: &WorldSnapshot, layer: Layer, region: &Region) -> Result<Vec<u8>> {
+    let mut output = Cursor::new(Vec::new());
+    let mut encoder = TiffEncoder::new(&mut output)?;
+    let mut image = encoder.new_image::<colortype::Gray32Float>(region.width, region.height)?;
+
+    // the tiepoint maps the top left corner of the first pixel to its coordinates
+    let origin = state::pixel_coords_to_latlong(region.x, region.y);
+    let pixel_width = 360. / state::MAP_WIDTH as f64;
+    let pixel_height = 180. / state::MAP_HEIGHT as f64;
+
+    let tags = image.encoder();
+    tags.write_tag(
+        Tag::Unknown(MODEL_PIXEL_SCALE_TAG),
+        &[pixel_width, pixel_height, 0.][..],
+    )?;
+    tags.write_tag(
+        Tag::Unknown(MODEL_TIEPOINT_TAG),
+        &[0., 0., 0., origin.long, origin.lat, 0.][..],
+    )?;
+    tags.write_tag(Tag::Unknown(GEO_KEY_DIRECTORY_TAG), &GEO_KEYS[..])?;
+    tags.write_tag(Tag::ImageDescription, layer_name(layer))?;
+
+    image.write_data(&field(snapshot, layer, region))?;
+
+    Ok(output.into_inner())
+}
+
+/// CF-1.8 NetCDF with one variable per layer on a latitude/longitude grid.
+fn cf_netcdf(snapshot: &WorldSnapshot, layers: &[Layer], region: &Region) -> Result<Vec<u8>> {
+    let dims = [
+        Dimension {
+            name: "lat".to_owned(),
+            len: region.height as usize,
+        },
+        Dimension {
+            name: "lon".to_owned(),
+            len: region.width as usize,
+        },
+    ];
+
+    let mut vars = vec![
+        NewVariable {
+            name: "lat".to_owned(),
+            dims: vec![0],
+            attrs: vec![
+                Attribute::text("standard_name", "latitude"),
+                Attribute::text("units", "degrees_north"),
+                Attribute::text("axis", "Y"),
+            ],
+            data: Data::Double(region.lats()),
+        },
+        NewVariable {
+            name: "lon".to_owned(),
+            dims: vec![1],
+            attrs: vec![
+                Attribute::text("standard_name", "longitude"),
+                Attribute::text("units", "degrees_east"),
+                Attribute::text("axis", "X"),
+            ],
+            data: Data::Double(region.lons()),
+        },
+    ];
+    for &layer in layers {
+        let (standard_name, long_name) = match layer {
+            Layer::Temperature => ("air_temperature", "temperature"),
+            Layer::WindX => ("eastward_wind", "eastward wind"),
+            Layer::WindY => ("northward_wind", "northward wind"),
+            Layer::Haze => ("cloud_area_fraction", "haze"),
+        };
+
+        vars.push(NewVariable {
+            name: layer_name(layer).to_owned(),
+            dims: vec![0, 1],
+            attrs: vec![
+                Attribute::text("standard_name", standard_name),
+                Attribute::text("long_name", long_name),
+                Attribute::text("units", units::unit(layer)),
+            ],
+            data: Data::Float(field(snapshot, layer, region)),
+        });
+    }
+
+    let attrs = [
+        Attribute::text("Conventions", "CF-1.8"),
+        Attribute::text("title", "Space Paint world state"),
+        Attribute::text(
+            "source",
+            &format!("spacepaint simulation at tick {}", snapshot.metadata.tick),
+        ),
+        Attribute::numbers("tick", &[snapshot.metadata.tick as f64]),
+    ];
+
+    netcdf::write(&dims, &attrs, &vars)
+}
+
+/// File name suggested for a download of the given snapshot.
+pub fn file_name(snapshot: &WorldSnapshot, format: Format) -> String {
+    format!(
+        "spacepaint-tick-{}.{}",
+        snapshot.metadata.tick,
+        format.extension()
+    )
+}
+
+/// Exports a snapshot file from the command line.
+pub fn export_file(
+    snapshot_path: &Path,
+    output: &Path,
+    layers: &[Layer],
+    area: Option<Rect>,
+    format: Option<Format>,
+) -> Result<()> {
+    let format = format
+        .or_else(|| Format::from_path(output))
+        .context("can't tell the format from the output name; pass --format")?;
+    let snapshot = WorldSnapshot::load(snapshot_path)
+        .with_context(|| format!("loading snapshot {}", snapshot_path.display()))?;
+
+    let bytes = export(&snapshot, layers, area, format)?;
+    std::fs::write(output, bytes).with_context(|| format!("writing {}", output.display()))?;
+    log::info!(
+        "Exported {} to {}",
+        layers
+            .iter()
+            .map(|layer| layer_name(*layer))
+            .collect::<Vec<_>>()
+            .join(", "),
+        output.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(region: &Region) -> (u32, u32, u32, u32) {
+        (region.x, region.y, region.width, region.height)
+    }
+
+    #[test]
+    fn explicit_whole_world_is_whole_map() {
+        let whole = Region::new(None).unwrap();
+        let explicit = Region::new(Some(parse_area("90,-180,-90,180").unwrap())).unwrap();
+
+        assert_eq!(cells(&explicit), cells(&whole));
+        assert_eq!(explicit.lats(), whole.lats());
+        assert_eq!(explicit.lons(), whole.lons());
+    }
+
+    #[test]
+    fn empty_areas_are_refused() {
+        assert!(Region::new(Some(parse_area("10,20,10,30").unwrap())).is_err());
+    }
+}
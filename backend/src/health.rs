@@ -0,0 +1,134 @@
+//! Liveness & readiness reports, served at `/healthz` and `/readyz`.
+//!
+//! Kept apart from [`crate::GlobalState`] so checks answer even while a tick holds its lock.
+
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::metrics::Metrics;
+use crate::state::{self, AdapterDiagnostics};
+
+/// Ticks are considered stalled after this many tick intervals without one...
+const STALLED_AFTER_INTERVALS: u32 = 5;
+
+/// ...but never sooner than this, since software rasterizers take seconds per tick.
+const MIN_STALL: Duration = Duration::from_secs(30);
+
+/// Outcome of the most recent tick & save.
+#[derive(Default)]
+struct Status {
+    /// Tick number & time of the last successful tick.
+    last_tick: Option<(u64, Instant, u64)>,
+
+    /// Error of the most recent tick, if it failed.
+    tick_error: Option<String>,
+
+    /// Unix time in ms of the last successful save.
+    last_save_ms: Option<u64>,
+
+    /// Error of the most recent save, if it failed.
+    save_error: Option<String>,
+}
+
+pub struct Health {
+    adapter: AdapterDiagnostics,
+    started: Instant,
+
+    /// Time between ticks, which can change at runtime.
+    tick_interval: watch::Receiver<Duration>,
+    metrics: Metrics,
+    status: Mutex<Status>,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub ready: bool,
+
+    /// Why the server isn't ready, if it isn't.
+    pub problem: Option<String>,
+    pub adapter: AdapterDiagnostics,
+    pub uptime_secs: f64,
+    pub tick: Option<u64>,
+    pub last_tick_ms: Option<u64>,
+    pub secs_since_last_tick: Option<f64>,
+    pub tick_error: Option<String>,
+    pub last_save_ms: Option<u64>,
+    pub save_error: Option<String>,
+    pub clients: i64,
+}
+
+impl Health {
+    pub fn new(
+        adapter: AdapterDiagnostics,
+        tick_interval: watch::Receiver<Duration>,
+        metrics: Metrics,
+    ) -> Health {
+        Health {
+            adapter,
+            started: Instant::now(),
+            tick_interval,
+            metrics,
+            status: Mutex::new(Status::default()),
+        }
+    }
+
+    pub fn record_tick(&self, tick: u64) {
+        let mut status = self.status.lock().unwrap();
+        status.last_tick = Some((tick, Instant::now(), state::now_ms()));
+        status.tick_error = None;
+    }
+
+    pub fn record_tick_error(&self, error: &anyhow::Error) {
+        self.status.lock().unwrap().tick_error = Some(format!("{error:#}"));
+    }
+
+    pub fn record_save(&self, result: &anyhow::Result<()>) {
+        let mut status = self.status.lock().unwrap();
+        match result {
+            Ok(()) => {
+                status.last_save_ms = Some(state::now_ms());
+                status.save_error = None;
+            }
+            Err(e) => status.save_error = Some(format!("{e:#}")),
+        }
+    }
+
+    /// Longest time between ticks before the server stops being ready.
+    fn stall_limit(&self) -> Duration {
+        (*self.tick_interval.borrow() * STALLED_AFTER_INTERVALS).max(MIN_STALL)
+    }
+
+    pub fn report(&self) -> Report {
+        let status = self.status.lock().unwrap();
+        let since_last_tick = status.last_tick.map(|(_, at, _)| at.elapsed());
+
+        let problem = match since_last_tick {
+            _ if status.tick_error.is_some() => Some(format!(
+                "last tick failed: {}",
+                status.tick_error.as_deref().unwrap_or_default()
+            )),
+            None => Some("no tick has completed yet".to_owned()),
+            Some(elapsed) if elapsed > self.stall_limit() => Some(format!(
+                "ticks stalled: none for {:.1} s",
+                elapsed.as_secs_f64()
+            )),
+            Some(_) => None,
+        };
+
+        Report {
+            ready: problem.is_none(),
+            problem,
+            adapter: self.adapter.clone(),
+            uptime_secs: self.started.elapsed().as_secs_f64(),
+            tick: status.last_tick.map(|(tick, _, _)| tick),
+            last_tick_ms: status.last_tick.map(|(_, _, ms)| ms),
+            secs_since_last_tick: since_last_tick.map(|elapsed| elapsed.as_secs_f64()),
+            tick_error: status.tick_error.clone(),
+            last_save_ms: status.last_save_ms,
+            save_error: status.save_error.clone(),
+            clients: self.metrics.connected_clients(),
+        }
+    }
+}
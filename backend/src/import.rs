@@ -0,0 +1,590 @@
+//! Builds a world from real gridded weather data.
+//!
+//! Reads temperature, wind components and cloud cover or humidity from NetCDF, GRIB2 or CSV
+//! files, regrids them onto the map and converts them to the raw channel encodings.
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::message::Layer;
+use crate::state::{self, units, WorldSnapshot};
+
+mod csv;
+mod grib;
+mod netcdf;
+
+/// A physical quantity the importer knows how to put on the map.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Quantity {
+    Temperature,
+    WindU,
+    WindV,
+    CloudCover,
+    RelativeHumidity,
+}
+
+impl Quantity {
+    /// Recognizes common variable names & CF standard names of the quantity.
+    fn from_name(name: &str) -> Option<Quantity> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "temperature" | "temp" | "t" | "t2m" | "tmp" | "air_temperature" => {
+                Quantity::Temperature
+            }
+            "u" | "u10" | "u_wind" | "ugrd" | "eastward_wind" => Quantity::WindU,
+            "v" | "v10" | "v_wind" | "vgrd" | "northward_wind" => Quantity::WindV,
+            "cloud" | "cloud_cover" | "tcc" | "tcdc" | "cloud_area_fraction" => {
+                Quantity::CloudCover
+            }
+            "humidity" | "rh" | "r" | "relative_humidity" => Quantity::RelativeHumidity,
+            _ => return None,
+        })
+    }
+
+    /// Unit values are converted to: kelvin, m/s, or a 0-1 fraction.
+    fn canonical_unit(self) -> &'static str {
+        match self {
+            Quantity::Temperature => "K",
+            Quantity::WindU | Quantity::WindV => "m s-1",
+            Quantity::CloudCover | Quantity::RelativeHumidity => "1",
+        }
+    }
+
+    /// Linear conversion from `unit` to the quantity's canonical unit.
+    fn conversion(self, unit: &str) -> Result<Conversion> {
+        let unit = unit.trim().to_ascii_lowercase();
+        let conversion = match (self, unit.as_str()) {
+            (Quantity::Temperature, "k" | "kelvin" | "degk" | "deg_k") => Conversion::IDENTITY,
+            (
+                Quantity::Temperature,
+                "c" | "degc" | "deg_c" | "°c" | "celsius" | "degrees_celsius",
+            ) => Conversion {
+                scale: 1.,
+                offset: 273.15,
+            },
+            (Quantity::Temperature, "f" | "degf" | "deg_f" | "°f" | "fahrenheit") => Conversion {
+                scale: 5. / 9.,
+                offset: 273.15 - 32. * 5. / 9.,
+            },
+            (Quantity::WindU | Quantity::WindV, "m s-1" | "m/s" | "m s**-1" | "ms-1" | "m.s-1") => {
+                Conversion::IDENTITY
+            }
+            (Quantity::WindU | Quantity::WindV, "km/h" | "km h-1" | "kph") => Conversion {
+                scale: 1. / 3.6,
+                offset: 0.,
+            },
+            (Quantity::WindU | Quantity::WindV, "kt" | "kn" | "knot" | "knots") => Conversion {
+                scale: 0.514444,
+                offset: 0.,
+            },
+            (
+                Quantity::CloudCover | Quantity::RelativeHumidity,
+                "1" | "" | "0-1" | "(0 - 1)" | "fraction",
+            ) => Conversion::IDENTITY,
+            (Quantity::CloudCover | Quantity::RelativeHumidity, "%" | "percent") => Conversion {
+                scale: 0.01,
+                offset: 0.,
+            },
+            _ => anyhow::bail!("don't know how to convert {self:?} from unit {unit:?}"),
+        };
+
+        Ok(conversion)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Conversion {
+    scale: f64,
+    offset: f64,
+}
+
+impl Conversion {
+    const IDENTITY: Conversion = Conversion {
+        scale: 1.,
+        offset: 0.,
+    };
+
+    fn apply(self, value: f64) -> f64 {
+        value * self.scale + self.offset
+    }
+}
+
+/// Values on a regular latitude/longitude grid, in the quantity's canonical unit.
+pub struct Grid {
+    /// Latitudes of the rows, ascending.
+    lats: Vec<f64>,
+
+    /// Longitudes of the columns in [-180, 180), ascending.
+    lons: Vec<f64>,
+
+    /// Row-major values; NaN where missing.
+    values: Vec<f64>,
+
+    /// Whether the columns go all the way around the globe.
+    wraps: bool,
+}
+
+impl Grid {
+    /// Builds a grid from row-major `values` with the given row latitudes & column longitudes,
+    /// in any order and longitude convention.
+    fn new(lats: Vec<f64>, lons: Vec<f64>, values: Vec<f64>) -> Result<Grid> {
+        if lats.len() < 2 || lons.len() < 2 {
+            anyhow::bail!("grids need at least two rows and columns");
+        }
+        if values.len() != lats.len() * lons.len() {
+            anyhow::bail!(
+                "grid has {} values, expected {}x{}",
+                values.len(),
+                lats.len(),
+                lons.len()
+            );
+        }
+
+        let lons: Vec<f64> = lons
+            .into_iter()
+            .map(|lon| (lon + 180.).rem_euclid(360.) - 180.)
+            .collect();
+        let row_order = sorted_order(&lats);
+        let column_order = sorted_order(&lons);
+
+        let mut sorted_values = Vec::with_capacity(values.len());
+        for &row in &row_order {
+            for &column in &column_order {
+                sorted_values.push(values[row * lons.len() + column]);
+            }
+        }
+        let lats: Vec<f64> = row_order.iter().map(|&i| lats[i]).collect();
+        let lons: Vec<f64> = column_order.iter().map(|&i| lons[i]).collect();
+
+        let spacing = (lons[lons.len() - 1] - lons[0]) / (lons.len() - 1) as f64;
+        let wraps = lons[lons.len() - 1] - lons[0] + spacing >= 359.;
+
+        Ok(Grid {
+            lats,
+            lons,
+            values: sorted_values,
+            wraps,
+        })
+    }
+
+    /// Builds a grid from scattered `(lat, lon, value)` points that lie on a regular grid.
+    /// Grid cells without a point are missing.
+    fn from_points(points: impl IntoIterator<Item = (f64, f64, f64)>) -> Result<Grid> {
+        let points: Vec<(f64, f64, f64)> = points.into_iter().collect();
+
+        let lats = unique_sorted(points.iter().map(|p| p.0));
+        let lons = unique_sorted(points.iter().map(|p| p.1));
+
+        let mut values = vec![f64::NAN; lats.len() * lons.len()];
+        for (lat, lon, value) in points {
+            let row = lats.partition_point(|&l| l < coordinate_key(lat));
+            let column = lons.partition_point(|&l| l < coordinate_key(lon));
+            values[row * lons.len() + column] = value;
+        }
+
+        Grid::new(lats, lons, values)
+    }
+
+    /// Bilinearly interpolates the grid at a point, skipping missing corners.
+    ///
+    /// Returns `None` outside the grid or if all surrounding values are missing.
+    fn sample(&self, lat: f64, lon: f64) -> Option<f64> {
+        let (row0, row1, ty) = axis_position(&self.lats, lat, false)?;
+        let (col0, col1, tx) = axis_position(&self.lons, lon, self.wraps)?;
+
+        let corners = [
+            (row0, col0, (1. - ty) * (1. - tx)),
+            (row0, col1, (1. - ty) * tx),
+            (row1, col0, ty * (1. - tx)),
+            (row1, col1, ty * tx),
+        ];
+
+        let (sum, weight) = corners
+            .into_iter()
+            .map(|(row, col, weight)| (self.values[row * self.lons.len() + col], weight))
+            .filter(|(value, _)| !value.is_nan())
+            .fold((0., 0.), |(sum, total), (value, weight)| {
+                (sum + value * weight, total + weight)
+            });
+
+        (weight > 0.).then(|| sum / weight)
+    }
+}
+
+/// Indices of the values in ascending order.
+fn sorted_order(values: &[f64]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+    order
+}
+
+/// Rounds coordinates so ones parsed from text match up.
+fn coordinate_key(value: f64) -> f64 {
+    (value * 1e6).round() / 1e6
+}
+
+fn unique_sorted(values: impl Iterator<Item = f64>) -> Vec<f64> {
+    let mut values: Vec<f64> = values.map(coordinate_key).collect();
+    values.sort_by(f64::total_cmp);
+    values.dedup();
+    values
+}
+
+/// Finds the two grid lines around `value` on an ascending axis, and how far it is between them.
+///
+/// Values up to one grid spacing past the ends are clamped to them, so e.g. the poles still get
+/// the nearest row.
+fn axis_position(axis: &[f64], value: f64, wraps: bool) -> Option<(usize, usize, f64)> {
+    let (first, last) = (axis[0], axis[axis.len() - 1]);
+    let spacing = (last - first) / (axis.len() - 1) as f64;
+
+    if value < first || value > last {
+        if wraps {
+            // between the last and the first column, across the antimeridian
+            let span = first + 360. - last;
+            let t = (value - last).rem_euclid(360.) / span;
+            return Some((axis.len() - 1, 0, t.clamp(0., 1.)));
+        }
+
+        let edge = if value < first { 0 } else { axis.len() - 1 };
+        let distance = (value - axis[edge]).abs();
+        return (distance <= spacing).then_some((edge, edge, 0.));
+    }
+
+    let after = axis.partition_point(|&v| v <= value).min(axis.len() - 1);
+    let before = after.saturating_sub(1);
+    let t = match axis[after] - axis[before] {
+        0. => 0.,
+        width => (value - axis[before]) / width,
+    };
+
+    Some((before, after, t))
+}
+
+/// Fields read from input files.
+#[derive(Default)]
+pub struct Fields {
+    grids: HashMap<Quantity, Grid>,
+}
+
+impl Fields {
+    /// Adds a grid of `quantity`, whose values are in `unit`.
+    fn insert(&mut self, quantity: Quantity, unit: &str, mut grid: Grid) -> Result<()> {
+        let conversion = quantity.conversion(unit)?;
+        grid.values
+            .iter_mut()
+            .for_each(|value| *value = conversion.apply(*value));
+
+        info!(
+            "Read {quantity:?} on a {}x{} grid (converted from {unit:?} to {:?})",
+            grid.lats.len(),
+            grid.lons.len(),
+            quantity.canonical_unit()
+        );
+        if self.grids.insert(quantity, grid).is_some() {
+            warn!("{quantity:?} was given more than once; using the last one");
+        }
+
+        Ok(())
+    }
+
+    /// Reads every field it recognizes from a file, picking the format from its extension.
+    pub fn read_file(&mut self, path: &Path) -> Result<()> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+
+        let result = match extension.as_str() {
+            "nc" | "nc3" | "cdf" | "netcdf" => netcdf::read(path, self),
+            "grib" | "grib2" | "grb" | "grb2" => grib::read(path, self),
+            "csv" => csv::read(path, self),
+            _ => anyhow::bail!(
+                "don't know the format of {}; expected a .nc, .grib2 or .csv file",
+                path.display()
+            ),
+        };
+
+        result.with_context(|| format!("importing {}", path.display()))
+    }
+
+    /// Writes the fields onto the raw state, leaving cells they don't cover alone.
+    ///
+    /// Haze comes from cloud cover, or relative humidity if there's no cloud cover.
+    fn apply(&self, buffer: &mut [u8]) {
+        let haze = self
+            .grids
+            .get(&Quantity::CloudCover)
+            .or_else(|| self.grids.get(&Quantity::RelativeHumidity));
+        let layers = [
+            (Layer::Temperature, self.grids.get(&Quantity::Temperature)),
+            (Layer::WindX, self.grids.get(&Quantity::WindU)),
+            // image rows go north to south, so the state's Y axis points south
+            (Layer::WindY, self.grids.get(&Quantity::WindV)),
+            (Layer::Haze, haze),
+        ];
+
+        for (layer, grid) in layers {
+            let Some(grid) = grid else {
+                warn!("No data for {layer:?}; keeping the base world's");
+                continue;
+            };
+            let sign = if layer == Layer::WindY { -1. } else { 1. };
+            let offset = state::layer_offset(layer);
+
+            let mut covered = 0;
+            for y in 0..state::MAP_HEIGHT {
+                for x in 0..state::MAP_WIDTH {
+                    let center = state::cell_center(x, y);
+                    let Some(value) = grid.sample(center.lat, center.long) else {
+                        continue;
+                    };
+
+                    let index = (y * state::MAP_WIDTH + x) * state::BYTES_PER_PIXEL + offset;
+                    buffer[index] = units::to_raw(layer, sign * value);
+                    covered += 1;
+                }
+            }
+
+            info!(
+                "{layer:?} covers {:.1}% of the map",
+                covered as f64 / (state::MAP_WIDTH * state::MAP_HEIGHT) as f64 * 100.
+            );
+        }
+    }
+}
+
+/// A calm world at 15 °C without haze, for imports that don't cover everything.
+fn neutral_world() -> WorldSnapshot {
+    let mut pixel = [0; state::BYTES_PER_PIXEL];
+    for (layer, value) in [
+        (Layer::Temperature, 288.15),
+        (Layer::WindX, 0.),
+        (Layer::WindY, 0.),
+        (Layer::Haze, 0.),
+    ] {
+        pixel[state::layer_offset(layer)] = units::to_raw(layer, value);
+    }
+
+    WorldSnapshot {
+        metadata: state::SnapshotMetadata::current(0),
+        data: pixel.repeat(state::MAP_WIDTH * state::MAP_HEIGHT),
+    }
+}
+
+/// Imports the given files on top of `base` (or a neutral world), saving the result to `output`.
+pub fn import(inputs: &[PathBuf], base: Option<&Path>, output: &Path) -> Result<()> {
+    let mut fields = Fields::default();
+    for input in inputs {
+        fields.read_file(input)?;
+    }
+    if fields.grids.is_empty() {
+        anyhow::bail!("no recognized fields in the input files");
+    }
+
+    let mut world = match base {
+        Some(base) => WorldSnapshot::load(base)
+            .with_context(|| format!("loading base snapshot {}", base.display()))?,
+        None => neutral_world(),
+    };
+    fields.apply(&mut world.data);
+
+    // the imported data is a new starting point rather than a continuation of the base, but the
+    // base's zones still protect it
+    let fresh = state::SnapshotMetadata::current(0);
+    world.metadata.tick = fresh.tick;
+    world.metadata.saved_at = fresh.saved_at;
+    world
+        .save(output)
+        .with_context(|| format!("saving {}", output.display()))?;
+    info!("Saved imported world to {}", output.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{LatLong, Rect, Region};
+    use crate::state::{Zone, ZonePolicy, MAP_HEIGHT, MAP_WIDTH};
+
+    /// An empty directory of its own for a test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("spacepaint-import-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A grid with the same value everywhere, around the whole globe.
+    fn uniform(value: f64) -> Grid {
+        Grid::new(vec![-90., 90.], vec![-180., 0.], vec![value; 4]).unwrap()
+    }
+
+    /// Raw value of a layer at a map cell.
+    fn raw_at(data: &[u8], layer: Layer, x: usize, y: usize) -> u8 {
+        data[(y * MAP_WIDTH + x) * state::BYTES_PER_PIXEL + state::layer_offset(layer)]
+    }
+
+    #[test]
+    fn grids_are_sorted_into_north_up_rows_and_wrapping_columns() {
+        // rows north first & columns from 0 to 270 east, as some files have them
+        let lats = vec![60., 0., -60.];
+        let lons = vec![0., 90., 180., 270.];
+        let values = [300., 280., 260.]
+            .into_iter()
+            .flat_map(|row| [0., 10., 20., 30.].map(|column| row + column))
+            .collect();
+        let grid = Grid::new(lats, lons, values).unwrap();
+
+        assert!(grid.wraps);
+        assert_eq!(grid.sample(60., 0.), Some(300.));
+        assert_eq!(grid.sample(-60., 90.), Some(270.));
+        // 270 east is 90 west
+        assert_eq!(grid.sample(0., -90.), Some(310.));
+        // halfway between 90 east & the antimeridian, across which the grid wraps
+        assert_eq!(grid.sample(0., 135.), Some(295.));
+        assert_eq!(grid.sample(0., -135.), Some(305.));
+        // up to a grid spacing past the last row is clamped onto it, so the poles are covered
+        assert_eq!(grid.sample(89., 0.), Some(300.));
+    }
+
+    #[test]
+    fn regional_grids_only_cover_their_region() {
+        let grid = Grid::new(vec![0., 10.], vec![0., 10.], vec![1.; 4]).unwrap();
+
+        assert!(!grid.wraps);
+        assert_eq!(grid.sample(5., 5.), Some(1.));
+        assert_eq!(grid.sample(5., 25.), None);
+        assert_eq!(grid.sample(-15., 5.), None);
+    }
+
+    #[test]
+    fn fields_are_regridded_onto_the_map_north_up() {
+        let mut fields = Fields::default();
+        let grid = Grid::new(
+            vec![-45., 45.],
+            vec![-180., 0.],
+            vec![260., 260., 300., 300.],
+        );
+        fields
+            .insert(Quantity::Temperature, "K", grid.unwrap())
+            .unwrap();
+
+        let mut data = neutral_world().data;
+        fields.apply(&mut data);
+
+        assert_eq!(data.len(), state::STATE_BYTES);
+        let north = units::to_raw(Layer::Temperature, 300.);
+        let south = units::to_raw(Layer::Temperature, 260.);
+        for x in [0, MAP_WIDTH / 2, MAP_WIDTH - 1] {
+            assert_eq!(raw_at(&data, Layer::Temperature, x, 0), north);
+            assert_eq!(raw_at(&data, Layer::Temperature, x, MAP_HEIGHT - 1), south);
+        }
+        // layers without data keep the base world's values
+        assert_eq!(raw_at(&data, Layer::Haze, 0, 0), 0);
+    }
+
+    #[test]
+    fn values_are_converted_to_raw_channels() {
+        let mut fields = Fields::default();
+        fields
+            .insert(Quantity::Temperature, "degC", uniform(15.))
+            .unwrap();
+        fields
+            .insert(Quantity::WindU, "m/s", uniform(1000.))
+            .unwrap();
+        fields
+            .insert(Quantity::WindV, "m s-1", uniform(10.))
+            .unwrap();
+        fields
+            .insert(Quantity::CloudCover, "%", uniform(50.))
+            .unwrap();
+
+        let mut data = neutral_world().data;
+        fields.apply(&mut data);
+
+        let raw = |layer| raw_at(&data, layer, MAP_WIDTH / 3, MAP_HEIGHT / 3);
+        assert_eq!(
+            raw(Layer::Temperature),
+            units::to_raw(Layer::Temperature, 288.15)
+        );
+        // far beyond what a channel holds, so clamped
+        assert_eq!(raw(Layer::WindX), u8::MAX);
+        // northward wind points up the map, against the state's Y axis
+        assert_eq!(raw(Layer::WindY), units::WIND_ZERO - 20);
+        assert_eq!(raw(Layer::Haze), 128);
+    }
+
+    #[test]
+    fn units_are_converted_to_canonical_ones() {
+        let convert =
+            |quantity: Quantity, unit, value| quantity.conversion(unit).unwrap().apply(value);
+
+        assert_eq!(convert(Quantity::Temperature, "K", 280.), 280.);
+        assert_eq!(convert(Quantity::Temperature, "degC", 0.), 273.15);
+        assert!((convert(Quantity::Temperature, "degF", 32.) - 273.15).abs() < 1e-9);
+        assert!((convert(Quantity::WindU, "km/h", 36.) - 10.).abs() < 1e-9);
+        assert_eq!(convert(Quantity::RelativeHumidity, "%", 80.), 0.8);
+        assert_eq!(convert(Quantity::CloudCover, "1", 0.3), 0.3);
+        assert!(Quantity::WindV.conversion("K").is_err());
+    }
+
+    #[test]
+    fn csv_imports_onto_a_base_keep_its_zones() {
+        let dir = scratch_dir("csv");
+        let csv = dir.join("weather.csv");
+        let mut rows = "lat,lon,temperature [degC],v_wind [m/s],humidity [%]\n".to_owned();
+        for lat in [-90, 90] {
+            for lon in [-180, -90, 0, 90] {
+                rows += &format!("{lat},{lon},-10,-4,25\n");
+            }
+        }
+        std::fs::write(&csv, rows).unwrap();
+
+        let mut base = neutral_world();
+        base.metadata.tick = 500;
+        base.metadata.zones.push(Zone {
+            name: "reserve".to_owned(),
+            region: Region::Rect(Rect {
+                top_left: LatLong {
+                    lat: 10.,
+                    long: -10.,
+                },
+                bottom_right: LatLong {
+                    lat: -10.,
+                    long: 10.,
+                },
+            }),
+            policy: ZonePolicy::Locked,
+        });
+        let base_path = dir.join("base.png");
+        base.save(&base_path).unwrap();
+        let output = dir.join("imported.png");
+
+        import(&[csv], Some(&base_path), &output).unwrap();
+        let imported = WorldSnapshot::load(&output).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(imported.metadata.tick, 0);
+        assert_eq!(imported.metadata.zones.len(), 1);
+        assert_eq!(imported.metadata.zones[0].name, "reserve");
+        for (x, y) in [
+            (0, 0),
+            (MAP_WIDTH / 2, MAP_HEIGHT / 2),
+            (MAP_WIDTH - 1, MAP_HEIGHT - 1),
+        ] {
+            let raw = |layer| raw_at(&imported.data, layer, x, y);
+            assert_eq!(
+                raw(Layer::Temperature),
+                units::to_raw(Layer::Temperature, 263.15)
+            );
+            assert_eq!(raw(Layer::WindX), units::WIND_ZERO);
+            assert_eq!(raw(Layer::WindY), units::WIND_ZERO + 8);
+            assert_eq!(raw(Layer::Haze), units::to_raw(Layer::Haze, 0.25));
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use log::warn;
+use std::path::Path;
+
+use super::{Fields, Grid, Quantity};
+
+/// Reads a CSV file with one row per grid point.
+///
+/// Needs `lat`/`latitude` and `lon`/`longitude` columns; value columns are named after their
+/// quantity with the unit in brackets, e.g. `temperature [degC]`, `u_wind [m/s]`, `cloud_cover [%]`.
+/// Columns without a unit are taken to be in kelvin, m/s or 0-1 fractions.
+pub fn read(path: &Path, fields: &mut Fields) -> Result<()> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)?;
+    let headers = reader.headers()?.clone();
+
+    let column = |names: &[&str]| {
+        headers
+            .iter()
+            .position(|header| names.contains(&header.to_ascii_lowercase().as_str()))
+    };
+    let lat_column = column(&["lat", "latitude"]).context("no latitude column")?;
+    let lon_column = column(&["lon", "long", "longitude"]).context("no longitude column")?;
+
+    // (column, quantity, unit) of each value column
+    let mut value_columns = Vec::new();
+    for (i, header) in headers.iter().enumerate() {
+        if i == lat_column || i == lon_column {
+            continue;
+        }
+
+        let (name, unit) = match header.split_once('[') {
+            Some((name, unit)) => (name.trim(), unit.trim_end_matches(']').trim()),
+            None => (header, ""),
+        };
+        match Quantity::from_name(name) {
+            Some(quantity) => {
+                let unit = if unit.is_empty() {
+                    quantity.canonical_unit()
+                } else {
+                    unit
+                };
+                value_columns.push((i, quantity, unit.to_owned()));
+            }
+            None => warn!("Skipping unrecognized column {header:?}"),
+        }
+    }
+
+    let mut points = vec![Vec::new(); value_columns.len()];
+    for (line, record) in reader.records().enumerate() {
+        let record = record?;
+        let number = |column: usize| -> Result<f64> {
+            let text = record.get(column).unwrap_or("");
+            text.parse()
+                .with_context(|| format!("line {}: {text:?} isn't a number", line + 2))
+        };
+
+        let (lat, lon) = (number(lat_column)?, number(lon_column)?);
+        for ((column, _, _), points) in value_columns.iter().zip(&mut points) {
+            // empty cells are missing values
+            if record.get(*column).is_some_and(|text| !text.is_empty()) {
+                points.push((lat, lon, number(*column)?));
+            }
+        }
+    }
+
+    for ((column, quantity, unit), points) in value_columns.into_iter().zip(points) {
+        if points.is_empty() {
+            warn!("Skipping column {:?} without any values", &headers[column]);
+            continue;
+        }
+        fields.insert(quantity, &unit, Grid::from_points(points)?)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_cells_are_missing_and_columns_without_units_canonical() {
+        let path = std::env::temp_dir().join(format!(
+            "spacepaint-import-columns-{}.csv",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "latitude,longitude,t2m,tcc,comment\n0,0,280,0.5,a\n0,10,,0.5,b\n10,0,290,,c\n10,10,300,0.5,d\n",
+        )
+        .unwrap();
+
+        let mut fields = Fields::default();
+        read(&path, &mut fields).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let temperature = &fields.grids[&Quantity::Temperature];
+        assert_eq!(temperature.sample(0., 0.), Some(280.));
+        // the missing corner is skipped rather than read as 0
+        assert_eq!(temperature.sample(0., 5.), Some(280.));
+        assert_eq!(temperature.sample(0., 10.), None);
+        assert_eq!(
+            fields.grids[&Quantity::CloudCover].sample(10., 10.),
+            Some(0.5)
+        );
+        assert_eq!(fields.grids.len(), 2);
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use super::{Fields, Grid, Quantity};
+
+/// Discipline of meteorological products.
+const METEOROLOGICAL: u8 = 0;
+
+/// Fixed surface types that count as "at the surface": the ground, and a height above it.
+const SURFACE_TYPES: [u8; 2] = [1, 103];
+
+/// Quantity & unit of a parameter, by (category, number) in code table 4.2 of discipline 0.
+fn parameter(category: u8, number: u8) -> Option<(Quantity, &'static str)> {
+    Some(match (category, number) {
+        (0, 0) => (Quantity::Temperature, "K"),
+        (1, 1) => (Quantity::RelativeHumidity, "%"),
+        (2, 2) => (Quantity::WindU, "m s-1"),
+        (2, 3) => (Quantity::WindV, "m s-1"),
+        (6, 1) => (Quantity::CloudCover, "%"),
+        _ => return None,
+    })
+}
+
+/// Reads recognized parameters from a GRIB2 file on latitude/longitude grids.
+///
+/// Files often hold a parameter at many levels; surface levels are preferred, otherwise the
+/// first message of that parameter is used.
+pub fn read(path: &Path, fields: &mut Fields) -> Result<()> {
+    let grib2 = grib::from_reader(BufReader::new(File::open(path)?))
+        .map_err(|e| anyhow!("reading GRIB2: {e}"))?;
+
+    // (is surface, message index) of the message to use for each quantity
+    let mut chosen: HashMap<Quantity, (bool, (usize, usize), &'static str)> = HashMap::new();
+    for (index, submessage) in grib2.iter() {
+        if submessage.indicator().discipline != METEOROLOGICAL {
+            continue;
+        }
+        let prod_def = submessage.prod_def();
+        let (Some(category), Some(number)) =
+            (prod_def.parameter_category(), prod_def.parameter_number())
+        else {
+            continue;
+        };
+        let Some((quantity, unit)) = parameter(category, number) else {
+            continue;
+        };
+
+        let surface = prod_def
+            .fixed_surfaces()
+            .is_some_and(|(first, _)| SURFACE_TYPES.contains(&first.surface_type));
+        let better = chosen
+            .get(&quantity)
+            .is_none_or(|(chosen_surface, _, _)| surface && !chosen_surface);
+        if better {
+            chosen.insert(quantity, (surface, index, unit));
+        }
+    }
+
+    for (quantity, (surface, index, unit)) in chosen {
+        let (_, submessage) = grib2
+            .iter()
+            .find(|(i, _)| *i == index)
+            .expect("chosen message should exist");
+        debug!("Using message {index:?} for {quantity:?} (surface level: {surface})");
+
+        let latlons = submessage
+            .latlons()
+            .map_err(|e| anyhow!("message {index:?} isn't on a supported grid: {e}"))?;
+        let decoder = grib::Grib2SubmessageDecoder::from(submessage)
+            .map_err(|e| anyhow!("decoding message {index:?}: {e}"))?;
+        let values = decoder
+            .dispatch()
+            .map_err(|e| anyhow!("decoding message {index:?}: {e}"))?;
+
+        let grid = Grid::from_points(
+            latlons
+                .zip(values)
+                .map(|((lat, lon), value)| (lat as f64, lon as f64, value as f64)),
+        )?;
+        fields.insert(quantity, unit, grid)?;
+    }
+
+    info!("Read {} GRIB2 messages", grib2.len());
+
+    Ok(())
+}
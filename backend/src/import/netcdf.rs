@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use std::path::Path;
+
+use crate::netcdf::{NetCdf, Variable};
+
+use super::{Fields, Grid, Quantity};
+
+const LAT_NAMES: [&str; 3] = ["lat", "latitude", "y"];
+const LON_NAMES: [&str; 3] = ["lon", "longitude", "x"];
+
+/// Reads every variable with latitude & longitude as its last two dimensions that's recognized
+/// by its `standard_name` or name. Leading dimensions like time or level are read at index 0.
+pub fn read(path: &Path, fields: &mut Fields) -> Result<()> {
+    let file = NetCdf::open(path)?;
+
+    let lat_dim = find_dim(&file, &LAT_NAMES).context("no latitude dimension")?;
+    let lon_dim = find_dim(&file, &LON_NAMES).context("no longitude dimension")?;
+    let lats = coordinates(&file, lat_dim)?;
+    let lons = coordinates(&file, lon_dim)?;
+
+    for var in &file.vars {
+        let quantity = var
+            .text_attr("standard_name")
+            .and_then(Quantity::from_name)
+            .or_else(|| Quantity::from_name(&var.name));
+        let Some(quantity) = quantity else {
+            debug!("Skipping unrecognized variable {}", var.name);
+            continue;
+        };
+
+        let lat_lon_last = match var.dims.as_slice() {
+            [.., a, b] => (*a, *b) == (lat_dim, lon_dim) || (*a, *b) == (lon_dim, lat_dim),
+            _ => false,
+        };
+        if !lat_lon_last {
+            warn!(
+                "Skipping {}: latitude & longitude aren't its last dimensions",
+                var.name
+            );
+            continue;
+        }
+
+        let values = slice_values(&file, var, lats.len() * lons.len())?;
+        let values = if var.dims[var.dims.len() - 1] == lat_dim {
+            transpose(&values, lons.len(), lats.len())
+        } else {
+            values
+        };
+
+        let unit = var.text_attr("units").unwrap_or("");
+        fields.insert(
+            quantity,
+            unit,
+            Grid::new(lats.clone(), lons.clone(), values)
+                .with_context(|| format!("reading variable {}", var.name))?,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn find_dim(file: &NetCdf, names: &[&str]) -> Option<usize> {
+    file.dims
+        .iter()
+        .position(|dim| names.contains(&dim.name.to_ascii_lowercase().as_str()))
+}
+
+/// Values of the coordinate variable of a dimension.
+fn coordinates(file: &NetCdf, dim: usize) -> Result<Vec<f64>> {
+    let name = &file.dims[dim].name;
+    let var = file
+        .var(name)
+        .with_context(|| format!("no coordinate variable for dimension {name}"))?;
+
+    unpack(var, file.read(var, 0)?)
+}
+
+/// Reads the first `count` values of the variable (i.e. the first time step/level), unpacked.
+fn slice_values(file: &NetCdf, var: &Variable, count: usize) -> Result<Vec<f64>> {
+    let mut values = file.read(var, 0)?;
+    values.truncate(count);
+
+    unpack(var, values)
+}
+
+/// Applies `scale_factor`/`add_offset` and turns fill values into NaN.
+fn unpack(var: &Variable, values: Vec<f64>) -> Result<Vec<f64>> {
+    let scale = var.number_attr("scale_factor").unwrap_or(1.);
+    let offset = var.number_attr("add_offset").unwrap_or(0.);
+    let fill = var
+        .number_attr("_FillValue")
+        .or_else(|| var.number_attr("missing_value"));
+
+    Ok(values
+        .into_iter()
+        .map(|value| {
+            if fill.is_some_and(|fill| value == fill) {
+                f64::NAN
+            } else {
+                value * scale + offset
+            }
+        })
+        .collect())
+}
+
+/// Turns `rows` x `columns` row-major values into column-major ones.
+fn transpose(values: &[f64], rows: usize, columns: usize) -> Vec<f64> {
+    (0..columns)
+        .flat_map(|column| (0..rows).map(move |row| values[row * columns + column]))
+        .collect()
+}
@@ -0,0 +1,428 @@
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::message::{self, Layer, Rect};
+use crate::persistence::FreshWorld;
+use crate::state::{self, Zone};
+
+/// A change that was applied to the world, as recorded in the journal.
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    /// Tick the state was at when the change was applied (i.e. after that many ticks).
+    pub tick: u64,
+
+    /// Milliseconds since the Unix epoch the change was applied at.
+    pub timestamp_ms: u64,
+
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Change {
+    /// A modification (or undo/redo) sent by a client.
+    Modification {
+        client_id: u64,
+
+        /// Address the client connected from, if known.
+        peer: Option<SocketAddr>,
+
+        /// Role of the client, which protected zones may depend on. Missing in journals from
+        /// before roles existed, when every client could paint.
+        #[serde(default)]
+        role: Option<message::Role>,
+
+        packet: message::Packet,
+    },
+
+    /// An edit made through the admin API.
+    Admin { admin: AdminEdit },
+}
+
+/// Edits of the admin API, with what replaying them needs.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum AdminEdit {
+    /// Layers within `area` set to a raw value each.
+    Fill {
+        area: Rect,
+        values: Vec<(Layer, u8)>,
+    },
+
+    /// Layers within `area` restored to how they are in a fresh world.
+    Reset {
+        area: Rect,
+        layers: Vec<Layer>,
+        fresh_world: FreshWorld,
+    },
+
+    /// The whole world replaced by a snapshot file, which was at `snapshot_tick`.
+    Load {
+        path: PathBuf,
+        snapshot_tick: u64,
+    },
+
+    SetZone(Zone),
+    RemoveZone {
+        name: String,
+    },
+}
+
+/// Append-only log of applied modifications, one JSON object per line.
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Journal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())
+            .with_context(|| format!("opening journal {}", path.as_ref().display()))?;
+
+        Ok(Journal { file })
+    }
+
+    /// Appends an entry, flushing it to the OS straight away so it survives a crash of the server.
+    ///
+    /// Strokes with non-finite numbers are refused, since JSON writes them as `null` and the
+    /// journal then couldn't be read back.
+    pub fn record(&mut self, entry: &Entry) -> Result<()> {
+        if let Change::Modification {
+            packet:
+                message::Packet::Modification {
+                    points,
+                    brush_size_degrees,
+                    ..
+                },
+            ..
+        } = &entry.change
+        {
+            if !brush_size_degrees.is_finite() || !points.iter().all(message::LatLong::is_finite) {
+                anyhow::bail!("refusing to journal a stroke with non-finite numbers");
+            }
+        }
+
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+
+        Ok(())
+    }
+
+    /// Records an admin edit applied at `tick`, logging rather than failing if it can't be written
+    /// since the edit has already been made.
+    pub fn record_admin(&mut self, tick: u64, edit: AdminEdit) {
+        let entry = Entry {
+            tick,
+            timestamp_ms: state::now_ms(),
+            change: Change::Admin { admin: edit },
+        };
+        if let Err(e) = self.record(&entry) {
+            error!("couldn't write admin edit to journal: {e:#}");
+        }
+    }
+}
+
+/// Reads all entries of a journal, in the order they were written.
+pub fn read_entries<P: AsRef<Path>>(path: P) -> Result<Vec<Entry>> {
+    let file = File::open(path.as_ref())
+        .with_context(|| format!("opening journal {}", path.as_ref().display()))?;
+
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|l| l.trim().is_empty()))
+        .map(|(number, line)| {
+            serde_json::from_str(&line?)
+                .with_context(|| format!("parsing journal line {}", number + 1))
+        })
+        .collect()
+}
+
+/// Rebuilds a world by applying a journal to a base snapshot, simulating the ticks in between.
+///
+/// Stops after the last entry, or keeps ticking until `until_tick` if that's later.
+pub async fn replay<P: AsRef<Path>>(
+    base: P,
+    journal: P,
+    output: P,
+    until_tick: Option<u64>,
+    settings: state::Settings,
+) -> Result<()> {
+    let mut state =
+        state::State::from_snapshot(state::WorldSnapshot::load(base)?, settings).await?;
+    let entries = read_entries(journal)?;
+    info!(
+        "Replaying {} journal entries from tick {}",
+        entries.len(),
+        state.tick()
+    );
+
+    let scheduled = schedule(state.tick(), entries, until_tick)?;
+
+    let applied = scheduled.len();
+    for (ticks_needed, entry) in scheduled {
+        if ticks_needed > 0 {
+            state.tick_state_by_count(ticks_needed.try_into()?).await?;
+        }
+
+        match entry.change {
+            Change::Modification {
+                client_id,
+                role,
+                packet,
+                ..
+            } => {
+                let role = role.unwrap_or(message::Role::Painter);
+                if let Err(e) = state.process_modification(client_id, role, &packet) {
+                    warn!("error replaying entry from client {client_id}: {e}");
+                }
+            }
+            // carrying on without an admin edit would silently diverge from the recorded world
+            Change::Admin { admin } => replay_admin_edit(&mut state, &admin)
+                .with_context(|| format!("replaying admin edit at tick {}", entry.tick))?,
+        }
+    }
+
+    if let Some(until) = until_tick.filter(|until| *until > state.tick()) {
+        let remaining = until - state.tick();
+        state.tick_state_by_count(remaining.try_into()?).await?;
+    }
+
+    state.snapshot().save(output.as_ref())?;
+    info!(
+        "Applied {applied} entries; saved tick {} to {}",
+        state.tick(),
+        output.as_ref().display()
+    );
+
+    Ok(())
+}
+
+/// Picks the entries to replay on top of a base snapshot at `base_tick`, each with the number of
+/// ticks to simulate before applying it, up to `until_tick` if given.
+///
+/// Checked before simulating anything, since a journal with a gap can't be replayed.
+fn schedule(
+    base_tick: u64,
+    entries: Vec<Entry>,
+    until_tick: Option<u64>,
+) -> Result<Vec<(u64, Entry)>> {
+    let mut tick = base_tick;
+    let mut scheduled = Vec::new();
+
+    for entry in entries {
+        if until_tick.is_some_and(|until| entry.tick > until) {
+            break;
+        }
+        // loading a snapshot jumps to its tick, whichever way that is
+        let loaded_tick = match &entry.change {
+            Change::Admin {
+                admin: AdminEdit::Load { snapshot_tick, .. },
+            } => Some(*snapshot_tick),
+            _ => None,
+        };
+        if entry.tick < tick && loaded_tick.is_none() {
+            if !scheduled.is_empty() {
+                // the world went back without a load we know of, e.g. restored from a backup
+                anyhow::bail!(
+                    "journal goes back from tick {tick} to {} after {} entries, so it's missing \
+                     whatever changed the world in between",
+                    entry.tick,
+                    scheduled.len()
+                );
+            }
+            warn!(
+                "Skipping entry from tick {} which predates the base snapshot",
+                entry.tick
+            );
+            continue;
+        }
+
+        let ticks_needed = match loaded_tick {
+            Some(_) => 0,
+            None => entry.tick - tick,
+        };
+        tick = loaded_tick.unwrap_or(entry.tick);
+        scheduled.push((ticks_needed, entry));
+    }
+
+    Ok(scheduled)
+}
+
+fn replay_admin_edit(state: &mut state::State, edit: &AdminEdit) -> Result<()> {
+    match edit {
+        AdminEdit::Fill { area, values } => {
+            let layers: Vec<Layer> = values.iter().map(|(layer, _)| *layer).collect();
+            state.write_region(*area, &layers, |layer, _| {
+                values
+                    .iter()
+                    .find(|(filled, _)| *filled == layer)
+                    .map_or(0, |(_, raw)| *raw)
+            })?;
+        }
+        AdminEdit::Reset {
+            area,
+            layers,
+            fresh_world,
+        } => {
+            let fresh = fresh_world.create()?;
+            state.write_region(*area, layers, |layer, pixel| {
+                fresh.data[pixel * state::BYTES_PER_PIXEL + state::layer_offset(layer)]
+            })?;
+        }
+        AdminEdit::Load {
+            path,
+            snapshot_tick,
+        } => {
+            let snapshot = state::WorldSnapshot::load(path)?;
+            if snapshot.metadata.tick != *snapshot_tick {
+                anyhow::bail!(
+                    "{} is at tick {} but was at tick {snapshot_tick} when loaded, so it has changed since",
+                    path.display(),
+                    snapshot.metadata.tick
+                );
+            }
+            state.replace(snapshot);
+        }
+        AdminEdit::SetZone(zone) => {
+            state.set_zone(zone.clone());
+        }
+        AdminEdit::RemoveZone { name } => {
+            state.remove_zone(name);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{LatLong, ModificationType};
+
+    fn stroke(points: Vec<LatLong>) -> Entry {
+        Entry {
+            tick: 3,
+            timestamp_ms: 3000,
+            change: Change::Modification {
+                client_id: 1,
+                peer: None,
+                role: Some(message::Role::Painter),
+                packet: message::Packet::Modification {
+                    tpe: ModificationType::Heat,
+                    points,
+                    brush_size_degrees: 2.,
+                    client_id: 1,
+                },
+            },
+        }
+    }
+
+    fn entry(tick: u64, admin: AdminEdit) -> Entry {
+        Entry {
+            tick,
+            timestamp_ms: tick * 1000,
+            change: Change::Admin { admin },
+        }
+    }
+
+    fn edit(tick: u64) -> Entry {
+        entry(
+            tick,
+            AdminEdit::RemoveZone {
+                name: "zone".to_string(),
+            },
+        )
+    }
+
+    fn load(tick: u64, snapshot_tick: u64) -> Entry {
+        entry(
+            tick,
+            AdminEdit::Load {
+                path: PathBuf::from("backup.bin"),
+                snapshot_tick,
+            },
+        )
+    }
+
+    /// Ticks to simulate before each scheduled entry, with the tick it was recorded at.
+    fn ticks(scheduled: &[(u64, Entry)]) -> Vec<(u64, u64)> {
+        scheduled
+            .iter()
+            .map(|(needed, entry)| (*needed, entry.tick))
+            .collect()
+    }
+
+    #[test]
+    fn entries_are_applied_at_their_tick() {
+        let scheduled = schedule(10, vec![edit(10), edit(15), edit(15), edit(20)], None).unwrap();
+        assert_eq!(ticks(&scheduled), [(0, 10), (5, 15), (0, 15), (5, 20)]);
+
+        let scheduled = schedule(10, vec![edit(15), edit(20), edit(25)], Some(20)).unwrap();
+        assert_eq!(ticks(&scheduled), [(5, 15), (5, 20)]);
+    }
+
+    #[test]
+    fn entries_before_the_base_snapshot_are_skipped() {
+        let scheduled = schedule(10, vec![edit(5), edit(8), edit(12)], None).unwrap();
+        assert_eq!(ticks(&scheduled), [(2, 12)]);
+    }
+
+    #[test]
+    fn loads_jump_to_the_snapshot_tick() {
+        // back to an earlier snapshot, then on from there
+        let scheduled = schedule(10, vec![edit(20), load(30, 5), edit(8)], None).unwrap();
+        assert_eq!(ticks(&scheduled), [(10, 20), (0, 30), (3, 8)]);
+
+        // forward to a later one without simulating the ticks in between
+        let scheduled = schedule(10, vec![load(12, 50), edit(55)], None).unwrap();
+        assert_eq!(ticks(&scheduled), [(0, 12), (5, 55)]);
+    }
+
+    #[test]
+    fn going_back_without_a_load_is_refused() {
+        assert!(schedule(10, vec![edit(20), edit(15)], None).is_err());
+        assert!(schedule(10, vec![edit(20), load(25, 5), edit(3)], None).is_err());
+    }
+
+    #[test]
+    fn strokes_are_read_back_as_recorded() {
+        let path =
+            std::env::temp_dir().join(format!("spacepaint-journal-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut journal = Journal::open(&path).unwrap();
+
+        let point = LatLong {
+            lat: 12.5,
+            long: -40.25,
+        };
+        journal.record(&stroke(vec![point])).unwrap();
+        let nan = LatLong {
+            lat: f64::NAN,
+            long: 0.,
+        };
+        assert!(journal.record(&stroke(vec![point, nan])).is_err());
+
+        let entries = read_entries(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].tick, 3);
+        let Change::Modification {
+            packet: message::Packet::Modification { points, .. },
+            ..
+        } = &entries[0].change
+        else {
+            panic!("stroke read back as something else");
+        };
+        assert_eq!((points[0].lat, points[0].long), (12.5, -40.25));
+    }
+}
@@ -1,24 +1,153 @@
+use anyhow::Context;
+use clap::Parser;
 use flexbuffers::Reader;
-use futures::{SinkExt, StreamExt};
-use log::{debug, info, warn};
-use serde::Deserialize;
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::Mutex;
 use warp::ws::{self, WebSocket};
 use warp::Filter;
 
+mod admin;
+mod auth;
+mod config;
+mod export;
+mod health;
+mod import;
+mod journal;
 mod message;
+mod metrics;
+mod netcdf;
+mod outbound;
+mod persistence;
+mod room;
+mod scenario;
 mod state;
 
+/// A single view a client has subscribed to.
+#[derive(Clone)]
+struct Viewport {
+    /// Area covered by the view.
+    area: message::Rect,
+
+    /// Snapshot size requested by the client, if any.
+    resolution: Option<message::Resolution>,
+
+    /// Layers included in snapshots of this view.
+    layers: Vec<message::Layer>,
+}
+
 struct Client {
-    /// Viewport last sent by client, if applicable.
-    viewport: Option<message::Rect>,
+    /// Viewports last sent by client, keyed by their client-chosen IDs.
+    viewports: HashMap<u32, Viewport>,
+
+    /// Snapshot encoding requested in the client's handshake.
+    encoding: message::SnapshotEncoding,
+
+    /// Address the client connected from, if known.
+    peer: Option<SocketAddr>,
+
+    /// What the client's token allows it to do.
+    role: message::Role,
+
+    /// Who the client's token was issued to, if it had one.
+    subject: Option<String>,
+
+    /// Queue of packets for the client's writer task.
+    outbound: outbound::ClientSender,
+
+    /// Task writing queued packets to the client websocket.
+    writer: tokio::task::JoinHandle<()>,
+
+    /// Stops the connection's reader once fired or dropped, so a client that's been removed (e.g.
+    /// kicked) can't keep queueing modifications while its close frame goes unanswered.
+    removed: tokio::sync::oneshot::Sender<()>,
+}
+
+impl Client {
+    /// Stops reading from the client right away, then writes its queued packets & a close frame,
+    /// giving up and aborting the writer if the socket doesn't take them within [`CLOSE_TIMEOUT`].
+    fn close(self, code: u16, reason: &'static str) -> impl std::future::Future<Output = ()> {
+        let Client {
+            outbound,
+            writer,
+            removed,
+            ..
+        } = self;
+        drop(removed);
+        let abort = writer.abort_handle();
+
+        // queueing the close frame waits too when the queue is full, so it counts towards the limit
+        let closing = async move {
+            outbound.close(code, reason).await;
+            drop(outbound);
+            writer.await
+        };
+        async move {
+            if tokio::time::timeout(CLOSE_TIMEOUT, closing).await.is_err() {
+                warn!("Gave up waiting for a client's websocket to close");
+                abort.abort();
+            }
+        }
+    }
+}
+
+/// What a connection's reader gets next.
+enum Incoming {
+    Message(ws::Message),
+    Failed(warp::Error),
+
+    /// The client ended the connection.
+    Closed,
+
+    /// The client sent nothing within the heartbeat timeout.
+    TimedOut,
+
+    /// The client was removed from its room.
+    Removed,
+}
+
+/// Waits for the next message of a connection, unless the client is removed first.
+async fn next_incoming<S>(
+    stream: &mut S,
+    removed: &mut tokio::sync::oneshot::Receiver<()>,
+    timeout: std::time::Duration,
+) -> Incoming
+where
+    S: futures::Stream<Item = Result<ws::Message, warp::Error>> + Unpin,
+{
+    tokio::select! {
+        // checked first so nothing more is read once the client's gone
+        biased;
+        _ = removed => Incoming::Removed,
+        next = tokio::time::timeout(timeout, stream.next()) => match next {
+            Ok(Some(Ok(message))) => Incoming::Message(message),
+            Ok(Some(Err(e))) => Incoming::Failed(e),
+            Ok(None) => Incoming::Closed,
+            Err(_) => Incoming::TimedOut,
+        },
+    }
+}
+
+/// How long closing a client waits for its queued packets & close frame to be written.
+const CLOSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
 
-    /// Write end of the client websocket.
-    ws_sink: futures::stream::SplitSink<WebSocket, ws::Message>,
+/// Most presence updates relayed for a client each second; others are dropped. The frontend sends
+/// one per 50 ms, but one per mouse move while painting, so this allows one per frame at 120 Hz.
+const MAX_PRESENCE_PER_SEC: u32 = 120;
+
+/// Modification waiting to be applied, along with who sent it.
+struct QueuedModification {
+    client_id: u64,
+
+    /// Address the client connected from, if known.
+    peer: Option<SocketAddr>,
+
+    role: message::Role,
+
+    packet: message::Packet,
 }
 
 struct GlobalState {
@@ -27,222 +156,1043 @@ struct GlobalState {
 
     /// Map from client IDs to client info.
     clients: HashMap<u64, Client>,
+
+    /// Journal of changes made to `map`, written under the same lock so entries keep the order the
+    /// changes were applied in.
+    journal: journal::Journal,
+}
+
+/// Who is on the other end of a new websocket connection.
+struct Connection {
+    /// Address the client connected from, if known.
+    peer: Option<SocketAddr>,
+
+    role: message::Role,
+
+    /// Who the client's token was issued to, if it had one.
+    subject: Option<String>,
+}
+
+/// Query string of websocket upgrade requests.
+#[derive(Deserialize)]
+struct SyncQuery {
+    /// Signed token, as an alternative to the cookie.
+    token: Option<String>,
+}
+
+/// Query string of metrics scrapes.
+#[derive(Deserialize)]
+struct MetricsQuery {
+    /// Room whose modification queue is reported; the default room if missing.
+    room: Option<String>,
+}
+
+/// Cookie a signed token can be given in.
+const TOKEN_COOKIE: &str = "spacepaint_token";
+
+/// Limits & timings that apply to every websocket connection.
+#[derive(Clone, Copy)]
+struct ConnectionSettings {
+    max_snapshot_pixels: u32,
+
+    /// Packets each client can have waiting to be written.
+    client_queue_size: usize,
+
+    /// Time between pings sent to the client.
+    heartbeat_interval: std::time::Duration,
+
+    /// Clients that send nothing for this long are dropped.
+    heartbeat_timeout: std::time::Duration,
+
+    /// Widest brush clients may paint with.
+    max_brush_degrees: f64,
 }
 
 fn start_syncing(
     websocket: ws::Ws,
-    state_shard: Arc<Mutex<GlobalState>>,
-    modification_sink: tokio::sync::mpsc::Sender<message::Packet>,
+    connection: Connection,
+    membership: room::Membership,
+    settings: ConnectionSettings,
+    connection_permit: tokio::sync::OwnedSemaphorePermit,
+    metrics: metrics::Metrics,
 ) -> impl warp::Reply {
-    info!("New websocket connection");
+    let Connection {
+        peer,
+        role,
+        subject,
+    } = connection;
+    info!(
+        "New websocket connection from {peer:?} to room {} as {role:?} ({subject:?})",
+        membership.name
+    );
+    let state_shard = membership.state_shard.clone();
+    let modification_sink = membership.modification_sink.clone();
 
     websocket.on_upgrade(move |actual_ws: WebSocket| async move {
         // split websocket into stream and sink ends
-        let (mut sink, mut stream) = actual_ws.split();
+        let (sink, mut stream) = actual_ws.split();
 
         // generate a random client ID & send to client
         let client_id: u64 = rand::random();
-        let id_packet = message::Packet::AssignId { client_id };
+        let id_packet = message::Packet::AssignId {
+            client_id,
+            max_snapshot_pixels: settings.max_snapshot_pixels,
+            role,
+        };
         let id_payload =
             message::serialize_packet(id_packet).expect("couldn't serialize client ID packet");
-        sink.send(ws::Message::binary(id_payload))
-            .await
-            .expect("couldn't send ID packet to client");
 
+        let (outbound, writer) =
+            outbound::spawn_writer(client_id, sink, settings, metrics.clone());
+        outbound.send_reliable("assign_id", id_payload).await;
+        let (removed, mut removal) = tokio::sync::oneshot::channel();
         let client_info = Client {
-            viewport: None,
-            ws_sink: sink,
+            viewports: HashMap::new(),
+            encoding: message::SnapshotEncoding::Png,
+            peer,
+            role,
+            subject,
+            outbound,
+            writer,
+            removed,
         };
 
         // add sink/viewport to global state to send updates to
         {
             let mut locked_state = state_shard.lock().await;
             locked_state.clients.insert(client_id, client_info);
+            metrics.client_connected();
             info!("New client connected with id {client_id}");
         }
 
         // task to process incoming messages
         tokio::spawn(async move {
-            while let Some(message) = stream.next().await {
-                match message {
-                    Ok(message) => {
-                        let packet = message.as_bytes();
-
-                        if message.is_binary() {
-                            let message_reader = Reader::get_root(packet)
-                                .expect("couldn't construct flexbuffer reader for packet body");
-                            let payload = message::Packet::deserialize(message_reader)
-                                .expect("couldn't deserialize packet from websocket message");
-
-                            match payload {
-                                message::Packet::Snapshot { .. } | message::Packet::AssignId { .. } => {
-                                    warn!("received server-only packet from client, this shouldn't happen");
+            // held for as long as the connection is
+            let _connection_permit = connection_permit;
+            let _membership = membership;
+            // start of the current second & presence updates relayed within it
+            let mut presence_window = (std::time::Instant::now(), 0);
+
+            loop {
+                // pongs to the writer's pings count as signs of life too
+                let message = match next_incoming(&mut stream, &mut removal, settings.heartbeat_timeout).await {
+                    Incoming::Message(message) => message,
+                    Incoming::Failed(e) => {
+                        warn!("error receiving message from client {client_id}: {e}");
+                        break;
+                    }
+                    Incoming::Closed => break,
+                    Incoming::TimedOut => {
+                        warn!("Client {client_id} sent nothing for {:?}, dropping it", settings.heartbeat_timeout);
+                        metrics.record_dropped_connection("heartbeat_timeout");
+                        break;
+                    }
+                    Incoming::Removed => {
+                        debug!("Client {client_id} was removed, ignoring anything else it sends");
+                        break;
+                    }
+                };
+
+                let packet = message.as_bytes();
+
+                if message.is_binary() {
+                    // a malformed frame is the client's problem, so skip it rather than the cleanup below
+                    let decoded = Reader::get_root(packet)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|reader| Ok(message::Packet::deserialize(reader)?));
+                    let payload = match decoded {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            warn!("Ignored malformed packet from client {client_id}: {e:#}");
+                            continue;
+                        }
+                    };
+
+                    match payload {
+                        message::Packet::Snapshot { .. }
+                        | message::Packet::HistorySnapshot { .. }
+                        | message::Packet::ModificationRejected { .. }
+                        | message::Packet::Stats { .. }
+                        | message::Packet::AssignId { .. } => {
+                            warn!("received server-only packet from client, this shouldn't happen");
+                        }
+                        modif @ (message::Packet::Modification { .. }
+                        | message::Packet::Undo { .. }
+                        | message::Packet::Redo { .. }) => {
+                            if role < message::Role::Painter {
+                                warn!("Ignored modification from client {client_id}, which may only view");
+                                continue;
+                            }
+                            if let message::Packet::Modification { brush_size_degrees, ref points, .. } = modif {
+                                // checked before queueing, since painting takes the square of the width
+                                if !(brush_size_degrees > 0. && brush_size_degrees <= settings.max_brush_degrees) {
+                                    warn!("Ignored stroke of client {client_id} with brush size {brush_size_degrees}");
+                                    continue;
                                 }
-                                modif @ message::Packet::Modification { .. } => {
-                                    modification_sink
-                                        .send(modif)
-                                        .await
-                                        .expect("couldn't send modification to sink");
-                                    debug!("Received modification packet");
+                                if !points.iter().all(message::LatLong::is_finite) {
+                                    warn!("Ignored stroke of client {client_id} with non-finite points");
+                                    continue;
+                                }
+                            }
+
+                            // the connection's ID is recorded rather than the one claimed by the packet
+                            let queued = QueuedModification {
+                                client_id,
+                                peer,
+                                role,
+                                packet: modif,
+                            };
+                            if modification_sink.send(queued).await.is_err() {
+                                warn!("Dropped modification from client {client_id} while shutting down");
+                            } else {
+                                debug!("Received modification packet");
+                            }
+                        }
+                        // viewports are always the connection's own, whatever ID the packet claims
+                        message::Packet::Viewport {
+                            area,
+                            viewport_id,
+                            resolution,
+                            layers,
+                            ..
+                        } => {
+                            let Some(area) = area.normalized() else {
+                                warn!("Ignored viewport {viewport_id} of client {client_id} with empty area {area:?}");
+                                continue;
+                            };
+                            let mut locked_state = state_shard.lock().await;
+
+                            match locked_state.clients.get_mut(&client_id) {
+                                Some(client) => {
+                                    debug!("Updated viewport {viewport_id} to {area:?} at {resolution:?} with {layers:?}");
+                                    client.viewports.insert(
+                                        viewport_id,
+                                        Viewport {
+                                            area,
+                                            resolution,
+                                            layers,
+                                        },
+                                    );
                                 }
-                                message::Packet::Viewport { area, client_id } => {
-                                    let mut locked_state = state_shard.lock().await;
-
-                                    match locked_state.clients.get_mut(&client_id) {
-                                        Some(client) => {
-                                            client.viewport = Some(area);
-                                            debug!("Updated viewport to {area:?}");
-                                        }
-                                        None => warn!(
-                                            "received viewport packet from nonexistent client {client_id}"
-                                        ),
+                                None => warn!(
+                                    "received viewport packet from nonexistent client {client_id}"
+                                ),
+                            }
+                        }
+                        message::Packet::CloseViewport { viewport_id, .. } => {
+                            let mut locked_state = state_shard.lock().await;
+
+                            match locked_state.clients.get_mut(&client_id) {
+                                Some(client) => {
+                                    if client.viewports.remove(&viewport_id).is_none() {
+                                        warn!("Client {client_id} tried to close nonexistent viewport {viewport_id}");
                                     }
                                 }
+                                None => warn!(
+                                    "received close viewport packet from nonexistent client {client_id}"
+                                ),
+                            }
+                        }
+                        message::Packet::Handshake { encoding, .. } => {
+                            let mut locked_state = state_shard.lock().await;
+
+                            match locked_state.clients.get_mut(&client_id) {
+                                Some(client) => {
+                                    debug!("Client {client_id} requested {encoding:?} snapshots");
+                                    client.encoding = encoding;
+                                }
+                                None => warn!(
+                                    "received handshake packet from nonexistent client {client_id}"
+                                ),
+                            }
+                        }
+                        message::Packet::HistoryRequest { viewport_id, at, .. } => {
+                            let request = {
+                                let locked_state = state_shard.lock().await;
+
+                                let Some(client) = locked_state.clients.get(&client_id) else {
+                                    warn!("received history request from nonexistent client {client_id}");
+                                    continue;
+                                };
+                                let Some(view) = client.viewports.get(&viewport_id) else {
+                                    warn!("Client {client_id} requested history for nonexistent viewport {viewport_id}");
+                                    continue;
+                                };
+
+                                locked_state.map.history_frame(at).map(|found| {
+                                    (found, view.clone(), client.encoding.clone(), client.outbound.clone())
+                                })
+                            };
+                            let Some(((frame, shared), view, encoding, outbound)) = request else {
+                                debug!("No history recorded yet for request at {at:?}");
+                                continue;
+                            };
+
+                            // render off the lock & off the async runtime
+                            let rendered = tokio::task::spawn_blocking(move || {
+                                let (data, location) = shared
+                                    .render(view.area, view.resolution, &view.layers, &encoding)
+                                    .context("rendering history frame")?;
+                                let packet = message::Packet::HistorySnapshot {
+                                    viewport_id,
+                                    tick: frame.tick,
+                                    timestamp_ms: frame.timestamp_ms,
+                                    oldest_tick: frame.oldest_tick,
+                                    newest_tick: frame.newest_tick,
+                                    data,
+                                    location,
+                                };
+                                message::serialize_packet(packet)
+                                    .context("serializing history snapshot packet")
+                            })
+                            .await;
+                            let payload = match rendered {
+                                Ok(Ok(payload)) => payload,
+                                Ok(Err(e)) => {
+                                    warn!("couldn't answer history request of client {client_id}: {e:#}");
+                                    continue;
+                                }
+                                Err(e) => {
+                                    error!("history rendering task failed: {e}");
+                                    continue;
+                                }
+                            };
+
+                            outbound.send_reliable("history", payload).await;
+                        }
+                        message::Packet::StatsRequest {
+                            request_id,
+                            area,
+                            fields,
+                            ..
+                        } => {
+                            // the same checks as `/stats`, since every cell of the area gets visited
+                            if let Err(e) = state::validate_region(&area) {
+                                warn!("Ignored stats request {request_id} of client {client_id}: {e:#}");
+                                continue;
+                            }
+
+                            let request = {
+                                let locked_state = state_shard.lock().await;
+
+                                let Some(client) = locked_state.clients.get(&client_id) else {
+                                    warn!("received stats request from nonexistent client {client_id}");
+                                    continue;
+                                };
+                                (locked_state.map.frame(), locked_state.map.tick(), client.outbound.clone())
+                            };
+                            let (frame, tick, outbound) = request;
+
+                            // every cell of the area is visited, so keep it off the lock & off the async runtime
+                            let computed = tokio::task::spawn_blocking(move || {
+                                let stats = frame.stats(&area, &fields).context("computing stats")?;
+                                let packet = message::Packet::Stats {
+                                    request_id,
+                                    tick,
+                                    cells: stats.cells,
+                                    fields: stats.fields,
+                                };
+                                message::serialize_packet(packet).context("serializing stats packet")
+                            })
+                            .await;
+                            let payload = match computed {
+                                Ok(Ok(payload)) => payload,
+                                Ok(Err(e)) => {
+                                    warn!("couldn't answer stats request of client {client_id}: {e:#}");
+                                    continue;
+                                }
+                                Err(e) => {
+                                    error!("stats task failed: {e}");
+                                    continue;
+                                }
+                            };
+
+                            outbound.send_reliable("stats", payload).await;
+                        }
+                        message::Packet::Presence {
+                            cursor,
+                            tool,
+                            painting,
+                            brush_size_degrees,
+                            ..
+                        } => {
+                            if role < message::Role::Painter && tool.is_some() {
+                                debug!("Ignored presence of client {client_id} with a tool, which may only view");
+                                continue;
+                            }
+                            // every relayed update takes the lock & goes out to every client, so a
+                            // client can't send them as fast as it likes
+                            if presence_window.0.elapsed() >= std::time::Duration::from_secs(1) {
+                                presence_window = (std::time::Instant::now(), 0);
+                            }
+                            if presence_window.1 >= MAX_PRESENCE_PER_SEC {
+                                debug!("Dropped presence of client {client_id} over the rate limit");
+                                continue;
                             }
-                        } else if message.is_close() {
-                            // closing message: unregister viewport/client from global state
-                            {
-                                let mut locked_state = state_shard.lock().await;
-                                if locked_state.clients.remove(&client_id).is_none() {
-                                    warn!("Tried to remove nonexistent client {client_id} on connection close");
-                                } else {
-                                    info!("Client disconnected - viewport/websocket cleared");
+                            presence_window.1 += 1;
+
+                            // re-stamp with the connection's ID so clients can't impersonate each other
+                            let presence_packet = message::Packet::Presence {
+                                client_id,
+                                cursor,
+                                tool,
+                                painting,
+                                brush_size_degrees,
+                            };
+                            let payload = message::serialize_packet(presence_packet)
+                                .expect("couldn't serialize presence packet");
+
+                            // relay to every other client currently looking at the cursor
+                            let locked_state = state_shard.lock().await;
+                            for (other_id, other) in locked_state.clients.iter() {
+                                let visible = other
+                                    .viewports
+                                    .values()
+                                    .any(|view| view.area.contains(cursor));
+
+                                if *other_id != client_id && visible {
+                                    other.outbound.send("presence", payload.clone());
                                 }
                             }
-                        } else {
-                            warn!("unexpected message type with data {packet:?}");
                         }
                     }
-                    Err(e) => warn!("error receiving message: {e}")
+                } else if message.is_close() {
+                    debug!("Client {client_id} closed its connection");
+                } else if !message.is_ping() && !message.is_pong() {
+                    warn!("unexpected message type with data {packet:?}");
                 }
             }
+
+            // connection is gone, cleanly or not: unregister viewport/client from global state
+            let mut locked_state = state_shard.lock().await;
+            locked_state.map.forget_client(client_id);
+            if let Some(client) = locked_state.clients.remove(&client_id) {
+                // don't leave the writer stuck on a dead socket
+                client.writer.abort();
+                metrics.client_disconnected();
+                info!("Client disconnected - viewport/websocket cleared");
+            } else {
+                debug!("Client {client_id} was already removed when its connection ended");
+            }
         });
     })
 }
 
+/// Resolves on SIGINT, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("couldn't listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("couldn't listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
+
+/// Query of a download of the world state.
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: export::Format,
+
+    /// Comma-separated layer names.
+    layers: String,
+
+    /// `north,west,south,east` in degrees; the whole map if missing.
+    area: Option<String>,
+
+    /// Room to export; the default room if missing.
+    room: Option<String>,
+}
+
+/// Finds the room named in a query among `rooms`, answering with an error response if there's no
+/// such room or it couldn't be loaded.
+async fn find_room(
+    rooms: &room::Rooms,
+    name: Option<&str>,
+) -> Result<room::Membership, warp::reply::Response> {
+    use warp::http::StatusCode;
+    use warp::Reply;
+
+    match rooms.get(name).await {
+        Ok(Some(room)) => Ok(room),
+        Ok(None) => Err(warp::reply::with_status(
+            format!("no room {}", name.unwrap_or_default()),
+            StatusCode::NOT_FOUND,
+        )
+        .into_response()),
+        Err(e) => Err(
+            warp::reply::with_status(format!("{e:#}"), StatusCode::SERVICE_UNAVAILABLE)
+                .into_response(),
+        ),
+    }
+}
+
+/// Serves an export of the current state to a client bearing an admin token.
+async fn export_download(
+    authorization: Option<String>,
+    query: ExportQuery,
+    rooms: Arc<room::Rooms>,
+    auth: Arc<auth::Auth>,
+) -> warp::reply::Response {
+    use warp::http::StatusCode;
+    use warp::Reply;
+
+    if !auth.is_admin(authorization.as_deref()) {
+        return warp::reply::with_status("unauthorized", StatusCode::UNAUTHORIZED).into_response();
+    }
+
+    let request = query
+        .layers
+        .split(',')
+        .map(export::parse_layer)
+        .collect::<anyhow::Result<Vec<_>>>()
+        .and_then(|layers| {
+            let area = query.area.as_deref().map(export::parse_area).transpose()?;
+            Ok((layers, area))
+        });
+    let (layers, area) = match request {
+        Ok(request) => request,
+        Err(e) => {
+            return warp::reply::with_status(format!("{e:#}"), StatusCode::BAD_REQUEST)
+                .into_response()
+        }
+    };
+
+    let room = match find_room(&rooms, query.room.as_deref()).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+    let snapshot = room.state_shard.lock().await.map.snapshot();
+    let format = query.format;
+    let exported = tokio::task::spawn_blocking(move || {
+        let bytes = export::export(&snapshot, &layers, area, format)?;
+        Ok::<_, anyhow::Error>((export::file_name(&snapshot, format), bytes))
+    })
+    .await
+    .expect("export task panicked");
+
+    match exported {
+        Ok((file_name, bytes)) => {
+            info!("Serving export {file_name}");
+            warp::http::Response::builder()
+                .header("content-type", format.content_type())
+                .header(
+                    "content-disposition",
+                    format!("attachment; filename=\"{file_name}\""),
+                )
+                .body(bytes.into())
+                .expect("export response should be valid")
+        }
+        Err(e) => {
+            warp::reply::with_status(format!("{e:#}"), StatusCode::BAD_REQUEST).into_response()
+        }
+    }
+}
+
+/// Query of regional statistics of the world state.
+#[derive(Deserialize)]
+struct StatsQuery {
+    /// Comma-separated field names; like in exports, `wind_y` is northward.
+    fields: String,
+
+    /// `north,west,south,east` in degrees.
+    area: Option<String>,
+
+    /// `lat,long;lat,long;...` corners in degrees, instead of `area`; the whole map if neither.
+    polygon: Option<String>,
+
+    /// Signed token, as an alternative to the cookie.
+    token: Option<String>,
+
+    /// Room to compute statistics of; the default room if missing.
+    room: Option<String>,
+}
+
+#[derive(Serialize)]
+struct StatsReply {
+    tick: u64,
+    cells: u64,
+    fields: Vec<message::FieldStats>,
+}
+
+/// Serves statistics of the current state to anyone who may join `/sync`.
+async fn stats_query(
+    query: StatsQuery,
+    cookie: Option<String>,
+    rooms: Arc<room::Rooms>,
+    auth: Arc<auth::Auth>,
+) -> warp::reply::Response {
+    use warp::http::StatusCode;
+    use warp::Reply;
+
+    if let Err(e) = auth.connection_role(query.token.as_deref(), cookie.as_deref()) {
+        return warp::reply::with_status(format!("{e:#}"), StatusCode::UNAUTHORIZED)
+            .into_response();
+    }
+
+    let request = query
+        .fields
+        .split(',')
+        .map(state::parse_field)
+        .collect::<anyhow::Result<Vec<_>>>()
+        .and_then(|fields| {
+            let area = match (query.area, query.polygon) {
+                (Some(area), None) => {
+                    let area = message::Region::Rect(export::parse_area(&area)?);
+                    state::validate_region(&area)?;
+                    area
+                }
+                (None, Some(polygon)) => state::parse_polygon(&polygon)?,
+                (None, None) => message::Region::Rect(message::Rect {
+                    top_left: message::LatLong {
+                        lat: 90.,
+                        long: -180.,
+                    },
+                    bottom_right: message::LatLong {
+                        lat: -90.,
+                        long: 180.,
+                    },
+                }),
+                (Some(_), Some(_)) => anyhow::bail!("give at most one of area or polygon"),
+            };
+            Ok((fields, area))
+        });
+    let (fields, area) = match request {
+        Ok(request) => request,
+        Err(e) => {
+            return warp::reply::with_status(format!("{e:#}"), StatusCode::BAD_REQUEST)
+                .into_response()
+        }
+    };
+
+    let room = match find_room(&rooms, query.room.as_deref()).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+    let (frame, tick) = {
+        let locked_state = room.state_shard.lock().await;
+        (locked_state.map.frame(), locked_state.map.tick())
+    };
+    let stats = tokio::task::spawn_blocking(move || frame.stats(&area, &fields))
+        .await
+        .context("stats task failed")
+        .and_then(|stats| stats);
+
+    match stats {
+        Ok(stats) => warp::reply::json(&StatsReply {
+            tick,
+            cells: stats.cells,
+            fields: stats.fields,
+        })
+        .into_response(),
+        Err(e) => warp::reply::with_status(format!("{e:#}"), StatusCode::INTERNAL_SERVER_ERROR)
+            .into_response(),
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
 
-    let state = match state::State::load_from_image("state.png").await {
-        Ok(v) => v,
-        Err(_) => state::State::load_from_image("images/just-noise.png")
-            .await
-            .unwrap(),
+    let cli = config::Cli::parse();
+    let config = config::Config::load(&cli)?;
+
+    match cli.command {
+        Some(config::Command::Replay {
+            base,
+            journal,
+            output,
+            until_tick,
+        }) => {
+            return journal::replay(base, journal, output, until_tick, config.state_settings())
+                .await;
+        }
+        Some(config::Command::Import {
+            output,
+            inputs,
+            base,
+        }) => {
+            return import::import(&inputs, base.as_deref(), &output);
+        }
+        Some(config::Command::Export {
+            output,
+            snapshot,
+            layers,
+            area,
+            format,
+        }) => {
+            let snapshot = snapshot.unwrap_or_else(|| config.paths.state_file.clone());
+            return export::export_file(&snapshot, &output, &layers, area, format);
+        }
+        Some(config::Command::Generate { output, seed }) => {
+            let seed = seed.unwrap_or(config.world.seed);
+            state::generate_world(seed).save(&output)?;
+            info!("Generated world from seed {seed} into {}", output.display());
+            return Ok(());
+        }
+        Some(config::Command::Keygen { output }) => {
+            let output = output
+                .or_else(|| config.auth.key_file.clone())
+                .context("no key file given or configured")?;
+            auth::Key::generate(&output)?;
+            info!("Generated key file {}", output.display());
+            return Ok(());
+        }
+        Some(config::Command::Token {
+            role,
+            subject,
+            valid_hours,
+        }) => {
+            let key_file = config
+                .auth
+                .key_file
+                .as_deref()
+                .context("no key file configured")?;
+            let claims = auth::Claims {
+                sub: subject,
+                role,
+                exp: valid_hours.map(|hours| state::now_ms() / 1000 + hours * 3600),
+            };
+            println!("{}", auth::Key::load(key_file)?.issue(&claims));
+            return Ok(());
+        }
+        None => {}
+    }
+
+    config.validate()?;
+    debug!("Running with {config:?}");
+    let auth = Arc::new(config.auth()?);
+    let scenario = match &config.paths.scenario {
+        Some(path) => Some(scenario::Scenario::load(path).await?),
+        None => None,
     };
+    if auth.key.is_none() {
+        info!("No key file configured, so every client may paint");
+    }
 
-    let (mod_sender, mut mod_queue) = tokio::sync::mpsc::channel(50);
-    let global_state = GlobalState {
-        map: state,
-        clients: HashMap::new(),
+    let persistence = persistence::Persistence::new(
+        &config.paths.state_file,
+        &config.paths.backup_dir,
+        config.storage.backup_retention,
+        config.backup_interval(),
+    );
+    let state = persistence
+        .load_state(&config.fresh_world(), config.state_settings())
+        .await
+        .context("couldn't load any state")?;
+    let adapter = state.adapter().clone();
+
+    let metrics = metrics::Metrics::new(config.tick_interval())?;
+    let metrics_wsroute = metrics.clone();
+
+    let journal = journal::Journal::open(&config.paths.journal).context("couldn't open journal")?;
+
+    // tick interval is shared by every room & changed through the admin API
+    let (tick_interval_sender, tick_interval) = tokio::sync::watch::channel(config.tick_interval());
+    let health = Arc::new(health::Health::new(
+        adapter,
+        tick_interval.clone(),
+        metrics.clone(),
+    ));
+
+    let services = room::Services {
+        metrics: metrics.clone(),
+        tick_interval,
+        modification_queue_size: config.server.modification_queue_size,
+        save_interval: config.save_interval(),
     };
-    let global_state = Arc::new(Mutex::new(global_state));
-
-    // yay lifetimes and ownershpi
-    let global_state_modification = global_state.clone();
-    let global_state_ticking = global_state.clone();
-    let global_state_saving = global_state.clone();
-    let global_state_clone_wsroute = global_state.clone();
-
-    // spawn task to apply modifications
-    tokio::spawn(async move {
-        while let Some(modif @ message::Packet::Modification { .. }) = mod_queue.recv().await {
-            debug!("Processing modification packet");
-            {
-                let mut locked_state = global_state_modification.lock().await;
-                if let Err(e) = locked_state.map.process_modification(modif) {
-                    warn!("error processing modification: {e}");
-                }
-            }
-        }
-    });
+    let default_room = room::Room::spawn(
+        room::DEFAULT_ROOM.to_owned(),
+        state,
+        persistence,
+        journal,
+        &services,
+        Some(health.clone()),
+    );
+    if let Some(scenario) = scenario {
+        default_room.play_scenario(scenario);
+    }
+    let rooms = Arc::new(room::Rooms::new(
+        default_room.clone(),
+        services,
+        config.room_settings(),
+    ));
+
+    // unloaded rooms are saved, so this stops once the server is shutting down
+    let (shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+    let rooms_unloading = rooms.clone();
+    let unload_task = tokio::spawn(async move { rooms_unloading.unload_idle(shutdown).await });
+
+    let index_route = warp::path::end().and(warp::fs::file(config.index_path()));
+    let static_route = warp::fs::dir(config.paths.frontend_dir.clone());
+    let connection_settings = ConnectionSettings {
+        max_snapshot_pixels: config.server.max_snapshot_pixels,
+        client_queue_size: config.server.client_queue_size,
+        heartbeat_interval: config.heartbeat_interval(),
+        heartbeat_timeout: config.heartbeat_timeout(),
+        max_brush_degrees: config.server.max_brush_degrees,
+    };
+    let auth_wsroute = auth.clone();
+    let connection_slots = Arc::new(tokio::sync::Semaphore::new(config.server.max_connections));
+    let rooms_wsroute = rooms.clone();
+    let room_name = warp::path::end()
+        .map(|| None)
+        .or(warp::path::param::<String>()
+            .and(warp::path::end())
+            .map(Some))
+        .unify();
+    let ws_route = warp::path("sync")
+        .and(room_name)
+        .and(warp::ws())
+        .and(warp::addr::remote())
+        .and(warp::query::<SyncQuery>())
+        .and(warp::cookie::optional::<String>(TOKEN_COOKIE))
+        .then(
+            move |room: Option<String>,
+                  ws: warp::ws::Ws,
+                  peer: Option<SocketAddr>,
+                  query: SyncQuery,
+                  cookie: Option<String>| {
+                let auth = auth_wsroute.clone();
+                let connection_slots = connection_slots.clone();
+                let rooms = rooms_wsroute.clone();
+                let metrics = metrics_wsroute.clone();
 
-    // also spawn task to step internal state every half second
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_millis(500));
-
-        loop {
-            interval.tick().await;
-
-            {
-                let mut locked_state = global_state_ticking.lock().await;
-
-                locked_state
-                    .map
-                    .tick_state_by_count(1)
-                    .await
-                    .expect("couldn't tick state");
-
-                let mut sends: HashMap<u64, Vec<u8>> = HashMap::new();
-                for (client_id, client) in locked_state.clients.iter() {
-                    if let Some(rect) = client.viewport {
-                        let snapshot_png = locked_state
-                            .map
-                            .render_cropped_state(rect)
-                            .expect("couldn't render cropped state");
-
-                        let packet = message::Packet::Snapshot {
-                            data: message::PNGFile(snapshot_png.0),
-                            location: snapshot_png.1,
+                async move {
+                    use warp::http::StatusCode;
+                    use warp::Reply;
+
+                    let (role, subject) =
+                        match auth.connection_role(query.token.as_deref(), cookie.as_deref()) {
+                            Ok(identity) => identity,
+                            Err(e) => {
+                                warn!("Refused connection from {peer:?}: {e:#}");
+                                return warp::reply::with_status(
+                                    "unauthorized",
+                                    StatusCode::UNAUTHORIZED,
+                                )
+                                .into_response();
+                            }
                         };
-                        let packet_data = message::serialize_packet(packet)
-                            .expect("couldn't serialize snapshot packet");
 
-                        sends.insert(*client_id, packet_data);
-                    }
-                }
+                    let Ok(permit) = connection_slots.try_acquire_owned() else {
+                        warn!("Turned away connection from {peer:?}: too many connections");
+                        metrics.record_dropped_connection("limit");
+                        return warp::reply::with_status(
+                            "too many connections",
+                            StatusCode::SERVICE_UNAVAILABLE,
+                        )
+                        .into_response();
+                    };
 
-                for (client_id, payload) in sends.into_iter() {
-                    let client = locked_state.clients.get_mut(&client_id).expect(
-                        "getting client info when sending snapshot to known existent client failed",
-                    );
-                    match client.ws_sink.send(ws::Message::binary(payload)).await {
-                        Ok(()) => debug!("Sent ticked state to client"),
-                        Err(e) => {
-                            warn!("Error sending ticked snapshot to client: {e}");
+                    if let Some(name) = &room {
+                        if let Err(e) = room::validate_name(name) {
+                            return warp::reply::with_status(
+                                format!("{e:#}"),
+                                StatusCode::BAD_REQUEST,
+                            )
+                            .into_response();
                         }
                     }
+                    let membership = match rooms.join(room.as_deref(), role).await {
+                        Ok(membership) => membership,
+                        Err(e) => {
+                            warn!("Couldn't put {peer:?} in room {room:?}: {e:#}");
+                            let status = match e.downcast_ref::<room::Refused>() {
+                                Some(room::Refused::NoSuchRoom(_)) => StatusCode::NOT_FOUND,
+                                Some(_) => StatusCode::FORBIDDEN,
+                                None => StatusCode::SERVICE_UNAVAILABLE,
+                            };
+                            return warp::reply::with_status(format!("{e:#}"), status)
+                                .into_response();
+                        }
+                    };
+
+                    start_syncing(
+                        ws,
+                        Connection {
+                            peer,
+                            role,
+                            subject,
+                        },
+                        membership,
+                        connection_settings,
+                        permit,
+                        metrics,
+                    )
+                    .into_response()
                 }
-            }
-        }
+            },
+        );
+
+    let admin_route = admin::routes(admin::Admin {
+        rooms: rooms.clone(),
+        auth: auth.clone(),
+        fresh_world: config.fresh_world(),
+        metrics: metrics.clone(),
+        tick_interval: tick_interval_sender,
     });
 
-    // *also* spawn task to save state to file every 10 seconds
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(10));
+    let rooms_export = rooms.clone();
+    let auth_export = auth.clone();
+    let export_route = warp::path("export")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<ExportQuery>())
+        .then(move |authorization, query| {
+            export_download(
+                authorization,
+                query,
+                rooms_export.clone(),
+                auth_export.clone(),
+            )
+        });
 
-        loop {
-            interval.tick().await;
+    let rooms_stats = rooms.clone();
+    let auth_stats = auth.clone();
+    let stats_route = warp::path("stats")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<StatsQuery>())
+        .and(warp::cookie::optional::<String>(TOKEN_COOKIE))
+        .then(move |query, cookie| {
+            stats_query(query, cookie, rooms_stats.clone(), auth_stats.clone())
+        });
 
-            let state_data = {
-                let locked_state = global_state_saving.lock().await;
-                locked_state.map.get_state_clone()
-            };
+    let rooms_metrics = rooms.clone();
+    let metrics_route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<MetricsQuery>())
+        .then(move |query: MetricsQuery| {
+            let rooms = rooms_metrics.clone();
+            let metrics = metrics.clone();
 
-            state::State::save_raw_to_image(state_data, "state.png")
-                .expect("couldn't save state image");
-            debug!("Saved state to state.png");
-        }
-    });
+            async move {
+                use warp::Reply;
 
-    let index_route = warp::path::end().and(warp::fs::file("../frontend/index.html"));
-    let static_route = warp::fs::dir("../frontend/");
-    let ws_route = warp::path("sync")
+                // scrapes shouldn't keep rooms loaded, & an unloaded room has nothing queued
+                let queue_depth = match rooms.loaded(query.room.as_deref()).await {
+                    Some(room) => {
+                        let sink = &room.modification_sink;
+                        sink.max_capacity() - sink.capacity()
+                    }
+                    None if query.room.as_deref().is_some_and(|name| rooms.exists(name)) => 0,
+                    None => {
+                        return warp::reply::with_status(
+                            format!("no room {}", query.room.unwrap_or_default()),
+                            warp::http::StatusCode::NOT_FOUND,
+                        )
+                        .into_response()
+                    }
+                };
+
+                warp::reply::with_header(
+                    metrics.render(queue_depth),
+                    "content-type",
+                    "text/plain; version=0.0.4",
+                )
+                .into_response()
+            }
+        });
+
+    let health_live = health.clone();
+    let healthz_route = warp::path("healthz")
         .and(warp::path::end())
-        .and(warp::ws())
-        .map(move |ws: warp::ws::Ws| {
-            let state_clone = global_state_clone_wsroute.clone();
-            start_syncing(ws, state_clone, mod_sender.clone())
+        .and(warp::get())
+        .map(move || warp::reply::json(&health_live.report()));
+    let readyz_route = warp::path("readyz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || {
+            let report = health.report();
+            let status = if report.ready {
+                warp::http::StatusCode::OK
+            } else {
+                warp::http::StatusCode::SERVICE_UNAVAILABLE
+            };
+            warp::reply::with_status(warp::reply::json(&report), status)
         });
 
-    let all_filters = index_route.or(static_route).or(ws_route);
+    let all_filters = index_route
+        .or(export_route)
+        .or(stats_route)
+        .or(metrics_route)
+        .or(healthz_route)
+        .or(readyz_route)
+        .or(static_route)
+        .or(ws_route)
+        .or(admin_route);
 
-    let bind_address: SocketAddr = std::env::var("SPACEPAINT_ADDR")
-        .unwrap_or_else(|_| "0.0.0.0:5000".to_owned())
-        .parse()
-        .expect("invalid socket addr");
+    let bind_address = config.server.bind_address;
     info!("Preparing to serve on {bind_address}");
-    warp::serve(all_filters).run(bind_address).await;
+    let (_, server) =
+        warp::serve(all_filters).bind_with_graceful_shutdown(bind_address, shutdown_signal());
+    server.await;
+
+    info!("Stopped accepting connections, shutting down");
+    shutdown_sender.send_replace(true);
+    unload_task.await.expect("room unloading task panicked");
+    let saved = rooms.shut_down().await;
+    info!("Closed all client connections");
+
+    saved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+    /// Stream of websocket messages each carrying an undo, as a painter would send.
+    fn undos(
+        count: usize,
+    ) -> impl futures::Stream<Item = Result<ws::Message, warp::Error>> + Unpin {
+        let packet = message::serialize_packet(message::Packet::Undo { client_id: 1 }).unwrap();
+        futures::stream::iter((0..count).map(move |_| Ok(ws::Message::binary(packet.clone()))))
+    }
+
+    #[tokio::test]
+    async fn kicked_clients_are_read_no_further() {
+        let (removed, mut removal) = tokio::sync::oneshot::channel();
+        let mut stream = undos(3);
+
+        assert!(matches!(
+            next_incoming(&mut stream, &mut removal, TIMEOUT).await,
+            Incoming::Message(_)
+        ));
+        removed.send(()).unwrap();
+        // the undos still waiting never reach the modification queue
+        assert!(matches!(
+            next_incoming(&mut stream, &mut removal, TIMEOUT).await,
+            Incoming::Removed
+        ));
+    }
+
+    #[tokio::test]
+    async fn clients_dropped_from_their_room_are_read_no_further() {
+        let (removed, mut removal) = tokio::sync::oneshot::channel::<()>();
+        let mut stream = undos(1);
 
-    Ok(())
+        drop(removed);
+        assert!(matches!(
+            next_incoming(&mut stream, &mut removal, TIMEOUT).await,
+            Incoming::Removed
+        ));
+    }
 }
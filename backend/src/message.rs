@@ -4,7 +4,85 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize)]
 pub struct PNGFile(pub Vec<u8>);
 
+/// A single channel of the simulation state.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum Layer {
+    Temperature,
+    WindX,
+    WindY,
+    Haze,
+}
+
+/// Quantity statistics can be computed for over a region.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum StatsField {
+    Temperature,
+    /// Eastward wind.
+    WindX,
+    /// Northward wind, like in exports (the `WindY` layer itself points south).
+    WindY,
+    /// Magnitude of the wind.
+    WindSpeed,
+    Haze,
+}
+
+/// Statistics of one field over a region, weighted by the area of each map cell.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FieldStats {
+    pub field: StatsField,
+    /// UDUNITS name of the unit the values are in.
+    pub unit: String,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub std_dev: f64,
+    /// Fraction of the area in each of equally wide bins spanning `min..=max`.
+    pub histogram: Vec<f64>,
+}
+
+/// What a client is allowed to do, from least to most.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Can only watch.
+    Viewer,
+    /// Can also paint, undo & redo.
+    Painter,
+    /// Can also use the admin API.
+    Admin,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+/// How a client wants its snapshots encoded, picked in its handshake.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum SnapshotEncoding {
+    /// RGBA PNG, one channel per layer; unsubscribed layers are zeroed.
+    Png,
+    /// Raw byte planes for only the subscribed layers.
+    Raw { compression: Compression },
+}
+
 #[derive(Serialize, Deserialize)]
+pub enum SnapshotData {
+    Png(PNGFile),
+    /// Layers stored one after another as `width * height` byte planes in row-major order.
+    ///
+    /// With LZ4 compression the planes are compressed as a whole, prefixed with their size.
+    Raw {
+        width: u32,
+        height: u32,
+        layers: Vec<Layer>,
+        compression: Compression,
+        planes: Vec<u8>,
+    },
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub enum ModificationType {
     Heat,
     Cool,
@@ -19,19 +97,176 @@ pub struct LatLong {
     pub long: f64,
 }
 
+impl LatLong {
+    /// Whether both coordinates are finite, which JSON (& so the journal) can only represent then.
+    pub fn is_finite(&self) -> bool {
+        self.lat.is_finite() && self.long.is_finite()
+    }
+}
+
 #[derive(Clone, Copy, Serialize, Deserialize, Debug)]
 pub struct Rect {
     pub top_left: LatLong,
     pub bottom_right: LatLong,
 }
 
+impl Rect {
+    /// Whether the given point lies within this rectangle (edges inclusive).
+    pub fn contains(&self, point: LatLong) -> bool {
+        (self.bottom_right.lat..=self.top_left.lat).contains(&point.lat)
+            && (self.top_left.long..=self.bottom_right.long).contains(&point.long)
+    }
+
+    /// This rectangle with its corners swapped into place if needed & clamped to the map, or
+    /// `None` if it has no area or isn't made of finite coordinates.
+    pub fn normalized(&self) -> Option<Rect> {
+        let Rect {
+            top_left,
+            bottom_right,
+        } = *self;
+        if ![
+            top_left.lat,
+            top_left.long,
+            bottom_right.lat,
+            bottom_right.long,
+        ]
+        .iter()
+        .all(|coordinate| coordinate.is_finite())
+        {
+            return None;
+        }
+
+        let north = top_left.lat.max(bottom_right.lat).min(90.);
+        let south = top_left.lat.min(bottom_right.lat).max(-90.);
+        let west = top_left.long.min(bottom_right.long).max(-180.);
+        let east = top_left.long.max(bottom_right.long).min(180.);
+        if north <= south || east <= west {
+            return None;
+        }
+
+        Some(Rect {
+            top_left: LatLong {
+                lat: north,
+                long: west,
+            },
+            bottom_right: LatLong {
+                lat: south,
+                long: east,
+            },
+        })
+    }
+}
+
+/// Area of the map, either a rectangle or a polygon.
+///
+/// Neither may cross the antimeridian.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum Region {
+    Rect(Rect),
+    /// Corners in order; the last one connects back to the first.
+    Polygon(Vec<LatLong>),
+}
+
+impl Region {
+    /// Whether the given point lies within this region.
+    pub fn contains(&self, point: LatLong) -> bool {
+        match self {
+            Region::Rect(rect) => rect.contains(point),
+            Region::Polygon(corners) => {
+                // count crossings of a ray going east from the point
+                let mut inside = false;
+                for (i, a) in corners.iter().enumerate() {
+                    let b = corners[(i + 1) % corners.len()];
+                    if (a.lat > point.lat) != (b.lat > point.lat) {
+                        let crossing =
+                            a.long + (point.lat - a.lat) / (b.lat - a.lat) * (b.long - a.long);
+                        if point.long < crossing {
+                            inside = !inside;
+                        }
+                    }
+                }
+                inside
+            }
+        }
+    }
+
+    /// Smallest rectangle containing the region.
+    pub fn bounds(&self) -> Rect {
+        match self {
+            Region::Rect(rect) => *rect,
+            Region::Polygon(corners) => {
+                let (mut north, mut west) = (f64::NEG_INFINITY, f64::INFINITY);
+                let (mut south, mut east) = (f64::INFINITY, f64::NEG_INFINITY);
+                for corner in corners {
+                    north = north.max(corner.lat);
+                    south = south.min(corner.lat);
+                    west = west.min(corner.long);
+                    east = east.max(corner.long);
+                }
+                Rect {
+                    top_left: LatLong {
+                        lat: north,
+                        long: west,
+                    },
+                    bottom_right: LatLong {
+                        lat: south,
+                        long: east,
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// Output dimensions of a snapshot, in pixels.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Point in the past to look up in the server's history.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub enum HistoryPoint {
+    Tick(u64),
+    /// Milliseconds since the Unix epoch.
+    Timestamp(u64),
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum Packet {
     AssignId {
         client_id: u64,
+        /// Largest snapshot (width * height) the server will send.
+        max_snapshot_pixels: u32,
+        /// What the client's token allows it to do.
+        role: Role,
     },
     Snapshot {
-        data: PNGFile,
+        viewport_id: u32,
+        /// Tick the state was at when the snapshot was rendered.
+        tick: u64,
+        data: SnapshotData,
+        location: Rect,
+    },
+    /// Asks for a past snapshot of one of the client's viewports.
+    HistoryRequest {
+        client_id: u64,
+        viewport_id: u32,
+        at: HistoryPoint,
+    },
+    /// Past snapshot of a viewport, closest to (but not after) the requested point.
+    ///
+    /// Points before the oldest frame get that frame, which is then after the point; `oldest_tick`
+    /// tells them apart.
+    HistorySnapshot {
+        viewport_id: u32,
+        tick: u64,
+        timestamp_ms: u64,
+        /// Ticks of the oldest & newest frames in the server's history.
+        oldest_tick: u64,
+        newest_tick: u64,
+        data: SnapshotData,
         location: Rect,
     },
     Modification {
@@ -40,9 +275,66 @@ pub enum Packet {
         brush_size_degrees: f64,
         client_id: u64,
     },
+    /// Tells a client parts of its stroke fell in protected zones and weren't applied.
+    ModificationRejected {
+        /// Points of the stroke whose brush was clipped.
+        points: Vec<LatLong>,
+        /// Names of the zones that clipped it.
+        zones: Vec<String>,
+    },
+    /// Reverts the client's last stroke.
+    Undo {
+        client_id: u64,
+    },
+    /// Re-applies the client's last undone stroke.
+    Redo {
+        client_id: u64,
+    },
+    /// Creates or updates one of a client's viewports.
     Viewport {
         area: Rect,
         client_id: u64,
+        /// Client-chosen ID, so a client can keep several views (e.g. a minimap).
+        viewport_id: u32,
+        /// Desired snapshot size; the server picks one if absent.
+        resolution: Option<Resolution>,
+        /// Layers to include in this viewport's snapshots.
+        layers: Vec<Layer>,
+    },
+    CloseViewport {
+        client_id: u64,
+        viewport_id: u32,
+    },
+    /// Asks for statistics of the current state over an area.
+    StatsRequest {
+        client_id: u64,
+        /// Client-chosen ID, echoed in the answer.
+        request_id: u32,
+        area: Region,
+        fields: Vec<StatsField>,
+    },
+    /// Answer to a `StatsRequest`, with a result per requested field.
+    Stats {
+        request_id: u32,
+        /// Tick the state was at when the statistics were computed.
+        tick: u64,
+        /// Map cells within the area; `fields` is empty if there are none.
+        cells: u64,
+        fields: Vec<FieldStats>,
+    },
+    /// Sent by clients once they've been assigned an ID.
+    Handshake {
+        client_id: u64,
+        encoding: SnapshotEncoding,
+    },
+    /// Cursor position & tool of a client, relayed to other clients that can see it.
+    Presence {
+        client_id: u64,
+        cursor: LatLong,
+        tool: Option<ModificationType>,
+        /// Whether the client is in the middle of a stroke.
+        painting: bool,
+        brush_size_degrees: f64,
     },
 }
 
@@ -0,0 +1,249 @@
+//! Prometheus metrics about the simulation and networking, served at `/metrics`.
+
+use prometheus::{
+    Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+use std::time::Duration;
+
+use crate::state;
+
+/// Buckets for tick phases & snapshot renders, in seconds: 1 ms up to ~4 s.
+const DURATION_BUCKETS: [f64; 13] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2., 3., 4.,
+];
+
+/// All metrics of the server. Cloning is cheap and shares the underlying metrics.
+///
+/// Client metrics are aggregated over all clients, since labelling them by client ID would make a
+/// series for every connection there ever was.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+
+    /// Tick durations, labelled by phase: upload, shader, readback or total.
+    tick_seconds: HistogramVec,
+
+    /// Time spent rendering a client's snapshots during a tick.
+    snapshot_render_seconds: Histogram,
+
+    connected_clients: IntGauge,
+    modification_queue_depth: IntGauge,
+
+    /// Packets that weren't sent, labelled by packet kind & whether the client's queue was full or
+    /// the socket failed.
+    failed_sends: IntCounterVec,
+
+    /// Bytes sent over websockets, labelled by packet kind.
+    bytes_sent: IntCounterVec,
+
+    /// Time from a snapshot being queued until it's written.
+    snapshot_lag_seconds: Histogram,
+
+    /// Snapshots replaced by a newer one before they could be written.
+    snapshots_superseded: IntCounter,
+
+    /// Connections turned away or dropped by the server, labelled by why.
+    dropped_connections: IntCounterVec,
+
+    /// Unix time of the last successful save of the default room, like `/readyz` reports.
+    last_save_timestamp: Gauge,
+    seconds_since_last_save: Gauge,
+
+    /// Configured tick interval, to compare tick durations against.
+    tick_interval_seconds: Gauge,
+
+    /// Named rooms currently loaded, besides the default one.
+    loaded_rooms: IntGauge,
+}
+
+impl Metrics {
+    pub fn new(tick_interval: Duration) -> prometheus::Result<Metrics> {
+        let registry = Registry::new_custom(Some("spacepaint".to_owned()), None)?;
+
+        let tick_seconds = HistogramVec::new(
+            HistogramOpts::new("tick_seconds", "Duration of simulation ticks by phase")
+                .buckets(DURATION_BUCKETS.to_vec()),
+            &["phase"],
+        )?;
+        let snapshot_render_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "snapshot_render_seconds",
+                "Time spent rendering a client's snapshots in a tick",
+            )
+            .buckets(DURATION_BUCKETS.to_vec()),
+        )?;
+        let connected_clients = IntGauge::new("connected_clients", "Connected websocket clients")?;
+        let modification_queue_depth = IntGauge::new(
+            "modification_queue_depth",
+            "Modifications waiting to be applied in the scraped room",
+        )?;
+        let failed_sends = IntCounterVec::new(
+            Opts::new(
+                "failed_sends_total",
+                "Packets that couldn't be sent to a client",
+            ),
+            &["kind", "reason"],
+        )?;
+        let bytes_sent = IntCounterVec::new(
+            Opts::new("bytes_sent_total", "Bytes of packets sent to clients"),
+            &["kind"],
+        )?;
+        let snapshot_lag_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "snapshot_lag_seconds",
+                "Time from a snapshot being queued until it's written to the socket",
+            )
+            .buckets(DURATION_BUCKETS.to_vec()),
+        )?;
+        let snapshots_superseded = IntCounter::new(
+            "snapshots_superseded_total",
+            "Snapshots replaced by a newer one before they were written to a client",
+        )?;
+        let dropped_connections = IntCounterVec::new(
+            Opts::new(
+                "dropped_connections_total",
+                "Connections turned away at the limit or dropped for missing heartbeats",
+            ),
+            &["reason"],
+        )?;
+        let last_save_timestamp = Gauge::new(
+            "last_save_timestamp_seconds",
+            "Unix time of the last successful save of the default room",
+        )?;
+        let seconds_since_last_save = Gauge::new(
+            "seconds_since_last_save",
+            "Seconds since the last successful save of the default room, or since startup",
+        )?;
+        let tick_interval_seconds =
+            Gauge::new("tick_interval_seconds", "Configured time between ticks")?;
+        let loaded_rooms =
+            IntGauge::new("loaded_rooms", "Named rooms loaded besides the default one")?;
+
+        registry.register(Box::new(tick_seconds.clone()))?;
+        registry.register(Box::new(snapshot_render_seconds.clone()))?;
+        registry.register(Box::new(connected_clients.clone()))?;
+        registry.register(Box::new(modification_queue_depth.clone()))?;
+        registry.register(Box::new(failed_sends.clone()))?;
+        registry.register(Box::new(bytes_sent.clone()))?;
+        registry.register(Box::new(snapshot_lag_seconds.clone()))?;
+        registry.register(Box::new(snapshots_superseded.clone()))?;
+        registry.register(Box::new(dropped_connections.clone()))?;
+        registry.register(Box::new(last_save_timestamp.clone()))?;
+        registry.register(Box::new(seconds_since_last_save.clone()))?;
+        registry.register(Box::new(tick_interval_seconds.clone()))?;
+        registry.register(Box::new(loaded_rooms.clone()))?;
+
+        tick_interval_seconds.set(tick_interval.as_secs_f64());
+        // count from startup until the first save
+        last_save_timestamp.set(now_secs());
+
+        Ok(Metrics {
+            registry,
+            tick_seconds,
+            snapshot_render_seconds,
+            connected_clients,
+            modification_queue_depth,
+            failed_sends,
+            bytes_sent,
+            snapshot_lag_seconds,
+            snapshots_superseded,
+            dropped_connections,
+            last_save_timestamp,
+            seconds_since_last_save,
+            tick_interval_seconds,
+            loaded_rooms,
+        })
+    }
+
+    pub fn record_tick(&self, timings: &state::TickTimings, total: Duration) {
+        for (phase, duration) in [
+            ("upload", timings.upload),
+            ("shader", timings.shader),
+            ("readback", timings.readback),
+            ("total", total),
+        ] {
+            self.tick_seconds
+                .with_label_values(&[phase])
+                .observe(duration.as_secs_f64());
+        }
+    }
+
+    pub fn record_render(&self, duration: Duration) {
+        self.snapshot_render_seconds.observe(duration.as_secs_f64());
+    }
+
+    /// Records the outcome of sending a packet of `bytes` bytes.
+    pub fn record_send(&self, kind: &str, bytes: usize, succeeded: bool) {
+        if succeeded {
+            self.bytes_sent
+                .with_label_values(&[kind])
+                .inc_by(bytes as u64);
+        } else {
+            self.failed_sends
+                .with_label_values(&[kind, "socket_error"])
+                .inc();
+        }
+    }
+
+    /// Records a packet dropped because the client's queue was full or gone.
+    pub fn record_dropped(&self, kind: &str) {
+        self.failed_sends
+            .with_label_values(&[kind, "queue_full"])
+            .inc();
+    }
+
+    pub fn record_lag(&self, lag: Duration) {
+        self.snapshot_lag_seconds.observe(lag.as_secs_f64());
+    }
+
+    pub fn record_superseded(&self) {
+        self.snapshots_superseded.inc();
+    }
+
+    pub fn record_dropped_connection(&self, reason: &str) {
+        self.dropped_connections.with_label_values(&[reason]).inc();
+    }
+
+    pub fn connected_clients(&self) -> i64 {
+        self.connected_clients.get()
+    }
+
+    pub fn client_connected(&self) {
+        self.connected_clients.inc();
+    }
+
+    pub fn client_disconnected(&self) {
+        self.connected_clients.dec();
+    }
+
+    pub fn set_tick_interval(&self, interval: Duration) {
+        self.tick_interval_seconds.set(interval.as_secs_f64());
+    }
+
+    pub fn set_loaded_rooms(&self, count: usize) {
+        self.loaded_rooms.set(count as i64);
+    }
+
+    pub fn record_save(&self) {
+        self.last_save_timestamp.set(now_secs());
+    }
+
+    /// Renders all metrics in the Prometheus text format.
+    pub fn render(&self, queue_depth: usize) -> String {
+        self.modification_queue_depth.set(queue_depth as i64);
+        self.seconds_since_last_save
+            .set(now_secs() - self.last_save_timestamp.get());
+
+        let mut output = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut output)
+            .expect("encoding metrics as text shouldn't fail");
+
+        String::from_utf8(output).expect("metrics text should be UTF-8")
+    }
+}
+
+fn now_secs() -> f64 {
+    state::now_ms() as f64 / 1000.
+}
@@ -0,0 +1,666 @@
+//! Minimal reader & writer for NetCDF classic & 64-bit offset files.
+//!
+//! Only what the importer & exporter need: the header, attributes, reading whole variables (or
+//! the first record of record variables) as `f64`s, and writing fixed-size variables.
+
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+
+const NC_DIMENSION: u32 = 0x0A;
+const NC_VARIABLE: u32 = 0x0B;
+const NC_ATTRIBUTE: u32 = 0x0C;
+
+/// External data types, numbered as in the file format.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NcType {
+    Byte = 1,
+    Char = 2,
+    Short = 3,
+    Int = 4,
+    Float = 5,
+    Double = 6,
+}
+
+impl NcType {
+    fn from_code(code: u32) -> Result<NcType> {
+        Ok(match code {
+            1 => NcType::Byte,
+            2 => NcType::Char,
+            3 => NcType::Short,
+            4 => NcType::Int,
+            5 => NcType::Float,
+            6 => NcType::Double,
+            _ => anyhow::bail!("unsupported NetCDF data type {code}"),
+        })
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            NcType::Byte | NcType::Char => 1,
+            NcType::Short => 2,
+            NcType::Int | NcType::Float => 4,
+            NcType::Double => 8,
+        }
+    }
+}
+
+pub struct Dimension {
+    pub name: String,
+
+    /// Length of the dimension; 0 for the record (unlimited) dimension.
+    pub len: usize,
+}
+
+pub enum AttributeValue {
+    Text(String),
+    Numbers(Vec<f64>),
+}
+
+pub struct Attribute {
+    pub name: String,
+    pub value: AttributeValue,
+}
+
+impl Attribute {
+    pub fn text(name: &str, text: &str) -> Attribute {
+        Attribute {
+            name: name.to_owned(),
+            value: AttributeValue::Text(text.to_owned()),
+        }
+    }
+
+    pub fn numbers(name: &str, numbers: &[f64]) -> Attribute {
+        Attribute {
+            name: name.to_owned(),
+            value: AttributeValue::Numbers(numbers.to_vec()),
+        }
+    }
+}
+
+pub struct Variable {
+    pub name: String,
+
+    /// Indices into [`NetCdf::dims`].
+    pub dims: Vec<usize>,
+
+    pub attrs: Vec<Attribute>,
+    pub nc_type: NcType,
+
+    /// Bytes of data per record (or in total for non-record variables), padded to 4 bytes.
+    vsize: usize,
+
+    /// Offset of the variable's data in the file.
+    begin: usize,
+}
+
+impl Variable {
+    pub fn attr(&self, name: &str) -> Option<&AttributeValue> {
+        find_attr(&self.attrs, name)
+    }
+
+    pub fn text_attr(&self, name: &str) -> Option<&str> {
+        match self.attr(name)? {
+            AttributeValue::Text(text) => Some(text.trim_end_matches('\0')),
+            AttributeValue::Numbers(_) => None,
+        }
+    }
+
+    pub fn number_attr(&self, name: &str) -> Option<f64> {
+        match self.attr(name)? {
+            AttributeValue::Numbers(numbers) => numbers.first().copied(),
+            AttributeValue::Text(_) => None,
+        }
+    }
+}
+
+/// A parsed NetCDF file, kept in memory.
+pub struct NetCdf {
+    pub dims: Vec<Dimension>,
+    pub attrs: Vec<Attribute>,
+    pub vars: Vec<Variable>,
+
+    /// Number of records along the record dimension.
+    num_records: usize,
+
+    bytes: Vec<u8>,
+}
+
+impl NetCdf {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<NetCdf> {
+        let bytes = std::fs::read(path.as_ref())
+            .with_context(|| format!("reading {}", path.as_ref().display()))?;
+        NetCdf::parse(bytes)
+            .with_context(|| format!("parsing NetCDF file {}", path.as_ref().display()))
+    }
+
+    pub fn parse(bytes: Vec<u8>) -> Result<NetCdf> {
+        if bytes.starts_with(b"\x89HDF") {
+            anyhow::bail!(
+                "NetCDF-4 (HDF5) files, which most current ERA5 & GFS downloads are, aren't \
+                 supported; convert to the classic format first with \
+                 `nccopy -k classic input.nc output.nc`"
+            );
+        }
+
+        let mut header = Header {
+            bytes: &bytes,
+            pos: 0,
+            offset_size: 4,
+        };
+        match header.take(4)? {
+            b"CDF\x01" => {}
+            b"CDF\x02" => header.offset_size = 8,
+            b"CDF\x05" => anyhow::bail!("CDF-5 files aren't supported"),
+            _ => anyhow::bail!("not a NetCDF file"),
+        }
+
+        let num_records = match header.u32()? {
+            // streaming files don't record the count, so work it out from the file size later
+            u32::MAX => None,
+            n => Some(n as usize),
+        };
+
+        let dims = header.list(NC_DIMENSION, |h| {
+            Ok(Dimension {
+                name: h.name()?,
+                len: h.u32()? as usize,
+            })
+        })?;
+        let attrs = header.attrs()?;
+        let vars = header.list(NC_VARIABLE, |h| {
+            let name = h.name()?;
+            let num_dims = h.u32()? as usize;
+            let dims = (0..num_dims)
+                .map(|_| h.u32().map(|d| d as usize))
+                .collect::<Result<Vec<_>>>()?;
+            let attrs = h.attrs()?;
+            let nc_type = NcType::from_code(h.u32()?)?;
+            let vsize = h.u32()? as usize;
+            let begin = h.offset()?;
+
+            Ok(Variable {
+                name,
+                dims,
+                attrs,
+                nc_type,
+                vsize,
+                begin,
+            })
+        })?;
+
+        if let Some(var) = vars
+            .iter()
+            .find(|var| var.dims.iter().any(|&d| d >= dims.len()))
+        {
+            anyhow::bail!("variable {} refers to a nonexistent dimension", var.name);
+        }
+
+        let mut file = NetCdf {
+            dims,
+            attrs,
+            vars,
+            num_records: 0,
+            bytes,
+        };
+        file.num_records = match num_records {
+            Some(n) => n,
+            None => file.count_streamed_records(),
+        };
+
+        Ok(file)
+    }
+
+    pub fn var(&self, name: &str) -> Option<&Variable> {
+        self.vars.iter().find(|var| var.name == name)
+    }
+
+    #[allow(unused)]
+    pub fn global_attr(&self, name: &str) -> Option<&AttributeValue> {
+        find_attr(&self.attrs, name)
+    }
+
+    pub fn is_record_var(&self, var: &Variable) -> bool {
+        var.dims.first().is_some_and(|&d| self.dims[d].len == 0)
+    }
+
+    /// Lengths of the variable's dimensions, with the record dimension counted as one record.
+    pub fn shape(&self, var: &Variable) -> Vec<usize> {
+        var.dims
+            .iter()
+            .map(|&d| match self.dims[d].len {
+                0 => 1,
+                len => len,
+            })
+            .collect()
+    }
+
+    /// Reads a non-record variable, or the given record of a record variable, converted to `f64`.
+    ///
+    /// Doesn't apply `scale_factor`/`add_offset` or fill values; that's up to the caller.
+    pub fn read(&self, var: &Variable, record: usize) -> Result<Vec<f64>> {
+        let count: usize = self.shape(var).iter().product();
+        let len = count * var.nc_type.size();
+
+        let start = if self.is_record_var(var) {
+            if record >= self.num_records {
+                anyhow::bail!(
+                    "variable {} has {} records, can't read record {record}",
+                    var.name,
+                    self.num_records
+                );
+            }
+            var.begin + record * self.record_size()
+        } else {
+            var.begin
+        };
+
+        let data = self
+            .bytes
+            .get(start..start + len)
+            .ok_or_else(|| anyhow!("data of variable {} is past the end of the file", var.name))?;
+
+        Ok(decode_values(var.nc_type, data))
+    }
+
+    /// Bytes taken up by one record of all record variables together.
+    fn record_size(&self) -> usize {
+        let record_vars: Vec<&Variable> = self
+            .vars
+            .iter()
+            .filter(|var| self.is_record_var(var))
+            .collect();
+
+        // a lone record variable isn't padded
+        match record_vars.as_slice() {
+            [var] => self.shape(var).iter().product::<usize>() * var.nc_type.size(),
+            vars => vars.iter().map(|var| var.vsize).sum(),
+        }
+    }
+
+    fn count_streamed_records(&self) -> usize {
+        let record_size = self.record_size();
+        let Some(first) = self
+            .vars
+            .iter()
+            .filter(|var| self.is_record_var(var))
+            .map(|var| var.begin)
+            .min()
+        else {
+            return 0;
+        };
+
+        match record_size {
+            0 => 0,
+            size => self.bytes.len().saturating_sub(first) / size,
+        }
+    }
+}
+
+fn find_attr<'a>(attrs: &'a [Attribute], name: &str) -> Option<&'a AttributeValue> {
+    attrs
+        .iter()
+        .find(|attr| attr.name == name)
+        .map(|attr| &attr.value)
+}
+
+/// Decodes big-endian values of the given type.
+fn decode_values(nc_type: NcType, data: &[u8]) -> Vec<f64> {
+    let chunks = data.chunks_exact(nc_type.size());
+
+    match nc_type {
+        NcType::Byte => data.iter().map(|&b| b as i8 as f64).collect(),
+        NcType::Char => data.iter().map(|&b| b as f64).collect(),
+        NcType::Short => chunks
+            .map(|c| i16::from_be_bytes([c[0], c[1]]) as f64)
+            .collect(),
+        NcType::Int => chunks
+            .map(|c| i32::from_be_bytes(c.try_into().unwrap()) as f64)
+            .collect(),
+        NcType::Float => chunks
+            .map(|c| f32::from_be_bytes(c.try_into().unwrap()) as f64)
+            .collect(),
+        NcType::Double => chunks
+            .map(|c| f64::from_be_bytes(c.try_into().unwrap()))
+            .collect(),
+    }
+}
+
+/// Data of a variable to be written.
+pub enum Data {
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+}
+
+impl Data {
+    fn nc_type(&self) -> NcType {
+        match self {
+            Data::Float(_) => NcType::Float,
+            Data::Double(_) => NcType::Double,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Data::Float(values) => values.iter().flat_map(|v| v.to_be_bytes()).collect(),
+            Data::Double(values) => values.iter().flat_map(|v| v.to_be_bytes()).collect(),
+        }
+    }
+}
+
+/// A variable to be written.
+pub struct NewVariable {
+    pub name: String,
+
+    /// Indices into the dimensions passed to [`write`].
+    pub dims: Vec<usize>,
+
+    pub attrs: Vec<Attribute>,
+    pub data: Data,
+}
+
+/// Writes a classic format file. Text attributes are written as chars and numeric ones as doubles.
+///
+/// Only fixed-size dimensions are supported, i.e. no record variables.
+pub fn write(dims: &[Dimension], attrs: &[Attribute], vars: &[NewVariable]) -> Result<Vec<u8>> {
+    if dims.iter().any(|dim| dim.len == 0) {
+        anyhow::bail!("record dimensions can't be written");
+    }
+    for var in vars {
+        let expected: usize = var.dims.iter().map(|&d| dims[d].len).product();
+        let actual = match &var.data {
+            Data::Float(values) => values.len(),
+            Data::Double(values) => values.len(),
+        };
+        if expected != actual {
+            anyhow::bail!(
+                "variable {} has {actual} values, expected {expected}",
+                var.name
+            );
+        }
+    }
+
+    let data: Vec<Vec<u8>> = vars.iter().map(|var| var.data.encode()).collect();
+
+    // the header has to hold the data offsets, which depend on the header's size
+    let header_len = write_header(dims, attrs, vars, &data, 0)?.len();
+    let mut file = write_header(dims, attrs, vars, &data, header_len)?;
+    for bytes in data {
+        file.extend(padded(&bytes));
+    }
+
+    Ok(file)
+}
+
+fn write_header(
+    dims: &[Dimension],
+    attrs: &[Attribute],
+    vars: &[NewVariable],
+    data: &[Vec<u8>],
+    data_start: usize,
+) -> Result<Vec<u8>> {
+    let mut header = b"CDF\x01".to_vec();
+    header.extend(0u32.to_be_bytes());
+
+    write_list(&mut header, NC_DIMENSION, dims, |header, dim| {
+        write_name(header, &dim.name);
+        header.extend((dim.len as u32).to_be_bytes());
+    });
+    write_attrs(&mut header, attrs);
+
+    let mut begin = data_start;
+    let mut offsets = Vec::with_capacity(vars.len());
+    for bytes in data {
+        offsets.push(u32::try_from(begin).context("file too large for the classic format")?);
+        begin += bytes.len().next_multiple_of(4);
+    }
+
+    let entries: Vec<_> = vars.iter().zip(data).zip(offsets).collect();
+    write_list(
+        &mut header,
+        NC_VARIABLE,
+        &entries,
+        |header, ((var, bytes), offset)| {
+            write_name(header, &var.name);
+            header.extend((var.dims.len() as u32).to_be_bytes());
+            for &dim in &var.dims {
+                header.extend((dim as u32).to_be_bytes());
+            }
+            write_attrs(header, &var.attrs);
+            header.extend((var.data.nc_type() as u32).to_be_bytes());
+            header.extend((bytes.len().next_multiple_of(4) as u32).to_be_bytes());
+            header.extend(offset.to_be_bytes());
+        },
+    );
+
+    Ok(header)
+}
+
+fn write_list<T>(
+    header: &mut Vec<u8>,
+    tag: u32,
+    items: &[T],
+    mut item: impl FnMut(&mut Vec<u8>, &T),
+) {
+    if items.is_empty() {
+        header.extend([0; 8]);
+        return;
+    }
+
+    header.extend(tag.to_be_bytes());
+    header.extend((items.len() as u32).to_be_bytes());
+    for element in items {
+        item(header, element);
+    }
+}
+
+fn write_attrs(header: &mut Vec<u8>, attrs: &[Attribute]) {
+    write_list(header, NC_ATTRIBUTE, attrs, |header, attr| {
+        write_name(header, &attr.name);
+        match &attr.value {
+            AttributeValue::Text(text) => {
+                header.extend((NcType::Char as u32).to_be_bytes());
+                header.extend((text.len() as u32).to_be_bytes());
+                header.extend(padded(text.as_bytes()));
+            }
+            AttributeValue::Numbers(numbers) => {
+                header.extend((NcType::Double as u32).to_be_bytes());
+                header.extend((numbers.len() as u32).to_be_bytes());
+                header.extend(numbers.iter().flat_map(|n| n.to_be_bytes()));
+            }
+        }
+    });
+}
+
+fn write_name(header: &mut Vec<u8>, name: &str) {
+    header.extend((name.len() as u32).to_be_bytes());
+    header.extend(padded(name.as_bytes()));
+}
+
+/// The bytes followed by zeroes up to the next multiple of 4.
+fn padded(bytes: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    let padding = bytes.len().next_multiple_of(4) - bytes.len();
+    bytes.iter().copied().chain(std::iter::repeat_n(0, padding))
+}
+
+/// Cursor over the header of a file.
+struct Header<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+
+    /// Size of variable data offsets: 4 in classic files, 8 in 64-bit offset ones.
+    offset_size: usize,
+}
+
+impl<'a> Header<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| anyhow!("header is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Takes `len` bytes plus the padding up to the next multiple of 4.
+    fn take_padded(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.take(len)?;
+        self.take(len.next_multiple_of(4) - len)?;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn offset(&mut self) -> Result<usize> {
+        let offset = match self.offset_size {
+            8 => u64::from_be_bytes(self.take(8)?.try_into()?),
+            _ => self.u32()? as u64,
+        };
+        Ok(usize::try_from(offset)?)
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8(self.take_padded(len)?.to_vec())?)
+    }
+
+    /// Reads a tagged list, which is either absent (two zero words) or the tag, a count and the
+    /// elements.
+    fn list<T>(
+        &mut self,
+        tag: u32,
+        mut element: impl FnMut(&mut Self) -> Result<T>,
+    ) -> Result<Vec<T>> {
+        let found_tag = self.u32()?;
+        let count = self.u32()? as usize;
+
+        match found_tag {
+            0 if count == 0 => Ok(Vec::new()),
+            t if t == tag => (0..count).map(|_| element(self)).collect(),
+            t => anyhow::bail!("expected list tag {tag:#x}, found {t:#x}"),
+        }
+    }
+
+    fn attrs(&mut self) -> Result<Vec<Attribute>> {
+        self.list(NC_ATTRIBUTE, |h| {
+            let name = h.name()?;
+            let nc_type = NcType::from_code(h.u32()?)?;
+            let count = h.u32()? as usize;
+            let data = h.take_padded(count * nc_type.size())?;
+
+            let value = match nc_type {
+                NcType::Char => AttributeValue::Text(String::from_utf8_lossy(data).into_owned()),
+                _ => AttributeValue::Numbers(decode_values(nc_type, data)),
+            };
+            Ok(Attribute { name, value })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn written_files_parse_back() {
+        let dims = [
+            Dimension {
+                name: "lat".into(),
+                len: 2,
+            },
+            Dimension {
+                name: "longitude".into(),
+                len: 3,
+            },
+        ];
+        let attrs = [Attribute::text("title", "round trip")];
+        let vars = [
+            NewVariable {
+                name: "lat".into(),
+                dims: vec![0],
+                attrs: vec![Attribute::text("units", "degrees_north")],
+                data: Data::Float(vec![45., -45.]),
+            },
+            NewVariable {
+                name: "t".into(),
+                dims: vec![0, 1],
+                attrs: vec![Attribute::numbers("valid_range", &[200., 330.])],
+                data: Data::Double(vec![250.5, 260., 270., 280., 290., 300.25]),
+            },
+        ];
+
+        let file = NetCdf::parse(write(&dims, &attrs, &vars).unwrap()).unwrap();
+
+        let dims: Vec<_> = file.dims.iter().map(|d| (d.name.as_str(), d.len)).collect();
+        assert_eq!(dims, [("lat", 2), ("longitude", 3)]);
+        assert!(matches!(
+            file.global_attr("title"),
+            Some(AttributeValue::Text(text)) if text == "round trip"
+        ));
+
+        let lat = file.var("lat").unwrap();
+        assert_eq!(lat.nc_type, NcType::Float);
+        assert_eq!(lat.text_attr("units"), Some("degrees_north"));
+        assert_eq!(file.read(lat, 0).unwrap(), [45., -45.]);
+
+        let t = file.var("t").unwrap();
+        assert_eq!(file.shape(t), [2, 3]);
+        assert_eq!(t.number_attr("valid_range"), Some(200.));
+        assert_eq!(
+            file.read(t, 0).unwrap(),
+            [250.5, 260., 270., 280., 290., 300.25]
+        );
+    }
+
+    /// `testdata/classic.nc` holds `testdata/classic.cdl`, laid out from the format specification
+    /// independently of [`write`]: two padded record variables of narrow types after two fixed ones.
+    #[test]
+    fn reads_classic_fixture() {
+        let file = NetCdf::parse(include_bytes!("../testdata/classic.nc").to_vec()).unwrap();
+
+        let dims: Vec<_> = file.dims.iter().map(|d| (d.name.as_str(), d.len)).collect();
+        assert_eq!(dims, [("time", 0), ("lat", 3), ("lon", 3)]);
+        assert!(matches!(
+            file.global_attr("title"),
+            Some(AttributeValue::Text(text)) if text == "spacepaint fixture"
+        ));
+
+        let lat = file.var("lat").unwrap();
+        assert!(!file.is_record_var(lat));
+        assert_eq!(file.read(lat, 0).unwrap(), [10., 0., -10.]);
+        assert_eq!(
+            file.read(file.var("lon").unwrap(), 0).unwrap(),
+            [-20., 0., 20.]
+        );
+
+        let t2m = file.var("t2m").unwrap();
+        assert!(file.is_record_var(t2m));
+        assert_eq!(file.shape(t2m), [1, 3, 3]);
+        assert_eq!(t2m.number_attr("scale_factor"), Some(0.01));
+        assert_eq!(t2m.number_attr("add_offset"), Some(273.15));
+        assert_eq!(t2m.text_attr("units"), Some("K"));
+        assert_eq!(
+            file.read(t2m, 0).unwrap(),
+            [0., 100., 200., 300., 400., 500., 600., 700., 800.]
+        );
+        assert_eq!(
+            file.read(t2m, 1).unwrap(),
+            [-100., -200., -300., -400., -500., -600., -700., -800., -900.]
+        );
+
+        let haze = file.var("haze").unwrap();
+        assert_eq!(haze.nc_type, NcType::Byte);
+        assert_eq!(
+            file.read(haze, 1).unwrap(),
+            [-1., -2., -3., -4., -5., -6., -7., -8., -9.]
+        );
+        assert!(file.read(haze, 2).is_err());
+    }
+
+    #[test]
+    fn refuses_netcdf4() {
+        let error = NetCdf::parse(b"\x89HDF\r\n\x1a\n".to_vec()).err().unwrap();
+        assert!(format!("{error:#}").contains("NetCDF-4"));
+    }
+}
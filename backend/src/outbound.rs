@@ -0,0 +1,292 @@
+//! Per-client writer tasks, so a slow socket only ever holds up its own client.
+//!
+//! Each client has two outbound paths: a queue of control packets (IDs, history, presence, close
+//! frames) that are written in order, and one slot per viewport holding the newest snapshot not
+//! yet written. A client that can't keep up skips frames instead of falling further behind.
+
+use futures::{Sink, SinkExt};
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use warp::ws;
+
+use crate::metrics::Metrics;
+use crate::ConnectionSettings;
+
+/// Message waiting to be written, along with what kind of packet it is for metrics.
+struct Outbound {
+    kind: &'static str,
+    message: ws::Message,
+}
+
+/// Newest snapshot of a viewport that hasn't been written yet.
+struct PendingSnapshot {
+    payload: Vec<u8>,
+
+    /// When it was queued, to measure how far behind the client is.
+    queued_at: Instant,
+}
+
+/// Snapshot slots shared between the senders & the writer task.
+#[derive(Default)]
+struct Snapshots {
+    pending: Mutex<BTreeMap<u32, PendingSnapshot>>,
+
+    /// Wakes the writer when a slot is filled.
+    filled: Notify,
+}
+
+/// Queues into a client's writer task. Cloning it shares the queues.
+#[derive(Clone)]
+pub struct ClientSender {
+    client_id: u64,
+    control: mpsc::Sender<Outbound>,
+    snapshots: Arc<Snapshots>,
+    metrics: Metrics,
+}
+
+impl ClientSender {
+    /// Queues a control packet without waiting. It's dropped if the client's queue is full or gone,
+    /// so only use this for packets that are fine to lose, like presence.
+    pub fn send(&self, kind: &'static str, payload: Vec<u8>) -> bool {
+        let outbound = Outbound {
+            kind,
+            message: ws::Message::binary(payload),
+        };
+
+        match self.control.try_send(outbound) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Dropped {kind} packet for client {}: {e}", self.client_id);
+                self.metrics.record_dropped(kind);
+                false
+            }
+        }
+    }
+
+    /// Queues a control packet, waiting for room in the queue.
+    pub async fn send_reliable(&self, kind: &'static str, payload: Vec<u8>) {
+        let outbound = Outbound {
+            kind,
+            message: ws::Message::binary(payload),
+        };
+
+        if self.control.send(outbound).await.is_err() {
+            debug!("Client {} was gone before {kind} packet", self.client_id);
+            self.metrics.record_dropped(kind);
+        }
+    }
+
+    /// Puts a snapshot of a viewport up for writing, replacing one that hasn't been written yet.
+    pub fn send_snapshot(&self, viewport_id: u32, payload: Vec<u8>) {
+        let replaced = self.snapshots.pending.lock().unwrap().insert(
+            viewport_id,
+            PendingSnapshot {
+                payload,
+                queued_at: Instant::now(),
+            },
+        );
+        if replaced.is_some() {
+            self.metrics.record_superseded();
+        }
+
+        self.snapshots.filled.notify_one();
+    }
+
+    /// Queues a close frame after the pending control packets. The writer stops after sending it.
+    pub async fn close(&self, code: u16, reason: &'static str) {
+        let outbound = Outbound {
+            kind: "close",
+            message: ws::Message::close_with(code, reason),
+        };
+
+        if self.control.send(outbound).await.is_err() {
+            debug!("Client {} was already gone when closing", self.client_id);
+        }
+    }
+}
+
+/// Spawns a task writing queued packets to `sink`, normally a client's websocket, until every
+/// [`ClientSender`] is dropped, the connection is closed or a write fails.
+///
+/// Control packets always go out before snapshots, and a ping is sent every heartbeat interval.
+pub fn spawn_writer<S>(
+    client_id: u64,
+    mut sink: S,
+    settings: ConnectionSettings,
+    metrics: Metrics,
+) -> (ClientSender, JoinHandle<()>)
+where
+    S: Sink<ws::Message> + Unpin + Send + 'static,
+    S::Error: Display,
+{
+    let (control, mut pending_control) = mpsc::channel::<Outbound>(settings.client_queue_size);
+    let snapshots = Arc::new(Snapshots::default());
+    let sender = ClientSender {
+        client_id,
+        control,
+        snapshots: snapshots.clone(),
+        metrics: metrics.clone(),
+    };
+
+    let writer = tokio::spawn(async move {
+        let mut heartbeat = tokio::time::interval(settings.heartbeat_interval);
+
+        loop {
+            tokio::select! {
+                biased;
+
+                control = pending_control.recv() => {
+                    let Some(Outbound { kind, message }) = control else {
+                        break;
+                    };
+                    let closing = message.is_close();
+                    if !write(&mut sink, client_id, kind, message, &metrics).await || closing {
+                        break;
+                    }
+                }
+                _ = snapshots.filled.notified() => {
+                    let pending = std::mem::take(&mut *snapshots.pending.lock().unwrap());
+
+                    let mut failed = false;
+                    for snapshot in pending.into_values() {
+                        let message = ws::Message::binary(snapshot.payload);
+                        if !write(&mut sink, client_id, "snapshot", message, &metrics).await {
+                            failed = true;
+                            break;
+                        }
+                        metrics.record_lag(snapshot.queued_at.elapsed());
+                    }
+                    if failed {
+                        break;
+                    }
+                }
+                _ = heartbeat.tick() => {
+                    if !write(&mut sink, client_id, "ping", ws::Message::ping(Vec::new()), &metrics).await {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = sink.close().await {
+            debug!("Error closing websocket of client {client_id}: {e}");
+        }
+    });
+
+    (sender, writer)
+}
+
+/// Writes a message to the socket, returning whether that worked.
+async fn write<S>(
+    sink: &mut S,
+    client_id: u64,
+    kind: &'static str,
+    message: ws::Message,
+    metrics: &Metrics,
+) -> bool
+where
+    S: Sink<ws::Message> + Unpin,
+    S::Error: Display,
+{
+    let len = message.as_bytes().len();
+
+    let result = sink.send(message).await;
+    metrics.record_send(kind, len, result.is_ok());
+    if let Err(e) = &result {
+        warn!("Error sending {kind} packet to client {client_id}: {e}");
+    }
+
+    result.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc as sink_channel;
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    fn settings() -> ConnectionSettings {
+        ConnectionSettings {
+            max_snapshot_pixels: 880,
+            client_queue_size: 4,
+            // only the ping right at the start is sent during a test
+            heartbeat_interval: Duration::from_secs(3600),
+            heartbeat_timeout: Duration::from_secs(7200),
+            max_brush_degrees: 10.,
+        }
+    }
+
+    /// The next `count` binary payloads written by a writer, skipping pings.
+    async fn written(
+        socket: &mut sink_channel::Receiver<ws::Message>,
+        count: usize,
+    ) -> Vec<Vec<u8>> {
+        let mut payloads = Vec::new();
+        while payloads.len() < count {
+            let message = tokio::time::timeout(Duration::from_secs(1), socket.next())
+                .await
+                .expect("writer should keep writing")
+                .expect("writer stopped early");
+            if message.is_binary() {
+                payloads.push(message.into_bytes());
+            }
+        }
+        payloads
+    }
+
+    fn superseded(metrics: &Metrics) -> bool {
+        metrics
+            .render(0)
+            .lines()
+            .any(|line| line == "spacepaint_snapshots_superseded_total 1")
+    }
+
+    #[tokio::test]
+    async fn newer_snapshots_replace_unwritten_ones() {
+        let metrics = Metrics::new(Duration::from_secs(1)).unwrap();
+        let (sink, mut socket) = sink_channel::channel(16);
+        // the writer can't run before the test yields, so neither snapshot is written yet
+        let (sender, _writer) = spawn_writer(1, sink, settings(), metrics.clone());
+
+        sender.send_snapshot(0, b"old".to_vec());
+        sender.send_snapshot(0, b"new".to_vec());
+        sender.send_snapshot(1, b"other viewport".to_vec());
+        assert!(superseded(&metrics));
+
+        assert_eq!(
+            written(&mut socket, 2).await,
+            [b"new".to_vec(), b"other viewport".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn control_packets_go_out_in_order_before_snapshots() {
+        let metrics = Metrics::new(Duration::from_secs(1)).unwrap();
+        let (sink, mut socket) = sink_channel::channel(16);
+        let (sender, _writer) = spawn_writer(1, sink, settings(), metrics);
+
+        sender.send_snapshot(0, b"snapshot 1".to_vec());
+        sender
+            .send_reliable("assign_id", b"assign id".to_vec())
+            .await;
+        sender.send_snapshot(0, b"snapshot 2".to_vec());
+        assert!(sender.send("presence", b"presence".to_vec()));
+        sender.send_reliable("history", b"history".to_vec()).await;
+
+        assert_eq!(
+            written(&mut socket, 4).await,
+            [
+                b"assign id".to_vec(),
+                b"presence".to_vec(),
+                b"history".to_vec(),
+                b"snapshot 2".to_vec()
+            ]
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::state::{self, WorldSnapshot};
+
+/// What a fresh world starts from when there's no saved state to load.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum FreshWorld {
+    /// A state image, e.g. one made by the importer.
+    Image(PathBuf),
+
+    /// A world generated from a seed.
+    Generated { seed: u32 },
+}
+
+impl FreshWorld {
+    /// Loads or generates the fresh world.
+    pub fn create(&self) -> Result<WorldSnapshot> {
+        match self {
+            FreshWorld::Image(path) => WorldSnapshot::load(path)
+                .with_context(|| format!("loading fresh world from {}", path.display())),
+            FreshWorld::Generated { seed } => Ok(state::generate_world(*seed)),
+        }
+    }
+}
+
+/// Prefix of backup file names, followed by a Unix timestamp in milliseconds (seconds for backups
+/// taken by older versions, which sort as older either way).
+const BACKUP_PREFIX: &str = "state-";
+
+/// Saves the world to disk without ever leaving a half-written file behind, and keeps rotating
+/// backups of it.
+#[derive(Clone)]
+pub struct Persistence {
+    /// Live state file.
+    state_path: PathBuf,
+
+    /// Directory timestamped backups are written to.
+    backup_dir: PathBuf,
+
+    /// Number of backups kept around; older ones get deleted.
+    retention: usize,
+
+    /// Minimum time between two backups.
+    backup_interval: Duration,
+
+    /// When the last backup was taken, if any.
+    last_backup: Option<Instant>,
+}
+
+impl Persistence {
+    pub fn new(
+        state_path: impl Into<PathBuf>,
+        backup_dir: impl Into<PathBuf>,
+        retention: usize,
+        backup_interval: Duration,
+    ) -> Persistence {
+        Persistence {
+            state_path: state_path.into(),
+            backup_dir: backup_dir.into(),
+            retention,
+            backup_interval,
+            last_backup: None,
+        }
+    }
+
+    /// Loads the live state file, or failing that the newest backup that loads, or failing *that*
+    /// starts a fresh world.
+    pub async fn load_state(
+        &self,
+        fresh: &FreshWorld,
+        settings: state::Settings,
+    ) -> Result<state::State> {
+        let snapshot = self.read_newest_valid(fresh)?;
+        state::State::from_snapshot(snapshot, settings).await
+    }
+
+    fn read_newest_valid(&self, fresh: &FreshWorld) -> Result<WorldSnapshot> {
+        match WorldSnapshot::load(&self.state_path) {
+            Ok(snapshot) => {
+                info!(
+                    "Loaded state from {} at tick {}",
+                    self.state_path.display(),
+                    snapshot.metadata.tick
+                );
+                return Ok(snapshot);
+            }
+            Err(e) if self.state_path.exists() => {
+                error!(
+                    "State file {} is unreadable: {e:#}",
+                    self.state_path.display()
+                );
+
+                // keep the broken file around for inspection instead of saving over it
+                let aside = self
+                    .state_path
+                    .with_extension(format!("corrupt-{}", state::now_ms()));
+                match fs::rename(&self.state_path, &aside) {
+                    Ok(()) => warn!("Moved unreadable state file to {}", aside.display()),
+                    Err(e) => warn!("couldn't move unreadable state file aside: {e}"),
+                }
+            }
+            Err(_) => warn!("No state file at {}", self.state_path.display()),
+        }
+
+        for backup in self.backups_newest_first() {
+            match WorldSnapshot::load(&backup) {
+                Ok(snapshot) => {
+                    warn!(
+                        "Restored state from backup {} at tick {}",
+                        backup.display(),
+                        snapshot.metadata.tick
+                    );
+                    return Ok(snapshot);
+                }
+                Err(e) => warn!("Skipping unreadable backup {}: {e:#}", backup.display()),
+            }
+        }
+
+        match fresh {
+            FreshWorld::Image(path) => {
+                error!(
+                    "NO VALID SAVED STATE OR BACKUP FOUND - starting a fresh world from {}",
+                    path.display()
+                );
+            }
+            FreshWorld::Generated { seed } => {
+                error!("NO VALID SAVED STATE OR BACKUP FOUND - generating a fresh world from seed {seed}");
+            }
+        }
+        fresh.create()
+    }
+
+    /// Atomically saves the given snapshot to the live state file, taking a backup if one is due.
+    ///
+    /// Encoding & syncing take a while, so they're done off the async runtime.
+    pub async fn save(&mut self, snapshot: WorldSnapshot) -> Result<()> {
+        let mut saving = self.clone();
+        let (saving, saved) = tokio::task::spawn_blocking(move || {
+            let saved = saving.save_blocking(&snapshot);
+            (saving, saved)
+        })
+        .await
+        .expect("saving task panicked");
+        *self = saving;
+
+        saved
+    }
+
+    fn save_blocking(&mut self, snapshot: &WorldSnapshot) -> Result<()> {
+        write_atomically(snapshot, &self.state_path)
+            .with_context(|| format!("saving state to {}", self.state_path.display()))?;
+        debug!("Saved state to {}", self.state_path.display());
+
+        let backup_due = self
+            .last_backup
+            .is_none_or(|last| last.elapsed() >= self.backup_interval);
+        if backup_due && self.retention > 0 {
+            self.back_up()?;
+            self.last_backup = Some(Instant::now());
+        }
+
+        Ok(())
+    }
+
+    /// Copies the live state file into a new timestamped backup, then prunes old backups.
+    fn back_up(&self) -> Result<()> {
+        fs::create_dir_all(&self.backup_dir)
+            .with_context(|| format!("creating backup directory {}", self.backup_dir.display()))?;
+
+        // saves can come quicker than the clock ticks, so never overwrite an existing backup
+        let mut timestamp = state::now_ms();
+        let mut backup_path = self.backup_path(timestamp);
+        while backup_path.exists() {
+            timestamp += 1;
+            backup_path = self.backup_path(timestamp);
+        }
+        let temp_path = backup_path.with_extension("png.tmp");
+        fs::copy(&self.state_path, &temp_path)?;
+        fs::File::open(&temp_path)?.sync_all()?;
+        fs::rename(&temp_path, &backup_path)?;
+        debug!("Backed up state to {}", backup_path.display());
+
+        for old_backup in self.backups_newest_first().into_iter().skip(self.retention) {
+            match fs::remove_file(&old_backup) {
+                Ok(()) => debug!("Removed old backup {}", old_backup.display()),
+                Err(e) => warn!("couldn't remove old backup {}: {e}", old_backup.display()),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn backup_path(&self, timestamp_ms: u64) -> PathBuf {
+        self.backup_dir
+            .join(format!("{BACKUP_PREFIX}{timestamp_ms}.png"))
+    }
+
+    /// Lists existing backups, sorted by the timestamp in their name.
+    fn backups_newest_first(&self) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(&self.backup_dir) else {
+            return Vec::new();
+        };
+
+        let mut backups: Vec<(u64, PathBuf)> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let timestamp = path
+                    .file_name()?
+                    .to_str()?
+                    .strip_prefix(BACKUP_PREFIX)?
+                    .strip_suffix(".png")?
+                    .parse()
+                    .ok()?;
+                Some((timestamp, path))
+            })
+            .collect();
+        backups.sort_unstable_by_key(|(timestamp, _)| std::cmp::Reverse(*timestamp));
+
+        backups.into_iter().map(|(_, path)| path).collect()
+    }
+}
+
+/// Writes the snapshot to a temporary file next to `path`, syncs it, then renames it over `path`.
+fn write_atomically(snapshot: &WorldSnapshot, path: &Path) -> Result<()> {
+    let temp_path = path.with_extension("png.tmp");
+    snapshot.save(&temp_path)?;
+    fs::File::open(&temp_path)?.sync_all()?;
+    fs::rename(&temp_path, path)?;
+
+    // make the rename itself durable; not all platforms allow opening directories, so best-effort
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        if let Ok(dir) = fs::File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{SnapshotMetadata, STATE_BYTES};
+
+    /// An empty directory of its own for a test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "spacepaint-persistence-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn snapshot(tick: u64) -> WorldSnapshot {
+        WorldSnapshot {
+            metadata: SnapshotMetadata::current(tick),
+            data: vec![0; STATE_BYTES],
+        }
+    }
+
+    #[test]
+    fn failed_writes_leave_the_old_state() {
+        let dir = scratch_dir("atomic");
+        let path = dir.join("state.png");
+
+        write_atomically(&snapshot(1), &path).unwrap();
+        assert_eq!(WorldSnapshot::load(&path).unwrap().metadata.tick, 1);
+        assert!(!path.with_extension("png.tmp").exists());
+
+        // the temporary file can't be created where a directory is in the way
+        fs::create_dir(path.with_extension("png.tmp")).unwrap();
+        assert!(write_atomically(&snapshot(2), &path).is_err());
+        assert_eq!(WorldSnapshot::load(&path).unwrap().metadata.tick, 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn retention_deletes_only_the_oldest_backups() {
+        let dir = scratch_dir("retention");
+        let backups = dir.join("backups");
+        fs::create_dir(&backups).unwrap();
+        fs::write(dir.join("state.png"), b"state").unwrap();
+        for name in [
+            "state-100.png",
+            "state-200.png",
+            "state-300.png",
+            "notes.txt",
+        ] {
+            fs::write(backups.join(name), b"old").unwrap();
+        }
+
+        let persistence =
+            Persistence::new(dir.join("state.png"), &backups, 2, Duration::from_secs(60));
+        persistence.back_up().unwrap();
+
+        let kept = persistence.backups_newest_first();
+        assert_eq!(kept.len(), 2);
+        assert_eq!(fs::read(&kept[0]).unwrap(), b"state");
+        assert_eq!(kept[1], backups.join("state-300.png"));
+        assert!(!backups.join("state-100.png").exists());
+        assert!(!backups.join("state-200.png").exists());
+        assert!(backups.join("notes.txt").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
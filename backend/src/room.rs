@@ -0,0 +1,770 @@
+//! Independent worlds served from one process, each with its own state, clients & tasks.
+//!
+//! `/sync` joins the default room, stored where `[paths]` says. `/sync/{name}` joins a named room
+//! kept in its own directory under `paths.rooms_dir`, loading it on first join. Painters & admins
+//! joining a room that doesn't exist create it; viewers can only join existing ones. Named rooms
+//! nobody has been in for a while are saved & unloaded until someone joins again.
+
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::admin::SaveRequest;
+use crate::health::Health;
+use crate::message::Role;
+use crate::metrics::Metrics;
+use crate::persistence::{FreshWorld, Persistence};
+use crate::scenario::{self, Scenario};
+use crate::{journal, message, state, Client, GlobalState, QueuedModification};
+
+/// Name of the room `/sync` joins.
+pub const DEFAULT_ROOM: &str = "default";
+
+/// Longest allowed room name.
+const MAX_NAME_LEN: usize = 32;
+
+/// Time between checks for idle rooms.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What every room's tasks share.
+pub struct Services {
+    pub metrics: Metrics,
+
+    /// Time between ticks, the same for every room.
+    pub tick_interval: watch::Receiver<Duration>,
+    pub modification_queue_size: usize,
+    pub save_interval: Duration,
+}
+
+/// Where named rooms live & how many may be loaded.
+pub struct Settings {
+    /// Directory holding a subdirectory per named room.
+    pub dir: PathBuf,
+
+    /// Most named rooms loaded at once.
+    pub max_loaded: usize,
+
+    /// Whether painters & admins joining a room that doesn't exist yet create it.
+    pub create_on_join: bool,
+
+    /// Most named rooms kept in `dir`.
+    pub max_rooms: usize,
+
+    /// Named rooms without connections for this long are unloaded.
+    pub idle_timeout: Duration,
+
+    /// What new rooms start from.
+    pub fresh_world: FreshWorld,
+    pub state: state::Settings,
+    pub backup_retention: usize,
+    pub backup_interval: Duration,
+}
+
+/// Why joining a room that doesn't exist didn't create it, as opposed to the room failing to load.
+#[derive(Debug)]
+pub enum Refused {
+    /// Rooms aren't created on join.
+    NoSuchRoom(String),
+
+    /// Only painters & admins may create rooms.
+    ViewersCantCreate,
+
+    /// This many rooms exist already, which is the limit.
+    TooManyRooms(usize),
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Refused::NoSuchRoom(name) => write!(f, "there's no room called {name}"),
+            Refused::ViewersCantCreate => write!(f, "viewers can only join existing rooms"),
+            Refused::TooManyRooms(count) => {
+                write!(f, "{count} rooms exist already, which is the limit")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// A running world along with the tasks ticking, saving & modifying it.
+pub struct Room {
+    pub name: String,
+    pub state_shard: Arc<Mutex<GlobalState>>,
+    pub modification_sink: mpsc::Sender<QueuedModification>,
+
+    /// Asks the save task to save immediately.
+    pub save_requests: mpsc::Sender<SaveRequest>,
+
+    /// World tick, updated after every step.
+    pub ticks: watch::Receiver<u64>,
+
+    /// Scenario playing in the room, if any.
+    scenario: std::sync::Mutex<Option<JoinHandle<()>>>,
+
+    /// Stops the room's tasks once it flips to true.
+    shutdown: watch::Sender<bool>,
+    tasks: std::sync::Mutex<Option<Tasks>>,
+    occupancy: std::sync::Mutex<Occupancy>,
+}
+
+struct Tasks {
+    modification: JoinHandle<()>,
+    tick: JoinHandle<()>,
+
+    /// Hands persistence back for the final save.
+    save: JoinHandle<Persistence>,
+}
+
+struct Occupancy {
+    connections: usize,
+
+    /// When the last connection left, or when the room was loaded.
+    empty_since: Instant,
+}
+
+/// Counts a connection or request as in a room for as long as it's held, keeping the room loaded.
+pub struct Membership(Arc<Room>);
+
+/// Every loaded room.
+pub struct Rooms {
+    default: Arc<Room>,
+
+    /// Named rooms by name. Held while rooms are loaded & taken out to be unloaded, so a room's
+    /// files are never used by two instances of it at once.
+    named: Mutex<HashMap<String, Arc<Room>>>,
+
+    /// Named rooms taken out of `named` that are still shutting down, each closed once it has.
+    /// Loading one of these again waits for that.
+    unloading: std::sync::Mutex<HashMap<String, watch::Receiver<()>>>,
+    services: Services,
+    settings: Settings,
+}
+
+impl Room {
+    /// Starts the tasks of a room holding `state`.
+    ///
+    /// Only the default room reports to `health`, and to the save metrics which track the same.
+    pub fn spawn(
+        name: String,
+        state: state::State,
+        persistence: Persistence,
+        journal: journal::Journal,
+        services: &Services,
+        health: Option<Arc<Health>>,
+    ) -> Arc<Room> {
+        let (tick_sender, ticks) = watch::channel(state.tick());
+        let state_shard = Arc::new(Mutex::new(GlobalState {
+            map: state,
+            clients: HashMap::new(),
+            journal,
+        }));
+        let (modification_sink, mut mod_queue) = mpsc::channel(services.modification_queue_size);
+        let (save_requests, mut save_request_queue) = mpsc::channel(1);
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+
+        // spawn task to apply modifications
+        let state_modification = state_shard.clone();
+        let mut modification_shutdown = shutdown_receiver.clone();
+        let modification = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    modif = mod_queue.recv() => match modif {
+                        Some(modif) => apply_modification(&state_modification, modif).await,
+                        None => break,
+                    },
+                    _ = modification_shutdown.changed() => {
+                        // refuse new modifications, but apply the ones already queued
+                        mod_queue.close();
+                        while let Some(modif) = mod_queue.recv().await {
+                            apply_modification(&state_modification, modif).await;
+                        }
+                        break;
+                    }
+                }
+            }
+        });
+
+        // also spawn task to step internal state every tick interval
+        let state_ticking = state_shard.clone();
+        let metrics_ticking = services.metrics.clone();
+        let health_ticking = health.clone();
+        let mut tick_interval = services.tick_interval.clone();
+        let mut tick_shutdown = shutdown_receiver.clone();
+        let tick = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(*tick_interval.borrow_and_update());
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = tick_shutdown.changed() => break,
+                    Ok(()) = tick_interval.changed() => {
+                        interval = tokio::time::interval(*tick_interval.borrow_and_update());
+                        continue;
+                    }
+                }
+
+                // only the tick itself happens under the lock; snapshots are rendered & history
+                // frames downsampled from shared copies
+                let (frame, tick, subscribers, due_frame) = {
+                    let mut locked_state = state_ticking.lock().await;
+
+                    let tick_start = Instant::now();
+                    // a failed tick is retried next interval rather than taking the room down
+                    let timings = match locked_state.map.tick_state_by_count(1).await {
+                        Ok(timings) => timings,
+                        Err(e) => {
+                            error!("couldn't tick state: {e:#}");
+                            if let Some(health) = &health_ticking {
+                                health.record_tick_error(&e);
+                            }
+                            continue;
+                        }
+                    };
+                    metrics_ticking.record_tick(&timings, tick_start.elapsed());
+                    tick_sender.send_replace(locked_state.map.tick());
+                    if let Some(health) = &health_ticking {
+                        health.record_tick(locked_state.map.tick());
+                    }
+
+                    let subscribers: Vec<_> = locked_state
+                        .clients
+                        .iter()
+                        .filter(|(_, client)| !client.viewports.is_empty())
+                        .map(|(client_id, client)| {
+                            (
+                                *client_id,
+                                client.outbound.clone(),
+                                client.encoding.clone(),
+                                client.viewports.clone(),
+                            )
+                        })
+                        .collect();
+
+                    (
+                        locked_state.map.frame(),
+                        locked_state.map.tick(),
+                        subscribers,
+                        locked_state.map.due_frame(),
+                    )
+                };
+
+                let metrics = metrics_ticking.clone();
+                let ready_frame = tokio::task::spawn_blocking(move || {
+                    for (client_id, outbound, encoding, viewports) in subscribers {
+                        let render_start = Instant::now();
+                        for (viewport_id, view) in viewports {
+                            let rendered = frame
+                                .render(view.area, view.resolution, &view.layers, &encoding)
+                                .and_then(|(data, location)| {
+                                    message::serialize_packet(message::Packet::Snapshot {
+                                        viewport_id,
+                                        tick,
+                                        data,
+                                        location,
+                                    })
+                                });
+
+                            // one bad viewport shouldn't keep the others (or the world) from updating
+                            match rendered {
+                                Ok(packet_data) => outbound.send_snapshot(viewport_id, packet_data),
+                                Err(e) => warn!(
+                                    "couldn't render viewport {viewport_id} of client {client_id}: {e:#}"
+                                ),
+                            }
+                        }
+                        metrics.record_render(render_start.elapsed());
+                    }
+
+                    due_frame.map(|due| due.downsample())
+                })
+                .await
+                .unwrap_or_else(|e| {
+                    error!("snapshot rendering task failed: {e}");
+                    None
+                });
+
+                if let Some(ready_frame) = ready_frame {
+                    state_ticking.lock().await.map.record_frame(ready_frame);
+                }
+            }
+        });
+
+        // *also* spawn task to save state to file every save interval
+        let state_saving = state_shard.clone();
+        let metrics_saving = services.metrics.clone();
+        let name_saving = name.clone();
+        let save_interval = services.save_interval;
+        let mut save_shutdown = shutdown_receiver;
+        let mut persistence = persistence;
+        let save = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(save_interval);
+
+            loop {
+                // saves are also done early when asked through the admin API
+                let requested: Option<SaveRequest> = tokio::select! {
+                    _ = interval.tick() => None,
+                    Some(reply) = save_request_queue.recv() => Some(reply),
+                    // hand persistence back for the final save
+                    _ = save_shutdown.changed() => break persistence,
+                };
+
+                let snapshot = {
+                    let locked_state = state_saving.lock().await;
+                    locked_state.map.snapshot()
+                };
+
+                let saved = persistence.save(snapshot).await;
+                if let Some(health) = &health {
+                    health.record_save(&saved);
+                    if saved.is_ok() {
+                        metrics_saving.record_save();
+                    }
+                }
+                if let Err(e) = &saved {
+                    error!("couldn't save room {name_saving}: {e:#}");
+                }
+                if let Some(reply) = requested {
+                    let _ = reply.send(saved);
+                }
+            }
+        });
+
+        Arc::new(Room {
+            name,
+            state_shard,
+            modification_sink,
+            save_requests,
+            ticks,
+            scenario: std::sync::Mutex::new(None),
+            shutdown,
+            tasks: std::sync::Mutex::new(Some(Tasks {
+                modification,
+                tick,
+                save,
+            })),
+            occupancy: std::sync::Mutex::new(Occupancy {
+                connections: 0,
+                empty_since: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn join(self: &Arc<Room>) -> Membership {
+        self.occupancy.lock().unwrap().connections += 1;
+        Membership(self.clone())
+    }
+
+    /// How long the room has been without connections, if it is.
+    fn idle_for(&self) -> Option<Duration> {
+        let occupancy = self.occupancy.lock().unwrap();
+        (occupancy.connections == 0).then(|| occupancy.empty_since.elapsed())
+    }
+
+    /// Plays a scenario in the room, stopping any already playing.
+    pub fn play_scenario(&self, scenario: Scenario) {
+        let task = tokio::spawn(scenario::run(
+            scenario,
+            self.modification_sink.clone(),
+            self.ticks.clone(),
+        ));
+        if let Some(previous) = self.scenario.lock().unwrap().replace(task) {
+            previous.abort();
+        }
+    }
+
+    /// Stops the scenario playing in the room. Returns whether one was still playing.
+    pub fn stop_scenario(&self) -> bool {
+        match self.scenario.lock().unwrap().take() {
+            Some(task) if !task.is_finished() => {
+                task.abort();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Stops the room's tasks, applying queued modifications, then ticks & saves one last time
+    /// and closes every client connection.
+    pub async fn shut_down(&self) -> Result<()> {
+        self.stop_scenario();
+        self.shutdown.send_replace(true);
+        let tasks = self
+            .tasks
+            .lock()
+            .unwrap()
+            .take()
+            .with_context(|| format!("room {} was already shut down", self.name))?;
+        // a task that panicked shouldn't keep the others' work from being saved
+        if let Err(e) = tasks.modification.await {
+            error!("modification task of room {} failed: {e}", self.name);
+        }
+        if let Err(e) = tasks.tick.await {
+            error!("tick task of room {} failed: {e}", self.name);
+        }
+        let persistence = tasks.save.await.context("save task failed");
+
+        // one last tick so drained modifications are stepped like any other
+        let mut locked_state = self.state_shard.lock().await;
+        let saved = match persistence {
+            Ok(mut persistence) => match locked_state.map.tick_state_by_count(1).await {
+                Ok(_) => persistence.save(locked_state.map.snapshot()).await,
+                Err(e) => Err(e.context("final tick failed")),
+            },
+            Err(e) => Err(e),
+        };
+        match &saved {
+            Ok(()) => info!(
+                "Saved room {} at tick {}",
+                self.name,
+                locked_state.map.tick()
+            ),
+            Err(e) => error!("couldn't save room {} on shutdown: {e:#}", self.name),
+        }
+
+        let clients: Vec<Client> = locked_state
+            .clients
+            .drain()
+            .map(|(_, client)| client)
+            .collect();
+        drop(locked_state);
+        futures::future::join_all(
+            clients
+                .into_iter()
+                .map(|client| client.close(1001, "server shutting down")),
+        )
+        .await;
+
+        saved
+    }
+}
+
+impl Deref for Membership {
+    type Target = Room;
+
+    fn deref(&self) -> &Room {
+        &self.0
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        let mut occupancy = self.0.occupancy.lock().unwrap();
+        occupancy.connections -= 1;
+        if occupancy.connections == 0 {
+            occupancy.empty_since = Instant::now();
+        }
+    }
+}
+
+impl Rooms {
+    pub fn new(default: Arc<Room>, services: Services, settings: Settings) -> Rooms {
+        Rooms {
+            default,
+            named: Mutex::new(HashMap::new()),
+            unloading: std::sync::Mutex::new(HashMap::new()),
+            services,
+            settings,
+        }
+    }
+
+    /// Joins the room called `name`, or the default room without one, loading it as needed.
+    /// Clients with `role` may create it if it doesn't exist yet, unless they're viewers.
+    pub async fn join(&self, name: Option<&str>, role: Role) -> Result<Membership> {
+        let name = match name {
+            None | Some(DEFAULT_ROOM) => return Ok(self.default.join()),
+            Some(name) => name,
+        };
+        validate_name(name)?;
+
+        let mut named = self.lock_settled(name).await;
+        if let Some(room) = named.get(name) {
+            return Ok(room.join());
+        }
+
+        if !self.settings.dir.join(name).is_dir() {
+            self.create(name, role)?;
+        }
+
+        // joined under the lock so the new room can't be unloaded as idle first
+        Ok(self.load_into(&mut named, name).await?.join())
+    }
+
+    /// The room called `name`, or the default room without one, loading it if it exists but
+    /// isn't loaded. `None` if there's no such room; unlike joining, this never creates one.
+    ///
+    /// The room is kept loaded for as long as the returned membership is held, so edits made
+    /// through it can't come after its final save.
+    pub async fn get(&self, name: Option<&str>) -> Result<Option<Membership>> {
+        let name = match name {
+            None | Some(DEFAULT_ROOM) => return Ok(Some(self.default.join())),
+            Some(name) => name,
+        };
+        if validate_name(name).is_err() {
+            return Ok(None);
+        }
+
+        let mut named = self.lock_settled(name).await;
+        if let Some(room) = named.get(name) {
+            return Ok(Some(room.join()));
+        }
+        if !self.settings.dir.join(name).is_dir() {
+            return Ok(None);
+        }
+
+        Ok(Some(self.load_into(&mut named, name).await?.join()))
+    }
+
+    /// Locks the named rooms once the room called `name` isn't still being unloaded, so it can be
+    /// loaded again.
+    async fn lock_settled(
+        &self,
+        name: &str,
+    ) -> tokio::sync::MutexGuard<'_, HashMap<String, Arc<Room>>> {
+        loop {
+            let named = self.named.lock().await;
+            let unloading = self.unloading.lock().unwrap().get(name).cloned();
+            let Some(mut unloaded) = unloading else {
+                return named;
+            };
+
+            // other rooms can be used while this one finishes shutting down
+            drop(named);
+            let _ = unloaded.changed().await;
+        }
+    }
+
+    /// The room called `name`, or the default room without one, if it's loaded.
+    pub async fn loaded(&self, name: Option<&str>) -> Option<Arc<Room>> {
+        match name {
+            None | Some(DEFAULT_ROOM) => Some(self.default.clone()),
+            Some(name) => self.named.lock().await.get(name).cloned(),
+        }
+    }
+
+    /// Whether there's a room called `name`, loaded or not.
+    pub fn exists(&self, name: &str) -> bool {
+        name == DEFAULT_ROOM
+            || (validate_name(name).is_ok() && self.settings.dir.join(name).is_dir())
+    }
+
+    /// Loads a named room into `named`, if that stays within the limit of loaded rooms.
+    async fn load_into(
+        &self,
+        named: &mut HashMap<String, Arc<Room>>,
+        name: &str,
+    ) -> Result<Arc<Room>> {
+        if named.len() >= self.settings.max_loaded {
+            anyhow::bail!(
+                "{} rooms are already loaded, which is the limit",
+                named.len()
+            );
+        }
+
+        let room = self.load(name).await?;
+        named.insert(name.to_owned(), room.clone());
+        self.services.metrics.set_loaded_rooms(named.len());
+
+        Ok(room)
+    }
+
+    /// Creates the directory of a new named room, if clients with `role` may create rooms and
+    /// there's room for one more. Called under the `named` lock, so rooms are counted one at a time.
+    fn create(&self, name: &str, role: Role) -> Result<()> {
+        if !self.settings.create_on_join {
+            return Err(Refused::NoSuchRoom(name.to_owned()).into());
+        }
+        if role == Role::Viewer {
+            return Err(Refused::ViewersCantCreate.into());
+        }
+
+        let existing = std::fs::read_dir(&self.settings.dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.path().is_dir())
+                    .count()
+            })
+            .unwrap_or(0);
+        if existing >= self.settings.max_rooms {
+            return Err(Refused::TooManyRooms(existing).into());
+        }
+
+        let dir = self.settings.dir.join(name);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("creating room directory {}", dir.display()))?;
+        info!("Creating room {name} in {}", dir.display());
+
+        Ok(())
+    }
+
+    /// Loads a named room from its directory.
+    async fn load(&self, name: &str) -> Result<Arc<Room>> {
+        let dir = self.settings.dir.join(name);
+        let state_file = dir.join("state.png");
+        let persistence = Persistence::new(
+            &state_file,
+            dir.join("backups"),
+            self.settings.backup_retention,
+            self.settings.backup_interval,
+        );
+        let state = if state_file.exists() {
+            persistence
+                .load_state(&self.settings.fresh_world, self.settings.state)
+                .await?
+        } else {
+            // a new room rather than a lost one, so start fresh without the alarm
+            state::State::from_snapshot(self.settings.fresh_world.create()?, self.settings.state)
+                .await?
+        };
+        let journal = journal::Journal::open(dir.join("journal.jsonl"))?;
+        info!("Loaded room {name} at tick {}", state.tick());
+
+        Ok(Room::spawn(
+            name.to_owned(),
+            state,
+            persistence,
+            journal,
+            &self.services,
+            None,
+        ))
+    }
+
+    /// Unloads named rooms that have been empty for longer than the idle timeout, until
+    /// `shutdown` flips.
+    pub async fn unload_idle(&self, mut shutdown: watch::Receiver<bool>) {
+        let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.changed() => break,
+            }
+
+            // idle rooms are taken out under the lock but shut down without it, since that takes
+            // a while & shouldn't hold up joining other rooms
+            let idle: Vec<(String, Arc<Room>, watch::Sender<()>)> = {
+                let mut named = self.named.lock().await;
+                let names: Vec<String> = named
+                    .iter()
+                    .filter(|(_, room)| {
+                        room.idle_for()
+                            .is_some_and(|idle| idle >= self.settings.idle_timeout)
+                    })
+                    .map(|(name, _)| name.clone())
+                    .collect();
+
+                let mut unloading = self.unloading.lock().unwrap();
+                let idle = names
+                    .into_iter()
+                    .map(|name| {
+                        let room = named.remove(&name).expect("idle room should be loaded");
+                        let (unloaded, waiting) = watch::channel(());
+                        unloading.insert(name.clone(), waiting);
+                        (name, room, unloaded)
+                    })
+                    .collect();
+                self.services.metrics.set_loaded_rooms(named.len());
+                idle
+            };
+
+            for (name, room, unloaded) in idle {
+                match room.shut_down().await {
+                    Ok(()) => info!("Unloaded idle room {name}"),
+                    Err(e) => error!("couldn't cleanly unload room {name}: {e:#}"),
+                }
+                // dropping the sender lets anyone waiting to load the room again go ahead
+                self.unloading.lock().unwrap().remove(&name);
+                drop(unloaded);
+            }
+        }
+    }
+
+    /// Shuts down every room, named ones first. Returns how saving the default room went.
+    pub async fn shut_down(&self) -> Result<()> {
+        for (name, room) in self.named.lock().await.drain() {
+            if let Err(e) = room.shut_down().await {
+                error!("couldn't cleanly shut down room {name}: {e:#}");
+            }
+        }
+        debug!("Shut down all named rooms");
+
+        self.default.shut_down().await
+    }
+}
+
+/// Checks that a room name is safe to use as a directory name.
+pub fn validate_name(name: &str) -> Result<()> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if name.is_empty() || name.len() > MAX_NAME_LEN || !valid_chars {
+        anyhow::bail!(
+            "room names are 1 to {MAX_NAME_LEN} lowercase letters, digits, dashes or underscores"
+        );
+    }
+
+    Ok(())
+}
+
+/// Applies a queued modification to the state and records it in the journal.
+async fn apply_modification(state_shard: &Mutex<GlobalState>, modif: QueuedModification) {
+    debug!("Processing modification packet");
+    let QueuedModification {
+        client_id,
+        peer,
+        role,
+        packet,
+    } = modif;
+
+    let mut locked_state = state_shard.lock().await;
+    let rejection = match locked_state
+        .map
+        .process_modification(client_id, role, &packet)
+    {
+        Ok(rejection) => rejection,
+        Err(e) => {
+            warn!("error processing modification: {e}");
+            return;
+        }
+    };
+
+    if !rejection.is_empty() {
+        debug!(
+            "Clipped stroke of client {client_id} against zones {:?}",
+            rejection.zones
+        );
+        if let (Some(client), message::Packet::Modification { points, .. }) =
+            (locked_state.clients.get(&client_id), &packet)
+        {
+            let rejected = message::Packet::ModificationRejected {
+                points: rejection.points.iter().map(|&i| points[i]).collect(),
+                zones: rejection.zones,
+            };
+            let payload = message::serialize_packet(rejected)
+                .expect("couldn't serialize modification rejection packet");
+            client.outbound.send("modification_rejected", payload);
+        }
+    }
+
+    let entry = journal::Entry {
+        tick: locked_state.map.tick(),
+        timestamp_ms: state::now_ms(),
+        change: journal::Change::Modification {
+            client_id,
+            peer,
+            role: Some(role),
+            packet,
+        },
+    };
+    if let Err(e) = locked_state.journal.record(&entry) {
+        error!("couldn't write modification to journal: {e:#}");
+    }
+}
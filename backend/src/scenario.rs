@@ -0,0 +1,546 @@
+//! Scripted storylines of weather events, read from TOML scenario files.
+//!
+//! Events are timed in ticks since the scenario was started and turned into brush strokes queued
+//! like any client's, so they're clipped by zones & journaled. A scenario file looks like:
+//!
+//! ```toml
+//! [[event]]
+//! at_tick = 200
+//! kind = "disk"
+//! tool = "heat"
+//! center = { lat = 10.0, long = -40.0 }
+//! radius_degrees = 5.0
+//!
+//! [[event]]
+//! at_tick = 400
+//! kind = "vortex"
+//! center = { lat = 30.0, long = 20.0 }
+//! radius_degrees = 8.0
+//!
+//! [[event]]
+//! at_tick = 500
+//! kind = "ramp"
+//! tool = "humidify"
+//! polygon = [{ lat = 0.0, long = 0.0 }, { lat = 10.0, long = 10.0 }, { lat = 0.0, long = 20.0 }]
+//! duration_ticks = 50
+//! ```
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::Deserialize;
+use std::path::Path;
+use tokio::sync::{mpsc, watch};
+
+use crate::message::{LatLong, ModificationType, Packet, Region, Role};
+use crate::{state, QueuedModification};
+
+/// Client ID strokes of scenarios are made under.
+pub const SCENARIO_CLIENT_ID: u64 = 0;
+
+/// Brush size of scenario strokes, which is also the spacing of their points.
+const BRUSH_DEGREES: f64 = 1.;
+
+/// Largest radius of disks & vortices, which are painted as a brush point per square degree.
+const MAX_RADIUS_DEGREES: f64 = 90.;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(default, rename = "event")]
+    pub events: Vec<Event>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Event {
+    /// Paints a disk once.
+    Disk {
+        at_tick: u64,
+        tool: Tool,
+        center: LatLong,
+        radius_degrees: f64,
+    },
+
+    /// Paints wind circling around `center`, counterclockwise unless `clockwise`.
+    Vortex {
+        at_tick: u64,
+        center: LatLong,
+        radius_degrees: f64,
+        #[serde(default)]
+        clockwise: bool,
+    },
+
+    /// Paints a polygon every tick for `duration_ticks`, holding it against the simulation.
+    Ramp {
+        at_tick: u64,
+        tool: Tool,
+        polygon: Vec<LatLong>,
+        duration_ticks: u64,
+    },
+}
+
+/// Brushes events may paint with; wind comes from vortices.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Tool {
+    Heat,
+    Cool,
+    Humidify,
+    Dehumidify,
+}
+
+impl From<Tool> for ModificationType {
+    fn from(tool: Tool) -> ModificationType {
+        match tool {
+            Tool::Heat => ModificationType::Heat,
+            Tool::Cool => ModificationType::Cool,
+            Tool::Humidify => ModificationType::Humidify,
+            Tool::Dehumidify => ModificationType::Dehumidify,
+        }
+    }
+}
+
+impl Scenario {
+    pub async fn load(path: &Path) -> Result<Scenario> {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("reading scenario {}", path.display()))?;
+        let scenario: Scenario = toml::from_str(&text)
+            .with_context(|| format!("parsing scenario {}", path.display()))?;
+
+        for (i, event) in scenario.events.iter().enumerate() {
+            event
+                .validate()
+                .with_context(|| format!("event {} of scenario {}", i + 1, path.display()))?;
+        }
+
+        Ok(scenario)
+    }
+
+    /// Tick after which no event paints anymore.
+    fn last_tick(&self) -> u64 {
+        self.events
+            .iter()
+            .map(|event| match event {
+                Event::Ramp {
+                    at_tick,
+                    duration_ticks,
+                    ..
+                } => at_tick + duration_ticks - 1,
+                Event::Disk { at_tick, .. } | Event::Vortex { at_tick, .. } => *at_tick,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Strokes painted on the given tick since the start.
+    fn strokes_at(&self, tick: u64) -> Vec<Packet> {
+        self.events
+            .iter()
+            .filter(|event| event.paints_at(tick))
+            .flat_map(Event::strokes)
+            .collect()
+    }
+}
+
+impl Event {
+    fn validate(&self) -> Result<()> {
+        match self {
+            Event::Disk {
+                center,
+                radius_degrees,
+                ..
+            }
+            | Event::Vortex {
+                center,
+                radius_degrees,
+                ..
+            } => {
+                if !on_map(center) {
+                    anyhow::bail!("center {center:?} isn't on the map");
+                }
+                if !(*radius_degrees > 0. && *radius_degrees <= MAX_RADIUS_DEGREES) {
+                    anyhow::bail!(
+                        "radius_degrees must be above 0 and at most {MAX_RADIUS_DEGREES}"
+                    );
+                }
+            }
+            Event::Ramp {
+                polygon,
+                duration_ticks,
+                ..
+            } => {
+                state::validate_region(&Region::Polygon(polygon.clone()))?;
+                // the polygon is painted a brush point per square degree of its bounds
+                if let Some(corner) = polygon.iter().find(|corner| !on_map(corner)) {
+                    anyhow::bail!("corner {corner:?} isn't on the map");
+                }
+                if *duration_ticks == 0 {
+                    anyhow::bail!("duration_ticks must be above 0");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn paints_at(&self, tick: u64) -> bool {
+        match self {
+            Event::Disk { at_tick, .. } | Event::Vortex { at_tick, .. } => tick == *at_tick,
+            Event::Ramp {
+                at_tick,
+                duration_ticks,
+                ..
+            } => (*at_tick..at_tick + duration_ticks).contains(&tick),
+        }
+    }
+
+    fn strokes(&self) -> Vec<Packet> {
+        match self {
+            Event::Disk {
+                tool,
+                center,
+                radius_degrees,
+                ..
+            } => {
+                let points = grid(center.lat - radius_degrees, center.lat + radius_degrees)
+                    .flat_map(|lat| {
+                        grid(center.long - radius_degrees, center.long + radius_degrees)
+                            .map(move |long| LatLong { lat, long })
+                    })
+                    .filter(|point| {
+                        (point.lat - center.lat).hypot(point.long - center.long) <= *radius_degrees
+                    })
+                    .collect();
+                // a disk's points can go in any order, so it stays one stroke
+                let points = onto_map(points).into_iter().flatten().collect();
+                vec![stroke((*tool).into(), points)]
+            }
+            Event::Vortex {
+                center,
+                radius_degrees,
+                clockwise,
+                ..
+            } => {
+                // one closed ring per brush width, each its own stroke so wind follows the ring
+                let rings = (radius_degrees / BRUSH_DEGREES).ceil() as usize;
+                (1..=rings)
+                    .flat_map(|ring| {
+                        let radius = radius_degrees * ring as f64 / rings as f64;
+                        let steps = ((std::f64::consts::TAU * radius / BRUSH_DEGREES).ceil()
+                            as usize)
+                            .max(4);
+                        let direction = if *clockwise { -1. } else { 1. };
+                        let points = (0..=steps)
+                            .map(|step| {
+                                let angle =
+                                    direction * std::f64::consts::TAU * step as f64 / steps as f64;
+                                LatLong {
+                                    lat: center.lat + radius * angle.sin(),
+                                    long: center.long + radius * angle.cos(),
+                                }
+                            })
+                            .collect();
+                        onto_map(points)
+                    })
+                    // wind needs two points for a direction
+                    .filter(|points| points.len() >= 2)
+                    .map(|points| stroke(ModificationType::Wind, points))
+                    .collect()
+            }
+            Event::Ramp { tool, polygon, .. } => {
+                let region = Region::Polygon(polygon.clone());
+                let bounds = region.bounds();
+                let points = grid(bounds.bottom_right.lat, bounds.top_left.lat)
+                    .flat_map(|lat| {
+                        grid(bounds.top_left.long, bounds.bottom_right.long)
+                            .map(move |long| LatLong { lat, long })
+                    })
+                    .filter(|point| region.contains(*point))
+                    .collect();
+                vec![stroke((*tool).into(), points)]
+            }
+        }
+    }
+}
+
+/// Whether a point is on the map, which also means it's finite.
+fn on_map(point: &LatLong) -> bool {
+    point.lat.abs() <= 90. && point.long.abs() <= 180.
+}
+
+/// Points of a stroke moved onto the map: longitudes wrap around the antimeridian & points past
+/// the poles are dropped, splitting the stroke where they were.
+fn onto_map(points: Vec<LatLong>) -> Vec<Vec<LatLong>> {
+    let mut runs = vec![Vec::new()];
+    for point in points {
+        if point.lat.abs() > 90. {
+            if !runs.last().is_some_and(Vec::is_empty) {
+                runs.push(Vec::new());
+            }
+            continue;
+        }
+        let long = (point.long + 180.).rem_euclid(360.) - 180.;
+        runs.last_mut()
+            .expect("there's always a run")
+            .push(LatLong {
+                lat: point.lat,
+                long,
+            });
+    }
+    runs.retain(|run| !run.is_empty());
+
+    runs
+}
+
+/// Centers of brush-sized steps covering `start..end` degrees.
+fn grid(start: f64, end: f64) -> impl Iterator<Item = f64> {
+    let steps = ((end - start) / BRUSH_DEGREES).ceil().max(1.) as usize;
+    (0..steps).map(move |step| start + (step as f64 + 0.5) * BRUSH_DEGREES)
+}
+
+fn stroke(tpe: ModificationType, points: Vec<LatLong>) -> Packet {
+    Packet::Modification {
+        tpe,
+        points,
+        brush_size_degrees: BRUSH_DEGREES,
+        client_id: SCENARIO_CLIENT_ID,
+    }
+}
+
+/// Queues the scenario's strokes into `sink` as the world in `ticks` advances, until every event
+/// has played or the room stops taking modifications.
+pub async fn run(
+    scenario: Scenario,
+    sink: mpsc::Sender<QueuedModification>,
+    mut ticks: watch::Receiver<u64>,
+) {
+    let start = *ticks.borrow_and_update();
+    let last_tick = scenario.last_tick();
+    let mut next = 0;
+    info!(
+        "Starting scenario of {} events at tick {start}",
+        scenario.events.len()
+    );
+
+    while next <= last_tick {
+        // ticks seen to have passed may be several at once if the world got ahead of us
+        let elapsed = ticks.borrow_and_update().saturating_sub(start);
+        while next <= elapsed.min(last_tick) {
+            for packet in scenario.strokes_at(next) {
+                let modification = QueuedModification {
+                    client_id: SCENARIO_CLIENT_ID,
+                    peer: None,
+                    role: Role::Admin,
+                    packet,
+                };
+                if sink.send(modification).await.is_err() {
+                    warn!("Scenario stopped as its room no longer takes modifications");
+                    return;
+                }
+            }
+            next += 1;
+        }
+
+        if next <= last_tick && ticks.changed().await.is_err() {
+            return;
+        }
+    }
+
+    info!("Scenario finished at tick {}", start + last_tick);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example of the module docs.
+    const EXAMPLE: &str = r#"
+        [[event]]
+        at_tick = 200
+        kind = "disk"
+        tool = "heat"
+        center = { lat = 10.0, long = -40.0 }
+        radius_degrees = 5.0
+
+        [[event]]
+        at_tick = 400
+        kind = "vortex"
+        center = { lat = 30.0, long = 20.0 }
+        radius_degrees = 8.0
+
+        [[event]]
+        at_tick = 500
+        kind = "ramp"
+        tool = "humidify"
+        polygon = [{ lat = 0.0, long = 0.0 }, { lat = 10.0, long = 10.0 }, { lat = 0.0, long = 20.0 }]
+        duration_ticks = 50
+    "#;
+
+    fn example() -> Scenario {
+        toml::from_str(EXAMPLE).unwrap()
+    }
+
+    #[test]
+    fn parses_documented_example() {
+        let scenario = example();
+
+        assert!(matches!(
+            scenario.events[..],
+            [
+                Event::Disk {
+                    at_tick: 200,
+                    tool: Tool::Heat,
+                    ..
+                },
+                Event::Vortex {
+                    at_tick: 400,
+                    clockwise: false,
+                    ..
+                },
+                Event::Ramp {
+                    at_tick: 500,
+                    tool: Tool::Humidify,
+                    duration_ticks: 50,
+                    ..
+                },
+            ]
+        ));
+        for event in &scenario.events {
+            event.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn events_paint_on_their_ticks() {
+        let scenario = example();
+        let [disk, vortex, ramp] = &scenario.events[..] else {
+            panic!("expected 3 events");
+        };
+
+        assert!(!disk.paints_at(199) && disk.paints_at(200) && !disk.paints_at(201));
+        assert!(vortex.paints_at(400) && !vortex.paints_at(401));
+        assert!(!ramp.paints_at(499) && ramp.paints_at(500) && ramp.paints_at(549));
+        assert!(!ramp.paints_at(550));
+
+        assert_eq!(scenario.last_tick(), 549);
+        assert!(scenario.strokes_at(300).is_empty());
+        assert_eq!(scenario.strokes_at(200).len(), 1);
+    }
+
+    #[test]
+    fn empty_scenarios_end_at_once() {
+        assert_eq!(Scenario { events: Vec::new() }.last_tick(), 0);
+    }
+
+    #[test]
+    fn invalid_events_are_refused() {
+        let center = LatLong { lat: 0., long: 0. };
+        let disk = |radius_degrees| Event::Disk {
+            at_tick: 0,
+            tool: Tool::Cool,
+            center,
+            radius_degrees,
+        };
+        assert!(disk(0.).validate().is_err());
+        assert!(disk(-1.).validate().is_err());
+        assert!(disk(f64::NAN).validate().is_err());
+        assert!(disk(f64::INFINITY).validate().is_err());
+        assert!(disk(1000.).validate().is_err());
+        assert!(disk(MAX_RADIUS_DEGREES).validate().is_ok());
+
+        let vortex_at = |lat, long| Event::Vortex {
+            at_tick: 0,
+            center: LatLong { lat, long },
+            radius_degrees: 5.,
+            clockwise: false,
+        };
+        assert!(vortex_at(f64::NAN, 0.).validate().is_err());
+        assert!(vortex_at(0., f64::INFINITY).validate().is_err());
+        assert!(vortex_at(95., 0.).validate().is_err());
+        assert!(vortex_at(0., -181.).validate().is_err());
+
+        let ramp = |corners, duration_ticks| Event::Ramp {
+            at_tick: 0,
+            tool: Tool::Cool,
+            polygon: vec![center; corners],
+            duration_ticks,
+        };
+        assert!(ramp(2, 1).validate().is_err());
+        assert!(ramp(3, 0).validate().is_err());
+        assert!(ramp(3, 1).validate().is_ok());
+
+        let ramp_around = |corner| Event::Ramp {
+            at_tick: 0,
+            tool: Tool::Cool,
+            polygon: vec![center, LatLong { lat: 10., long: 0. }, corner],
+            duration_ticks: 1,
+        };
+        assert!(ramp_around(LatLong {
+            lat: f64::NAN,
+            long: 5.
+        })
+        .validate()
+        .is_err());
+        assert!(ramp_around(LatLong { lat: 5., long: 1e6 })
+            .validate()
+            .is_err());
+        assert!(ramp_around(LatLong { lat: 5., long: 10. })
+            .validate()
+            .is_ok());
+    }
+
+    /// Points of every stroke of an event.
+    fn points(event: &Event) -> Vec<LatLong> {
+        event
+            .strokes()
+            .into_iter()
+            .flat_map(|packet| match packet {
+                Packet::Modification { points, .. } => points,
+                _ => panic!("scenarios only paint"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn strokes_near_the_edges_stay_on_the_map() {
+        let disk = Event::Disk {
+            at_tick: 0,
+            tool: Tool::Heat,
+            center: LatLong {
+                lat: 0.,
+                long: 178.,
+            },
+            radius_degrees: 5.,
+        };
+        let painted = points(&disk);
+        assert!(painted.iter().all(on_map));
+        // the part past the antimeridian wraps around to the west
+        assert!(painted.iter().any(|point| point.long < -170.));
+
+        let vortex = Event::Vortex {
+            at_tick: 0,
+            center: LatLong { lat: 88., long: 0. },
+            radius_degrees: 10.,
+            clockwise: false,
+        };
+        let strokes = vortex.strokes();
+        let painted = points(&vortex);
+        assert!(!painted.is_empty() && painted.iter().all(on_map));
+        // rings are split where they'd cross the pole rather than joined across it
+        assert!(strokes.len() > 10);
+        for packet in strokes {
+            let Packet::Modification { points, .. } = packet else {
+                panic!("scenarios only paint");
+            };
+            assert!(points.len() >= 2);
+        }
+    }
+
+    #[test]
+    fn unknown_fields_are_refused() {
+        let typo = "[[event]]\nat_tick = 1\nkind = \"disk\"\ntool = \"heat\"\n\
+                    center = { lat = 0.0, long = 0.0 }\nradius = 5.0\n";
+        assert!(toml::from_str::<Scenario>(typo).is_err());
+    }
+}
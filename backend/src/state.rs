@@ -1,190 +1,555 @@
 use anyhow::{anyhow, Result};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{io::Cursor, path::Path};
 
-use crate::message::{LatLong, ModificationType, Rect};
+use crate::message::{
+    Compression, HistoryPoint, LatLong, Layer, Rect, Region, Resolution, Role, SnapshotData,
+    SnapshotEncoding, StatsField,
+};
 
+mod generation;
+mod history;
+mod painting;
 mod processing;
+mod snapshot;
+mod stats;
+mod strokes;
+pub mod units;
+mod zones;
+
+pub use history::now_ms;
+pub use processing::AdapterDiagnostics;
+pub use snapshot::{SnapshotMetadata, WorldSnapshot};
+pub use stats::{parse_field, parse_polygon, validate_region, RegionStats};
+pub use zones::{Rejection, Zone, ZonePolicy};
 
 /// Width of the map. Cell every 6 minutes, 180 degrees of latitude.
 /// Rounded to nearest multiple of 256 for alignment reasons.
-const MAP_WIDTH: usize = 3584;
+pub const MAP_WIDTH: usize = 3584;
 
 /// Height of the map. Cell every 6 minutes, 180 degres of latitude.
-const MAP_HEIGHT: usize = 180 * 10;
+pub const MAP_HEIGHT: usize = 180 * 10;
 
 /// Bytes per pixel. 1 byte per channel * 4 channels.
-const BYTES_PER_PIXEL: usize = 4;
+pub const BYTES_PER_PIXEL: usize = 4;
 
 /// Size of the raw state data, in bytes.
-const STATE_BYTES: usize = MAP_WIDTH * MAP_HEIGHT * BYTES_PER_PIXEL;
+pub const STATE_BYTES: usize = MAP_WIDTH * MAP_HEIGHT * BYTES_PER_PIXEL;
+
+/// Pixels in a snapshot when the client doesn't ask for a resolution, & the size of snapshots too
+/// thin to scale, so [`Settings::max_snapshot_pixels`] can't be any less.
+pub const DEFAULT_SNAPSHOT_PIXELS: u32 = 40 * 22;
+
+/// Default for [`Settings::max_snapshot_pixels`].
+pub const MAX_SNAPSHOT_PIXELS: u32 = 256 * 256;
+
+/// Default for [`Settings::history_depth`].
+pub const HISTORY_DEPTH: usize = 180;
+
+/// Default for [`Settings::history_downsample`].
+pub const HISTORY_DOWNSAMPLE: u32 = 8;
+
+/// Default for [`Settings::history_interval`].
+pub const HISTORY_INTERVAL: u64 = 2;
+
+/// Default for [`Settings::undo_depth`].
+pub const UNDO_DEPTH: usize = 20;
+
+/// Default for [`Settings::undo_max_bytes`].
+pub const UNDO_MAX_BYTES: usize = 8 << 20;
 
 /// Change in a region when a user draws.
 /// TODO: change back
 const DRAW_DELTA: i8 = 127;
 
+/// Tunables of the state that can be set from the config.
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    /// Most pixels the server is willing to render into a single snapshot.
+    pub max_snapshot_pixels: u32,
+
+    /// Number of frames kept in the history.
+    pub history_depth: usize,
+
+    /// Factor the map is downsampled by in each dimension for history frames.
+    pub history_downsample: u32,
+
+    /// A history frame is recorded every this many ticks.
+    pub history_interval: u64,
+
+    /// Number of strokes each client can undo.
+    pub undo_depth: usize,
+
+    /// Most bytes of strokes kept per client for undo & redo.
+    pub undo_max_bytes: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            max_snapshot_pixels: MAX_SNAPSHOT_PIXELS,
+            history_depth: HISTORY_DEPTH,
+            history_downsample: HISTORY_DOWNSAMPLE,
+            history_interval: HISTORY_INTERVAL,
+            undo_depth: UNDO_DEPTH,
+            undo_max_bytes: UNDO_MAX_BYTES,
+        }
+    }
+}
+
+/// How long each phase of a tick took.
+pub struct TickTimings {
+    /// Writing the state into the source texture.
+    pub upload: Duration,
+
+    /// Running the shader until the GPU is done.
+    pub shader: Duration,
+
+    /// Copying the result back out of the GPU.
+    pub readback: Duration,
+}
+
 pub struct State {
     /// `wgpu` backend stuff.
     graphics: processing::GraphicsStuff,
 
     /// Buffer with raw RGBA8 data.
-    buffer: Vec<u8>,
-}
+    ///
+    /// Shared with [`SharedFrame`]s still being rendered, and copied on write if there are any.
+    buffer: Arc<Vec<u8>>,
 
-impl State {
-    #[allow(unused)]
-    pub async fn init() -> Result<State> {
-        let graphics = processing::GraphicsStuff::init().await?;
+    /// Number of times the state has been ticked.
+    tick: u64,
 
-        let buffer = Vec::with_capacity(STATE_BYTES);
+    /// Downsampled frames of past states.
+    history: history::History,
 
-        // TODO: perlin noise?
+    /// Recent strokes of each client, for undo/redo.
+    strokes: strokes::StrokeHistory,
 
-        Ok(State { graphics, buffer })
-    }
+    /// Areas where painting is restricted.
+    zones: Vec<Zone>,
 
-    pub async fn load_from_image<P: AsRef<Path>>(path: P) -> Result<State> {
-        let graphics = processing::GraphicsStuff::init().await?;
+    settings: Settings,
+}
 
-        let image_data = image::ImageReader::open(path.as_ref())?.decode()?;
+impl State {
+    fn new(
+        graphics: processing::GraphicsStuff,
+        buffer: Vec<u8>,
+        tick: u64,
+        zones: Vec<Zone>,
+        settings: Settings,
+    ) -> State {
+        State {
+            graphics,
+            buffer: Arc::new(buffer),
+            tick,
+            history: history::History::new(
+                settings.history_depth,
+                settings.history_downsample,
+                settings.history_interval,
+            ),
+            strokes: strokes::StrokeHistory::new(settings.undo_depth, settings.undo_max_bytes),
+            zones,
+            settings,
+        }
+    }
 
-        match image_data {
-            image::DynamicImage::ImageRgba8(data) => {
-                let buffer = data.into_raw();
+    /// Creates a freshly generated world, see [`generate_world`].
+    #[allow(unused)]
+    pub async fn init(seed: u32, settings: Settings) -> Result<State> {
+        State::from_snapshot(generate_world(seed), settings).await
+    }
 
-                // write buffer to underlying texture
-                graphics.set_source_texture_contents(&buffer).await?;
+    #[allow(unused)]
+    pub async fn load_from_image<P: AsRef<Path>>(path: P, settings: Settings) -> Result<State> {
+        State::from_snapshot(WorldSnapshot::load(path)?, settings).await
+    }
 
-                Ok(State { graphics, buffer })
-            }
-            _ => anyhow::bail!("State images must be 8-bit RGBA"),
-        }
+    /// Creates a state from a saved world, picking up at the tick it was saved at.
+    pub async fn from_snapshot(snapshot: WorldSnapshot, settings: Settings) -> Result<State> {
+        let graphics = processing::GraphicsStuff::init().await?;
+        let buffer = snapshot.data;
+
+        // write buffer to underlying texture
+        graphics.set_source_texture_contents(&buffer).await?;
+
+        Ok(State::new(
+            graphics,
+            buffer,
+            snapshot.metadata.tick,
+            snapshot.metadata.zones,
+            settings,
+        ))
     }
 
     /// Ticks the map state, and updates the internal copy of that state.
-    pub async fn tick_state_by_count(&mut self, count: u32) -> Result<()> {
+    pub async fn tick_state_by_count(&mut self, count: u32) -> Result<TickTimings> {
+        let start = Instant::now();
+
         // ensure texture contents are consistent with internal state
         self.graphics
             .set_source_texture_contents(&self.buffer)
             .await?;
+        let uploaded = Instant::now();
 
         for _ in 0..count {
             self.graphics.apply_shader()?;
         }
+        self.graphics.wait_idle();
+        let shaded = Instant::now();
+
+        self.graphics
+            .get_texture_contents(Arc::make_mut(&mut self.buffer).as_mut_slice())
+            .await?;
+        let read_back = Instant::now();
 
-        self.graphics.get_texture_contents(&mut self.buffer).await
+        self.tick += u64::from(count);
+
+        Ok(TickTimings {
+            upload: uploaded - start,
+            shader: shaded - uploaded,
+            readback: read_back - shaded,
+        })
     }
 
-    /// Saves the current map state as an 8-bit RGBA image.
-    pub fn get_state_clone(&self) -> Vec<u8> {
-        self.buffer.clone()
+    /// Number of times the state has been ticked.
+    pub fn tick(&self) -> u64 {
+        self.tick
     }
 
-    pub fn save_raw_to_image<P: AsRef<Path>>(raw_state: Vec<u8>, path: P) -> Result<()> {
-        let image_data = image::ImageBuffer::<image::Rgba<u8>, Vec<u8>>::from_raw(
-            MAP_WIDTH.try_into()?,
-            MAP_HEIGHT.try_into()?,
-            raw_state,
-        )
-        .ok_or_else(|| anyhow!("couldn't convert state to image"))?;
-        image_data.save(path.as_ref()).map_err(Into::into)
+    /// GPU adapter the state is simulated on.
+    pub fn adapter(&self) -> &AdapterDiagnostics {
+        self.graphics.adapter()
     }
 
-    /// Renders the current state to the provided rectangle/view.
-    ///
-    /// Currently just samples the state but eventually will average over regions.
-    pub fn render_cropped_state(&self, section: super::message::Rect) -> Result<(Vec<u8>, Rect)> {
-        let state = self.get_state_clone();
-        let image_data = image::ImageBuffer::<image::Rgba<u8>, Vec<u8>>::from_raw(
-            MAP_WIDTH.try_into()?,
-            MAP_HEIGHT.try_into()?,
-            state,
-        )
-        .ok_or_else(|| anyhow!("couldn't convert state to image"))?;
-        let image = image::DynamicImage::ImageRgba8(image_data);
+    /// Copies the current map state & its metadata, e.g. to save it.
+    pub fn snapshot(&self) -> WorldSnapshot {
+        let mut metadata = snapshot::SnapshotMetadata::current(self.tick);
+        metadata.zones = self.zones.clone();
+
+        WorldSnapshot {
+            metadata,
+            data: self.buffer.to_vec(),
+        }
+    }
 
-        let super::message::Rect {
-            top_left,
-            bottom_right,
-        } = section;
+    /// Read-only copy of the current state to render snapshots from.
+    pub fn frame(&self) -> SharedFrame {
+        SharedFrame {
+            data: self.buffer.clone(),
+            downsample: 1,
+            max_snapshot_pixels: self.settings.max_snapshot_pixels,
+        }
+    }
 
-        // NOTE: top_left/bottom_right will have different components because of how zooming works
+    /// The current state, if it's due to be recorded in the history.
+    ///
+    /// Downsampling it is left to the caller, so it can happen without holding the state; the
+    /// result is added with [`State::record_frame`].
+    pub fn due_frame(&self) -> Option<history::PendingFrame> {
+        self.history.pending(self.tick, &self.buffer)
+    }
 
-        let (x, y) = latlong_to_pixel_coords(top_left);
-        let (br_x, br_y) = latlong_to_pixel_coords(bottom_right);
-        log::debug!("{x}, {y} -> {br_x}, {br_y}");
-        let cropped = image.crop_imm(x, y, br_x - x, br_y - y);
+    pub fn record_frame(&mut self, frame: history::ReadyFrame) {
+        self.history.push(frame);
+    }
 
-        let rect = Rect {
-            top_left: pixel_coords_to_latlong(x, y),
-            bottom_right: pixel_coords_to_latlong(br_x, br_y)
+    /// Read-only copy of the recorded frame closest to `at`, along with where it sits in the
+    /// history, or `None` if nothing's been recorded.
+    pub fn history_frame(&self, at: HistoryPoint) -> Option<(history::FrameInfo, SharedFrame)> {
+        let frame = self.history.find(at)?;
+        let (oldest_tick, newest_tick) = self
+            .history
+            .range()
+            .expect("history with a frame should have a range");
+
+        let info = history::FrameInfo {
+            tick: frame.tick,
+            timestamp_ms: frame.timestamp_ms,
+            oldest_tick,
+            newest_tick,
+        };
+        let shared = SharedFrame {
+            data: frame.data.clone(),
+            downsample: self.history.downsample(),
+            max_snapshot_pixels: self.settings.max_snapshot_pixels,
         };
 
-        let total_pixels = 40 * 22;
-        let c = (rect.top_left.long - rect.bottom_right.long).abs() / (rect.top_left.lat - rect.bottom_right.lat).abs();
-        let mut h = ((total_pixels as f64) / c).sqrt().round() as u32;
-        let mut w = (((total_pixels as f64) / c).sqrt() * c).round() as u32;
-        
-        if h == 0 || w == 0 {
-            h = 40;
-            w = 22;
+        Some((info, shared))
+    }
+
+    /// Applies a modification, undo or redo packet sent by the given client.
+    ///
+    /// Strokes, undos & redos are clipped against protected zones that don't allow them; what was
+    /// left out is returned.
+    pub fn process_modification(
+        &mut self,
+        client_id: u64,
+        role: Role,
+        mod_packet: &crate::message::Packet,
+    ) -> Result<Rejection> {
+        painting::apply(
+            Arc::make_mut(&mut self.buffer).as_mut_slice(),
+            &self.zones,
+            &mut self.strokes,
+            client_id,
+            role,
+            mod_packet,
+        )
+    }
+
+    /// Forgets the undo history of a client that's gone.
+    pub fn forget_client(&mut self, client_id: u64) {
+        self.strokes.forget(client_id);
+    }
+
+    /// Protected zones of the world.
+    pub fn zones(&self) -> &[Zone] {
+        &self.zones
+    }
+
+    /// Adds a zone, replacing any with the same name. Returns whether one was replaced.
+    pub fn set_zone(&mut self, zone: Zone) -> bool {
+        match self.zones.iter_mut().find(|z| z.name == zone.name) {
+            Some(existing) => {
+                *existing = zone;
+                true
+            }
+            None => {
+                self.zones.push(zone);
+                false
+            }
         }
-        let scaled = cropped.resize_exact(w, h, image::imageops::FilterType::Gaussian);
+    }
+
+    /// Removes the zone called `name`, returning whether there was one.
+    pub fn remove_zone(&mut self, name: &str) -> bool {
+        let before = self.zones.len();
+        self.zones.retain(|zone| zone.name != name);
+        self.zones.len() != before
+    }
+
+    /// Replaces the whole world with a snapshot, picking up at its tick & taking its zones.
+    ///
+    /// Recorded history & undo strokes no longer match the world so they're dropped.
+    pub fn replace(&mut self, snapshot: WorldSnapshot) {
+        self.buffer = Arc::new(snapshot.data);
+        self.tick = snapshot.metadata.tick;
+        self.zones = snapshot.metadata.zones;
+        self.history.clear();
+        self.strokes =
+            strokes::StrokeHistory::new(self.settings.undo_depth, self.settings.undo_max_bytes);
+    }
 
-        // scaled.save("debug.png")?;
+    /// Sets `layers` of every cell within `area` to `value(layer, pixel index)`.
+    ///
+    /// Returns the number of cells written.
+    pub fn write_region(
+        &mut self,
+        area: Rect,
+        layers: &[Layer],
+        value: impl Fn(Layer, usize) -> u8,
+    ) -> Result<usize> {
+        let (columns, rows) = area_cells(&area);
+        if columns.is_empty() || rows.is_empty() {
+            anyhow::bail!("area {area:?} is empty or crosses the antimeridian");
+        }
 
-        let mut output_cursor = Cursor::new(Vec::new());
-        scaled.write_to(&mut output_cursor, image::ImageFormat::Png)?;
+        let buffer = Arc::make_mut(&mut self.buffer);
+        for y in rows.start as usize..rows.end as usize {
+            for x in columns.start as usize..columns.end as usize {
+                let pixel = y * MAP_WIDTH + x;
+                for &layer in layers {
+                    buffer[pixel * BYTES_PER_PIXEL + layer_offset(layer)] = value(layer, pixel);
+                }
+            }
+        }
 
-        Ok((output_cursor.into_inner(), rect))
+        Ok(columns.len() * rows.len())
     }
+}
 
-    pub fn process_modification(&mut self, mod_packet: crate::message::Packet) -> Result<()> {
-        match mod_packet {
-            crate::message::Packet::Modification {
-                tpe,
-                points,
-                brush_size_degrees,
-                ..
-            } => {
-                // convert brush size to simulation tiles
-                let brush_width_px = ((brush_size_degrees) / 180. * MAP_HEIGHT as f64) as usize;
-                let half_width = brush_width_px / 2;
+/// Read-only copy of a (possibly downsampled) frame of the state, so snapshots can be rendered
+/// without holding on to the state.
+#[derive(Clone)]
+pub struct SharedFrame {
+    data: Arc<Vec<u8>>,
+    downsample: u32,
+    max_snapshot_pixels: u32,
+}
 
-                let sign = match tpe {
-                    ModificationType::Heat | ModificationType::Humidify => 1,
-                    ModificationType::Cool | ModificationType::Dehumidify => -1,
-                    _ => 0,
-                };
+impl SharedFrame {
+    /// Statistics of `fields` over `region`, which needs a full-resolution frame from
+    /// [`State::frame`].
+    pub fn stats(&self, region: &Region, fields: &[StatsField]) -> Result<RegionStats> {
+        if self.downsample != 1 {
+            return Err(anyhow!("statistics need a full-resolution frame"));
+        }
 
-                let channel: Channel = tpe.into();
+        Ok(stats::compute(&self.data, region, fields))
+    }
 
-                for point in points {
-                    let (center_x, center_y) = latlong_to_pixel_coords(point);
+    /// Renders the frame to the provided rectangle/view, in the given encoding.
+    ///
+    /// Currently just samples the state but eventually will average over regions.
+    pub fn render(
+        &self,
+        section: Rect,
+        resolution: Option<Resolution>,
+        layers: &[Layer],
+        encoding: &SnapshotEncoding,
+    ) -> Result<(SnapshotData, Rect)> {
+        render_snapshot(
+            &self.data,
+            self.downsample,
+            self.max_snapshot_pixels,
+            section,
+            resolution,
+            layers,
+            encoding,
+        )
+    }
+}
 
-                    for i in 0..brush_width_px.pow(2) {
-                        let x_offset = center_x as usize + ((i / brush_width_px) - half_width);
-                        let y_offset = center_y as usize + ((i % brush_width_px) - half_width);
+/// Generates a world at tick 0 from `seed`; the same seed always gives the same world.
+pub fn generate_world(seed: u32) -> WorldSnapshot {
+    let data = generation::generate(seed);
+    debug_assert_eq!(data.len(), STATE_BYTES);
 
-                        // 4 bytes per pixel
-                        let index = y_offset * MAP_WIDTH * BYTES_PER_PIXEL
-                            + x_offset * BYTES_PER_PIXEL
-                            + channel as usize;
+    WorldSnapshot {
+        metadata: snapshot::SnapshotMetadata::current(0),
+        data,
+    }
+}
 
-                        if index < STATE_BYTES {
-                            self.buffer[index] =
-                                self.buffer[index].saturating_add_signed(sign * DRAW_DELTA);
-                        }
-                    }
+/// Crops & scales a (possibly downsampled) RGBA8 copy of the state to the given view.
+fn render_snapshot(
+    buffer: &[u8],
+    downsample: u32,
+    max_pixels: u32,
+    section: Rect,
+    resolution: Option<Resolution>,
+    layers: &[Layer],
+    encoding: &SnapshotEncoding,
+) -> Result<(SnapshotData, Rect)> {
+    let image_data = image::ImageBuffer::<image::Rgba<u8>, &[u8]>::from_raw(
+        MAP_WIDTH as u32 / downsample,
+        MAP_HEIGHT as u32 / downsample,
+        buffer,
+    )
+    .ok_or_else(|| anyhow!("couldn't convert state to image"))?;
+
+    let Rect {
+        top_left,
+        bottom_right,
+    } = section;
+
+    // NOTE: top_left/bottom_right will have different components because of how zooming works
+
+    // snap to the grid of the (downsampled) buffer
+    let (x, y) = latlong_to_pixel_coords(top_left);
+    let (br_x, br_y) = latlong_to_pixel_coords(bottom_right);
+    let (x, y, br_x, br_y) = (
+        x / downsample,
+        y / downsample,
+        br_x / downsample,
+        br_y / downsample,
+    );
+    log::debug!("{x}, {y} -> {br_x}, {br_y}");
+    if br_x <= x || br_y <= y {
+        anyhow::bail!("section {section:?} covers no cells");
+    }
+    let cropped = image::imageops::crop_imm(&image_data, x, y, br_x - x, br_y - y);
+
+    let rect = Rect {
+        top_left: pixel_coords_to_latlong(x * downsample, y * downsample),
+        bottom_right: pixel_coords_to_latlong(br_x * downsample, br_y * downsample),
+    };
+
+    let (w, h) = snapshot_dimensions(&rect, resolution, max_pixels);
+    let mut pixels =
+        image::imageops::resize(&*cropped, w, h, image::imageops::FilterType::Gaussian);
+
+    // pixels.save("debug.png")?;
+
+    let data = match encoding {
+        SnapshotEncoding::Png => {
+            // blank out layers the viewport isn't subscribed to
+            for channel in ALL_CHANNELS {
+                if !layers.iter().any(|layer| Channel::from(*layer) == channel) {
+                    pixels
+                        .pixels_mut()
+                        .for_each(|pixel| pixel.0[channel as usize] = 0);
                 }
+            }
+
+            let mut output_cursor = Cursor::new(Vec::new());
+            pixels.write_to(&mut output_cursor, image::ImageFormat::Png)?;
 
-                Ok(())
+            SnapshotData::Png(crate::message::PNGFile(output_cursor.into_inner()))
+        }
+        SnapshotEncoding::Raw { compression } => {
+            let pixels = pixels.into_raw();
+
+            // split interleaved RGBA into one plane per requested layer
+            let mut planes = Vec::with_capacity(layers.len() * (w * h) as usize);
+            for layer in layers {
+                let channel: Channel = (*layer).into();
+                planes.extend(
+                    pixels
+                        .iter()
+                        .skip(channel as usize)
+                        .step_by(BYTES_PER_PIXEL),
+                );
+            }
+
+            let planes = match compression {
+                Compression::None => planes,
+                Compression::Lz4 => lz4_flex::compress_prepend_size(&planes),
+            };
+
+            SnapshotData::Raw {
+                width: w,
+                height: h,
+                layers: layers.to_vec(),
+                compression: *compression,
+                planes,
             }
-            _ => anyhow::bail!("Non-modification packet received for processing"),
         }
+    };
+
+    Ok((data, rect))
+}
+
+/// Picks the output size of a snapshot of `rect`, keeping it within `max_pixels`.
+fn snapshot_dimensions(rect: &Rect, resolution: Option<Resolution>, max_pixels: u32) -> (u32, u32) {
+    let (w, h) = match resolution {
+        Some(Resolution { width, height }) => (width as f64, height as f64),
+        None => {
+            // fit the default pixel budget to the aspect ratio of the viewport
+            let total_pixels = DEFAULT_SNAPSHOT_PIXELS as f64;
+            let c = (rect.top_left.long - rect.bottom_right.long).abs()
+                / (rect.top_left.lat - rect.bottom_right.lat).abs();
+            ((total_pixels * c).sqrt(), (total_pixels / c).sqrt())
+        }
+    };
+
+    // scale down uniformly if over budget
+    let scale = (max_pixels as f64 / (w * h)).sqrt().min(1.);
+    let (w, h) = ((w * scale).floor() as u32, (h * scale).floor() as u32);
+
+    if w == 0 || h == 0 {
+        (40, 22)
+    } else {
+        (w, h)
     }
 }
 
-fn latlong_to_pixel_coords(latlong: LatLong) -> (u32, u32) {
+pub fn latlong_to_pixel_coords(latlong: LatLong) -> (u32, u32) {
     let x = ((latlong.long + 180.) / 360.) * MAP_WIDTH as f64;
     let y = ((latlong.lat + 90.) / 180.) * MAP_HEIGHT as f64;
 
@@ -194,13 +559,63 @@ fn latlong_to_pixel_coords(latlong: LatLong) -> (u32, u32) {
     )
 }
 
-fn pixel_coords_to_latlong(x: u32, y: u32) -> LatLong {
+/// Map cells `(x_start..x_end, y_start..y_end)` covered by `area`, including cells it only partly
+/// covers. Unlike [`latlong_to_pixel_coords`], the ends are exclusive, so the whole world is the
+/// whole map.
+pub fn area_cells(area: &Rect) -> (Range<u32>, Range<u32>) {
+    let column = |long: f64| (long + 180.) / 360. * MAP_WIDTH as f64;
+    let row = |lat: f64| (90. - lat) / 180. * MAP_HEIGHT as f64;
+
+    let x_start = column(area.top_left.long)
+        .floor()
+        .clamp(0., MAP_WIDTH as f64);
+    let x_end = column(area.bottom_right.long)
+        .ceil()
+        .clamp(0., MAP_WIDTH as f64);
+    let y_start = row(area.top_left.lat).floor().clamp(0., MAP_HEIGHT as f64);
+    let y_end = row(area.bottom_right.lat)
+        .ceil()
+        .clamp(0., MAP_HEIGHT as f64);
+
+    (x_start as u32..x_end as u32, y_start as u32..y_end as u32)
+}
+
+/// Latitude & longitude of the top left corner of a map cell.
+pub fn pixel_coords_to_latlong(x: u32, y: u32) -> LatLong {
     let lat = ((MAP_HEIGHT as u32 - y) as f64 / MAP_HEIGHT as f64) * 180.0 - 90.0;
     let long = (x as f64 / MAP_WIDTH as f64) * 360.0 - 180.0;
 
     LatLong { lat, long }
 }
 
+/// Map cells `(x_start..x_end, y_start..y_end)` the region can cover, to skip testing cells far
+/// from it.
+fn cell_bounds(region: &Region) -> (usize, usize, usize, usize) {
+    let bounds = region.bounds();
+    let (x_start, y_start) = latlong_to_pixel_coords(bounds.top_left);
+    let (x_end, y_end) = latlong_to_pixel_coords(bounds.bottom_right);
+
+    (
+        x_start as usize,
+        (x_end as usize + 1).min(MAP_WIDTH),
+        y_start as usize,
+        (y_end as usize + 1).min(MAP_HEIGHT),
+    )
+}
+
+/// Latitude & longitude of the center of a map cell.
+pub fn cell_center(x: usize, y: usize) -> LatLong {
+    LatLong {
+        lat: 90. - (y as f64 + 0.5) / MAP_HEIGHT as f64 * 180.,
+        long: (x as f64 + 0.5) / MAP_WIDTH as f64 * 360. - 180.,
+    }
+}
+
+/// Byte offset of a layer within each pixel of the raw state.
+pub fn layer_offset(layer: Layer) -> usize {
+    Channel::from(layer) as usize
+}
+
 #[repr(usize)]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 enum Channel {
@@ -210,12 +625,57 @@ enum Channel {
     Haze = 3,
 }
 
-impl From<ModificationType> for Channel {
-    fn from(value: ModificationType) -> Self {
+const ALL_CHANNELS: [Channel; 4] = [
+    Channel::Temperature,
+    Channel::WindX,
+    Channel::WindY,
+    Channel::Haze,
+];
+
+impl From<Layer> for Channel {
+    fn from(value: Layer) -> Self {
         match value {
-            ModificationType::Cool | ModificationType::Heat => Channel::Temperature,
-            ModificationType::Humidify | ModificationType::Dehumidify => Channel::Haze,
-            _ => unreachable!(),
+            Layer::Temperature => Channel::Temperature,
+            Layer::WindX => Channel::WindX,
+            Layer::WindY => Channel::WindY,
+            Layer::Haze => Channel::Haze,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(north: f64, west: f64, south: f64, east: f64) -> Rect {
+        Rect {
+            top_left: LatLong {
+                lat: north,
+                long: west,
+            },
+            bottom_right: LatLong {
+                lat: south,
+                long: east,
+            },
+        }
+    }
+
+    #[test]
+    fn whole_world_covers_whole_map() {
+        let (columns, rows) = area_cells(&rect(90., -180., -90., 180.));
+        assert_eq!(columns, 0..MAP_WIDTH as u32);
+        assert_eq!(rows, 0..MAP_HEIGHT as u32);
+    }
+
+    #[test]
+    fn partly_covered_cells_count() {
+        // a tenth of a degree is one row, and a bit less than one column
+        let (columns, rows) = area_cells(&rect(0.05, 0.05, -0.05, 0.15));
+        assert_eq!(columns, 1792..1794);
+        assert_eq!(rows, 899..901);
+
+        let (columns, rows) = area_cells(&rect(0.1, -0.1, 0., 0.));
+        assert_eq!(columns.len(), 1);
+        assert_eq!(rows, 899..900);
+    }
+}
@@ -0,0 +1,186 @@
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
+use std::f64::consts::PI;
+
+use super::{Channel, BYTES_PER_PIXEL, MAP_HEIGHT, MAP_WIDTH};
+
+/// Fields are generated on a grid this many times coarser than the map, then scaled up.
+/// Features are continent-sized anyway, and it keeps generation quick.
+const COARSE_FACTOR: usize = 4;
+
+const COARSE_WIDTH: usize = MAP_WIDTH / COARSE_FACTOR;
+const COARSE_HEIGHT: usize = MAP_HEIGHT / COARSE_FACTOR;
+
+/// Raw temperature at the poles and at the equator, before noise.
+const POLE_TEMPERATURE: f64 = 40.;
+const EQUATOR_TEMPERATURE: f64 = 210.;
+
+/// Largest raw deviation noise adds to the temperature.
+const TEMPERATURE_NOISE: f64 = 45.;
+
+/// Noise level above which haze starts forming, so it clusters instead of covering everything.
+const HAZE_THRESHOLD: f64 = 0.1;
+
+/// Raw haze in the densest clusters.
+const MAX_HAZE: f64 = 220.;
+
+/// Largest raw deviation of a wind component from its zero value.
+const MAX_WIND: f64 = 90.;
+
+/// Raw value of a calm wind component.
+const WIND_ZERO: f64 = 127.;
+
+/// Generates a fresh world from `seed`. The same seed always gives the same world.
+///
+/// - temperature falls off from the equator towards the poles, with fractal noise on top
+/// - haze forms clusters wherever its own noise field is high
+/// - wind is the curl of a noise stream function, so it's divergence-free and has no net drift
+pub fn generate(seed: u32) -> Vec<u8> {
+    let temperature_noise = fbm(seed, 2.5, 6);
+    let haze_noise = fbm(seed.wrapping_add(1), 3., 5);
+    let stream_noise = fbm(seed.wrapping_add(2), 1.5, 4);
+
+    let mut temperature = vec![0.; COARSE_WIDTH * COARSE_HEIGHT];
+    let mut haze = vec![0.; COARSE_WIDTH * COARSE_HEIGHT];
+    let mut stream = vec![0.; COARSE_WIDTH * COARSE_HEIGHT];
+
+    for y in 0..COARSE_HEIGHT {
+        let lat = (0.5 - (y as f64 + 0.5) / COARSE_HEIGHT as f64) * PI;
+
+        for x in 0..COARSE_WIDTH {
+            let long = ((x as f64 + 0.5) / COARSE_WIDTH as f64) * 2. * PI - PI;
+
+            // sample noise on the sphere so there's no seam at the antimeridian or pinching at the poles
+            let point = [lat.cos() * long.cos(), lat.cos() * long.sin(), lat.sin()];
+            let i = y * COARSE_WIDTH + x;
+
+            temperature[i] = POLE_TEMPERATURE
+                + (EQUATOR_TEMPERATURE - POLE_TEMPERATURE) * lat.cos()
+                + TEMPERATURE_NOISE * temperature_noise.get(point);
+            haze[i] = ((haze_noise.get(point) - HAZE_THRESHOLD) * 2.).clamp(0., 1.) * MAX_HAZE;
+            stream[i] = stream_noise.get(point);
+        }
+    }
+
+    let (wind_x, wind_y) = curl(&stream);
+
+    let mut coarse = vec![0; COARSE_WIDTH * COARSE_HEIGHT * BYTES_PER_PIXEL];
+    for (i, pixel) in coarse.chunks_exact_mut(BYTES_PER_PIXEL).enumerate() {
+        pixel[Channel::Temperature as usize] = to_raw(temperature[i]);
+        pixel[Channel::WindX as usize] = to_raw(WIND_ZERO + wind_x[i]);
+        pixel[Channel::WindY as usize] = to_raw(WIND_ZERO + wind_y[i]);
+        pixel[Channel::Haze as usize] = to_raw(haze[i]);
+    }
+
+    let coarse = image::ImageBuffer::<image::Rgba<u8>, _>::from_raw(
+        COARSE_WIDTH as u32,
+        COARSE_HEIGHT as u32,
+        coarse,
+    )
+    .expect("coarse buffer should match its dimensions");
+
+    image::imageops::resize(
+        &coarse,
+        MAP_WIDTH as u32,
+        MAP_HEIGHT as u32,
+        image::imageops::FilterType::Triangle,
+    )
+    .into_raw()
+}
+
+fn fbm(seed: u32, frequency: f64, octaves: usize) -> Fbm<Perlin> {
+    Fbm::<Perlin>::new(seed)
+        .set_frequency(frequency)
+        .set_octaves(octaves)
+}
+
+/// Wind components `(-dψ/dy, dψ/dx)` of a stream function `ψ`, scaled to [`MAX_WIND`].
+///
+/// Central differences keep the discrete divergence at zero; the mean flow is removed so the
+/// field doesn't push everything in one direction.
+fn curl(stream: &[f64]) -> (Vec<f64>, Vec<f64>) {
+    let at = |x: usize, y: usize| stream[y * COARSE_WIDTH + x];
+
+    let mut wind_x = vec![0.; stream.len()];
+    let mut wind_y = vec![0.; stream.len()];
+    for y in 0..COARSE_HEIGHT {
+        let (up, down) = (y.saturating_sub(1), (y + 1).min(COARSE_HEIGHT - 1));
+
+        for x in 0..COARSE_WIDTH {
+            // longitude wraps around
+            let (left, right) = (
+                (x + COARSE_WIDTH - 1) % COARSE_WIDTH,
+                (x + 1) % COARSE_WIDTH,
+            );
+
+            let i = y * COARSE_WIDTH + x;
+            wind_x[i] = -(at(x, down) - at(x, up)) / 2.;
+            wind_y[i] = (at(right, y) - at(left, y)) / 2.;
+        }
+    }
+
+    for component in [&mut wind_x, &mut wind_y] {
+        let mean = component.iter().sum::<f64>() / component.len() as f64;
+        component.iter_mut().for_each(|v| *v -= mean);
+    }
+
+    let peak = wind_x
+        .iter()
+        .chain(&wind_y)
+        .fold(0., |peak: f64, v| peak.max(v.abs()));
+    if peak > 0. {
+        let scale = MAX_WIND / peak;
+        for v in wind_x.iter_mut().chain(wind_y.iter_mut()) {
+            *v *= scale;
+        }
+    }
+
+    (wind_x, wind_y)
+}
+
+fn to_raw(value: f64) -> u8 {
+    value.round().clamp(0., u8::MAX as f64) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::STATE_BYTES;
+
+    /// Mean raw value of a channel over the whole map.
+    fn mean(world: &[u8], channel: Channel) -> f64 {
+        let sum: f64 = world
+            .chunks_exact(BYTES_PER_PIXEL)
+            .map(|pixel| pixel[channel as usize] as f64)
+            .sum();
+        sum / (MAP_WIDTH * MAP_HEIGHT) as f64
+    }
+
+    #[test]
+    fn worlds_are_reproducible_from_their_seed() {
+        let world = generate(42);
+
+        assert_eq!(world.len(), STATE_BYTES);
+        assert!(world == generate(42));
+        assert!(world != generate(43));
+    }
+
+    #[test]
+    fn wind_is_calm_on_average_and_equator_warmer_than_poles() {
+        let world = generate(7);
+
+        for channel in [Channel::WindX, Channel::WindY] {
+            let drift = mean(&world, channel) - WIND_ZERO;
+            assert!(drift.abs() < 2., "{channel:?} drifts by {drift}");
+        }
+
+        let temperature = |y: usize| {
+            world[(y * MAP_WIDTH) * BYTES_PER_PIXEL..]
+                .chunks_exact(BYTES_PER_PIXEL)
+                .take(MAP_WIDTH)
+                .map(|pixel| pixel[Channel::Temperature as usize] as f64)
+                .sum::<f64>()
+        };
+        assert!(temperature(MAP_HEIGHT / 2) > temperature(0));
+        assert!(temperature(MAP_HEIGHT / 2) > temperature(MAP_HEIGHT - 1));
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::message::HistoryPoint;
+
+use super::{MAP_HEIGHT, MAP_WIDTH};
+
+/// A downsampled copy of the state at some point in the past.
+pub struct Frame {
+    /// Tick the state was at when this frame was taken.
+    pub tick: u64,
+
+    /// Milliseconds since the Unix epoch when this frame was taken.
+    pub timestamp_ms: u64,
+
+    /// Raw RGBA8 data, `MAP_WIDTH / downsample` by `MAP_HEIGHT / downsample`.
+    pub data: Arc<Vec<u8>>,
+}
+
+/// Where a rendered history frame sits in the history.
+pub struct FrameInfo {
+    pub tick: u64,
+    pub timestamp_ms: u64,
+    pub oldest_tick: u64,
+    pub newest_tick: u64,
+}
+
+/// Full-resolution state due to be recorded, taken under the state lock so it can be downsampled
+/// after the lock is released.
+pub struct PendingFrame {
+    epoch: u64,
+    tick: u64,
+    timestamp_ms: u64,
+    state: Arc<Vec<u8>>,
+    downsample: u32,
+}
+
+/// A downsampled frame ready to be added to the history it was taken from.
+pub struct ReadyFrame {
+    epoch: u64,
+    frame: Frame,
+}
+
+/// Ring buffer of past frames, oldest first.
+pub struct History {
+    frames: VecDeque<Frame>,
+
+    /// Bumped whenever the history is cleared, so frames taken before then are dropped.
+    epoch: u64,
+
+    /// Most frames kept before the oldest ones get dropped.
+    depth: usize,
+
+    /// Factor each dimension of the map is divided by in stored frames.
+    downsample: u32,
+
+    /// Only every `interval`th tick gets recorded.
+    interval: u64,
+}
+
+impl History {
+    pub fn new(depth: usize, downsample: u32, interval: u64) -> History {
+        History {
+            frames: VecDeque::with_capacity(depth),
+            epoch: 0,
+            depth,
+            downsample,
+            interval: interval.max(1),
+        }
+    }
+
+    pub fn downsample(&self) -> u32 {
+        self.downsample
+    }
+
+    /// The given full-resolution state, if `tick` is due for a frame.
+    pub fn pending(&self, tick: u64, state: &Arc<Vec<u8>>) -> Option<PendingFrame> {
+        if self.depth == 0 || !tick.is_multiple_of(self.interval) {
+            return None;
+        }
+
+        Some(PendingFrame {
+            epoch: self.epoch,
+            tick,
+            timestamp_ms: now_ms(),
+            state: state.clone(),
+            downsample: self.downsample,
+        })
+    }
+
+    /// Adds a frame, unless the history was cleared since it was taken.
+    pub fn push(&mut self, ready: ReadyFrame) {
+        if ready.epoch != self.epoch {
+            return;
+        }
+
+        if self.frames.len() == self.depth {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(ready.frame);
+    }
+
+    /// Drops every frame, along with those still being downsampled.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.epoch += 1;
+    }
+
+    /// Finds the latest frame taken at or before `at`, or the oldest one if `at` predates them all,
+    /// so scrubbing further back than the history goes shows as far back as it does.
+    pub fn find(&self, at: HistoryPoint) -> Option<&Frame> {
+        // frames are recorded in order, so both keys are sorted
+        let after = self.frames.partition_point(|frame| match at {
+            HistoryPoint::Tick(tick) => frame.tick <= tick,
+            HistoryPoint::Timestamp(timestamp_ms) => frame.timestamp_ms <= timestamp_ms,
+        });
+
+        self.frames.get(after.saturating_sub(1))
+    }
+
+    /// Ticks of the oldest and newest recorded frames.
+    pub fn range(&self) -> Option<(u64, u64)> {
+        Some((self.frames.front()?.tick, self.frames.back()?.tick))
+    }
+}
+
+impl PendingFrame {
+    /// Downsamples the frame, which takes a while for a full map.
+    pub fn downsample(self) -> ReadyFrame {
+        let full = image::ImageBuffer::<image::Rgba<u8>, &[u8]>::from_raw(
+            MAP_WIDTH as u32,
+            MAP_HEIGHT as u32,
+            self.state.as_slice(),
+        )
+        .expect("state buffer should match map dimensions");
+        let data = image::imageops::resize(
+            &full,
+            MAP_WIDTH as u32 / self.downsample,
+            MAP_HEIGHT as u32 / self.downsample,
+            image::imageops::FilterType::Triangle,
+        )
+        .into_raw();
+
+        ReadyFrame {
+            epoch: self.epoch,
+            frame: Frame {
+                tick: self.tick,
+                timestamp_ms: self.timestamp_ms,
+                data: Arc::new(data),
+            },
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{BYTES_PER_PIXEL, STATE_BYTES};
+
+    /// A frame of `history`'s current epoch taken at `tick`, `tick` seconds after the epoch.
+    fn ready(history: &History, tick: u64) -> ReadyFrame {
+        ReadyFrame {
+            epoch: history.epoch,
+            frame: Frame {
+                tick,
+                timestamp_ms: tick * 1000,
+                data: Arc::new(Vec::new()),
+            },
+        }
+    }
+
+    #[test]
+    fn oldest_frames_are_evicted() {
+        let mut history = History::new(3, 8, 1);
+        for tick in 0..5 {
+            history.push(ready(&history, tick));
+        }
+
+        assert_eq!(history.range(), Some((2, 4)));
+        assert_eq!(history.find(HistoryPoint::Tick(0)).unwrap().tick, 2);
+        assert_eq!(history.find(HistoryPoint::Tick(3)).unwrap().tick, 3);
+        assert_eq!(history.find(HistoryPoint::Timestamp(3500)).unwrap().tick, 3);
+        assert_eq!(history.find(HistoryPoint::Tick(100)).unwrap().tick, 4);
+    }
+
+    #[test]
+    fn points_before_every_frame_get_the_oldest() {
+        let mut history = History::new(3, 8, 1);
+        assert!(history.find(HistoryPoint::Tick(5)).is_none());
+        for tick in 5..8 {
+            history.push(ready(&history, tick));
+        }
+
+        assert_eq!(history.find(HistoryPoint::Tick(4)).unwrap().tick, 5);
+        assert_eq!(history.find(HistoryPoint::Timestamp(0)).unwrap().tick, 5);
+        assert_eq!(history.find(HistoryPoint::Timestamp(4999)).unwrap().tick, 5);
+        assert_eq!(history.find(HistoryPoint::Timestamp(6999)).unwrap().tick, 6);
+    }
+
+    #[test]
+    fn only_ticks_on_the_interval_are_recorded() {
+        let state = Arc::new(Vec::new());
+        let history = History::new(3, 8, 4);
+
+        assert!(history.pending(8, &state).is_some());
+        assert!(history.pending(9, &state).is_none());
+        assert!(History::new(0, 8, 1).pending(8, &state).is_none());
+    }
+
+    #[test]
+    fn frames_taken_before_a_clear_are_dropped() {
+        let mut history = History::new(3, 8, 1);
+        history.push(ready(&history, 1));
+        let stale = ready(&history, 2);
+
+        history.clear();
+        assert_eq!(history.range(), None);
+        history.push(stale);
+        assert_eq!(history.range(), None);
+
+        history.push(ready(&history, 3));
+        assert_eq!(history.range(), Some((3, 3)));
+    }
+
+    #[test]
+    fn pending_frames_keep_their_epoch_through_downsampling() {
+        let state = Arc::new(vec![0; STATE_BYTES]);
+        let mut history = History::new(3, 8, 1);
+
+        let taken = history.pending(1, &state).unwrap().downsample();
+        assert_eq!(
+            taken.frame.data.len(),
+            (MAP_WIDTH / 8) * (MAP_HEIGHT / 8) * BYTES_PER_PIXEL
+        );
+        let downsampling = history.pending(2, &state).unwrap();
+        history.clear();
+
+        history.push(taken);
+        history.push(downsampling.downsample());
+        assert_eq!(history.range(), None);
+    }
+}
@@ -0,0 +1,413 @@
+//! Brush strokes painted onto the raw state, and undoing & redoing them, clipped against
+//! protected zones.
+//!
+//! Only the buffer is touched, not the GPU.
+
+use anyhow::Result;
+
+use crate::message::{LatLong, ModificationType, Packet, Role};
+
+use super::strokes::{Stroke, StrokeHistory};
+use super::zones::{Rejection, Zone};
+use super::{
+    cell_bounds, cell_center, latlong_to_pixel_coords, Channel, BYTES_PER_PIXEL, DRAW_DELTA,
+    MAP_HEIGHT, MAP_WIDTH,
+};
+
+/// Map cells `(x_start..x_end, y_start..y_end)` a zone can cover.
+type Cells = (usize, usize, usize, usize);
+
+/// Zones that don't allow a tool to a role, along with the cells they can cover.
+struct Blocking<'a>(Vec<(&'a Zone, Cells)>);
+
+impl<'a> Blocking<'a> {
+    fn new(zones: &'a [Zone], tool: ModificationType, role: Role) -> Blocking<'a> {
+        Blocking(
+            zones
+                .iter()
+                .filter(|zone| !zone.allows(tool, role))
+                .map(|zone| (zone, cell_bounds(&zone.region)))
+                .collect(),
+        )
+    }
+
+    /// The zone keeping the cell at `(x, y)` from being painted, if any.
+    fn at(&self, x: usize, y: usize) -> Option<&'a Zone> {
+        self.0
+            .iter()
+            .find(|(zone, (x0, x1, y0, y1))| {
+                (*x0..*x1).contains(&x)
+                    && (*y0..*y1).contains(&y)
+                    && zone.region.contains(cell_center(x, y))
+            })
+            .map(|(zone, _)| *zone)
+    }
+}
+
+/// Applies a modification, undo or redo packet sent by the given client to `buffer`.
+///
+/// What protected zones don't allow the client is left out & returned.
+pub(super) fn apply(
+    buffer: &mut [u8],
+    zones: &[Zone],
+    strokes: &mut StrokeHistory,
+    client_id: u64,
+    role: Role,
+    packet: &Packet,
+) -> Result<Rejection> {
+    match packet {
+        Packet::Modification {
+            tpe,
+            points,
+            brush_size_degrees,
+            ..
+        } => Ok(paint(
+            buffer,
+            zones,
+            strokes,
+            client_id,
+            role,
+            *tpe,
+            points,
+            *brush_size_degrees,
+        )),
+        Packet::Undo { .. } => {
+            let mut rejection = Rejection::default();
+            if !strokes.undo(client_id, buffer, clip(zones, role, &mut rejection)) {
+                anyhow::bail!("client {client_id} has nothing to undo");
+            }
+            Ok(rejection)
+        }
+        Packet::Redo { .. } => {
+            let mut rejection = Rejection::default();
+            if !strokes.redo(client_id, buffer, clip(zones, role, &mut rejection)) {
+                anyhow::bail!("client {client_id} has nothing to redo");
+            }
+            Ok(rejection)
+        }
+        _ => anyhow::bail!("Non-modification packet received for processing"),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn paint(
+    buffer: &mut [u8],
+    zones: &[Zone],
+    strokes: &mut StrokeHistory,
+    client_id: u64,
+    role: Role,
+    tpe: ModificationType,
+    points: &[LatLong],
+    brush_size_degrees: f64,
+) -> Rejection {
+    // convert brush size to simulation tiles
+    let brush_width_px = (brush_size_degrees / 180. * MAP_HEIGHT as f64) as usize;
+    let half_width = (brush_width_px / 2) as isize;
+
+    let mut stroke = Stroke::new(tpe);
+    let blocking = Blocking::new(zones, tpe, role);
+    let mut rejection = Rejection::default();
+
+    for (point_index, point) in points.iter().enumerate() {
+        let (center_x, center_y) = latlong_to_pixel_coords(*point);
+        let deltas = brush_deltas(tpe, points, point_index);
+
+        for i in 0..brush_width_px.pow(2) {
+            let x_offset = center_x as isize + (i / brush_width_px) as isize - half_width;
+            let y_offset = center_y as isize + (i % brush_width_px) as isize - half_width;
+
+            if !(0..MAP_WIDTH as isize).contains(&x_offset)
+                || !(0..MAP_HEIGHT as isize).contains(&y_offset)
+            {
+                continue;
+            }
+
+            let (x, y) = (x_offset as usize, y_offset as usize);
+            if let Some(zone) = blocking.at(x, y) {
+                if rejection.points.last() != Some(&point_index) {
+                    rejection.points.push(point_index);
+                }
+                if !rejection.zones.contains(&zone.name) {
+                    rejection.zones.push(zone.name.clone());
+                }
+                continue;
+            }
+
+            for (channel, delta) in &deltas {
+                // 4 bytes per pixel
+                let index =
+                    y * MAP_WIDTH * BYTES_PER_PIXEL + x * BYTES_PER_PIXEL + *channel as usize;
+
+                let before = buffer[index];
+                buffer[index] = before.saturating_add_signed(*delta);
+                stroke.add(index, buffer[index] as i16 - before as i16);
+            }
+        }
+    }
+
+    strokes.record(client_id, stroke);
+
+    rejection
+}
+
+/// Whether a stroke painted with a tool may change a byte of the buffer, noting the zones that
+/// keep it from doing so in `rejection`.
+///
+/// Zones may have been created since the stroke was painted, so undo & redo are clipped too.
+fn clip<'a>(
+    zones: &'a [Zone],
+    role: Role,
+    rejection: &'a mut Rejection,
+) -> impl FnMut(ModificationType, usize) -> bool + 'a {
+    // a stroke has a single tool, so which zones block it only needs working out once
+    let mut blocking = None;
+
+    move |tool, index| {
+        let blocking = blocking.get_or_insert_with(|| Blocking::new(zones, tool, role));
+        let pixel = index / BYTES_PER_PIXEL;
+        match blocking.at(pixel % MAP_WIDTH, pixel / MAP_WIDTH) {
+            Some(zone) => {
+                if !rejection.zones.contains(&zone.name) {
+                    rejection.zones.push(zone.name.clone());
+                }
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+/// How much the brush of a stroke's `index`th point changes each channel.
+///
+/// Wind blows along the stroke, so it needs the neighbouring points for a direction; a lone point
+/// has none and paints no wind.
+fn brush_deltas(tpe: ModificationType, points: &[LatLong], index: usize) -> Vec<(Channel, i8)> {
+    match tpe {
+        ModificationType::Heat => vec![(Channel::Temperature, DRAW_DELTA)],
+        ModificationType::Cool => vec![(Channel::Temperature, -DRAW_DELTA)],
+        ModificationType::Humidify => vec![(Channel::Haze, DRAW_DELTA)],
+        ModificationType::Dehumidify => vec![(Channel::Haze, -DRAW_DELTA)],
+        ModificationType::Wind => {
+            let from = points[index.saturating_sub(1)];
+            let to = points[(index + 1).min(points.len() - 1)];
+
+            // in map cells; raw wind_y grows southward like the rows do. Strokes crossing the
+            // antimeridian take the short way round rather than back across the whole map
+            let dlong = (to.long - from.long + 180.).rem_euclid(360.) - 180.;
+            let dx = dlong / 360. * MAP_WIDTH as f64;
+            let dy = (from.lat - to.lat) / 180. * MAP_HEIGHT as f64;
+            let length = dx.hypot(dy);
+            if length == 0. {
+                return Vec::new();
+            }
+
+            let scale = DRAW_DELTA as f64 / length;
+            vec![
+                (Channel::WindX, (dx * scale).round() as i8),
+                (Channel::WindY, (dy * scale).round() as i8),
+            ]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Rect, Region};
+    use crate::state::zones::ZonePolicy;
+    use crate::state::STATE_BYTES;
+
+    const CLIENT: u64 = 1;
+    const INSIDE: LatLong = LatLong { lat: 5., long: 5. };
+    const OUTSIDE: LatLong = LatLong {
+        lat: 5.,
+        long: -20.,
+    };
+
+    fn locked() -> Vec<Zone> {
+        vec![Zone {
+            name: "reserve".into(),
+            region: Region::Rect(Rect {
+                top_left: LatLong { lat: 10., long: 0. },
+                bottom_right: LatLong { lat: 0., long: 10. },
+            }),
+            policy: ZonePolicy::Locked,
+        }]
+    }
+
+    fn stroke() -> Packet {
+        Packet::Modification {
+            tpe: ModificationType::Heat,
+            points: vec![INSIDE, OUTSIDE],
+            brush_size_degrees: 2.,
+            client_id: CLIENT,
+        }
+    }
+
+    fn temperature(buffer: &[u8], point: LatLong) -> u8 {
+        let (x, y) = latlong_to_pixel_coords(point);
+        buffer[(y as usize * MAP_WIDTH + x as usize) * BYTES_PER_PIXEL]
+    }
+
+    fn send(
+        buffer: &mut [u8],
+        zones: &[Zone],
+        strokes: &mut StrokeHistory,
+        packet: Packet,
+    ) -> Rejection {
+        apply(buffer, zones, strokes, CLIENT, Role::Painter, &packet).unwrap()
+    }
+
+    fn wind(points: &[(f64, f64)], index: usize) -> Vec<(Channel, i8)> {
+        let points: Vec<_> = points
+            .iter()
+            .map(|&(lat, long)| LatLong { lat, long })
+            .collect();
+        brush_deltas(ModificationType::Wind, &points, index)
+    }
+
+    #[test]
+    fn wind_blows_along_horizontal_strokes() {
+        let eastward = [(0., 0.), (0., 1.), (0., 2.)];
+        assert_eq!(
+            wind(&eastward, 1),
+            [(Channel::WindX, DRAW_DELTA), (Channel::WindY, 0)]
+        );
+        assert_eq!(
+            wind(&eastward, 0),
+            [(Channel::WindX, DRAW_DELTA), (Channel::WindY, 0)]
+        );
+    }
+
+    #[test]
+    fn wind_blows_along_vertical_strokes() {
+        // raw wind_y points south
+        let northward = [(0., 0.), (1., 0.), (2., 0.)];
+        assert_eq!(
+            wind(&northward, 2),
+            [(Channel::WindX, 0), (Channel::WindY, -DRAW_DELTA)]
+        );
+    }
+
+    #[test]
+    fn single_points_paint_no_wind() {
+        assert!(wind(&[(10., 10.)], 0).is_empty());
+    }
+
+    #[test]
+    fn wind_crosses_the_antimeridian_the_short_way() {
+        let eastward = [(0., 179.5), (0., -179.5)];
+        assert_eq!(
+            wind(&eastward, 0),
+            [(Channel::WindX, DRAW_DELTA), (Channel::WindY, 0)]
+        );
+        let westward = [(0., -179.5), (0., 179.5)];
+        assert_eq!(
+            wind(&westward, 1),
+            [(Channel::WindX, -DRAW_DELTA), (Channel::WindY, 0)]
+        );
+    }
+
+    #[test]
+    fn zone_before_stroke_keeps_undo_out() {
+        let mut buffer = vec![100; STATE_BYTES];
+        let mut strokes = StrokeHistory::new(4, usize::MAX);
+        let zones = locked();
+
+        let rejection = send(&mut buffer, &zones, &mut strokes, stroke());
+        assert_eq!(rejection.points, [0]);
+        assert_eq!(rejection.zones, ["reserve"]);
+        assert_eq!(temperature(&buffer, INSIDE), 100);
+        assert_eq!(temperature(&buffer, OUTSIDE), 227);
+
+        let rejection = send(
+            &mut buffer,
+            &zones,
+            &mut strokes,
+            Packet::Undo { client_id: CLIENT },
+        );
+        assert!(rejection.is_empty());
+        assert_eq!(temperature(&buffer, INSIDE), 100);
+        assert_eq!(temperature(&buffer, OUTSIDE), 100);
+    }
+
+    #[test]
+    fn zone_after_stroke_clips_undo() {
+        let mut buffer = vec![100; STATE_BYTES];
+        let mut strokes = StrokeHistory::new(4, usize::MAX);
+
+        send(&mut buffer, &[], &mut strokes, stroke());
+        assert_eq!(temperature(&buffer, INSIDE), 227);
+
+        let zones = locked();
+        let rejection = send(
+            &mut buffer,
+            &zones,
+            &mut strokes,
+            Packet::Undo { client_id: CLIENT },
+        );
+        assert!(rejection.points.is_empty());
+        assert_eq!(rejection.zones, ["reserve"]);
+        assert_eq!(temperature(&buffer, INSIDE), 227);
+        assert_eq!(temperature(&buffer, OUTSIDE), 100);
+
+        // the zone's cells weren't reverted, so there's nothing of them to redo either
+        let rejection = send(
+            &mut buffer,
+            &zones,
+            &mut strokes,
+            Packet::Redo { client_id: CLIENT },
+        );
+        assert!(rejection.is_empty());
+        assert_eq!(temperature(&buffer, OUTSIDE), 227);
+    }
+
+    #[test]
+    fn zone_after_undo_clips_redo() {
+        let mut buffer = vec![100; STATE_BYTES];
+        let mut strokes = StrokeHistory::new(4, usize::MAX);
+
+        send(&mut buffer, &[], &mut strokes, stroke());
+        send(
+            &mut buffer,
+            &[],
+            &mut strokes,
+            Packet::Undo { client_id: CLIENT },
+        );
+
+        let zones = locked();
+        let rejection = send(
+            &mut buffer,
+            &zones,
+            &mut strokes,
+            Packet::Redo { client_id: CLIENT },
+        );
+        assert_eq!(rejection.zones, ["reserve"]);
+        assert_eq!(temperature(&buffer, INSIDE), 100);
+        assert_eq!(temperature(&buffer, OUTSIDE), 227);
+    }
+
+    #[test]
+    fn admins_undo_through_role_zones() {
+        let mut buffer = vec![100; STATE_BYTES];
+        let mut strokes = StrokeHistory::new(4, usize::MAX);
+        send(&mut buffer, &[], &mut strokes, stroke());
+
+        let mut zones = locked();
+        zones[0].policy = ZonePolicy::Roles {
+            allowed: vec![Role::Admin],
+        };
+        let undo = Packet::Undo { client_id: CLIENT };
+        let rejection = apply(
+            &mut buffer,
+            &zones,
+            &mut strokes,
+            CLIENT,
+            Role::Admin,
+            &undo,
+        )
+        .unwrap();
+        assert!(rejection.is_empty());
+        assert_eq!(temperature(&buffer, INSIDE), 100);
+    }
+}
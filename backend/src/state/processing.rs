@@ -1,12 +1,34 @@
 use std::ops::Neg;
 
 use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
 use wgpu::BufferUsages;
 
 use super::{MAP_HEIGHT, MAP_WIDTH};
 
 const BYTES_PER_ROW: u32 = (super::MAP_WIDTH * super::BYTES_PER_PIXEL) as u32;
 
+/// What the simulation runs on, for operators to check.
+#[derive(Clone, Debug, Serialize)]
+pub struct AdapterDiagnostics {
+    pub name: String,
+    pub backend: &'static str,
+
+    /// e.g. `DiscreteGpu`, or `Cpu` for software rasterizers like llvmpipe.
+    pub device_type: String,
+    pub driver: String,
+    pub driver_info: String,
+    pub limits: AdapterLimits,
+}
+
+/// The device limits that matter for the simulation.
+#[derive(Clone, Debug, Serialize)]
+pub struct AdapterLimits {
+    pub max_texture_dimension_2d: u32,
+    pub max_buffer_size: u64,
+    pub max_bind_groups: u32,
+}
+
 pub struct GraphicsStuff {
     device: wgpu::Device,
     queue: wgpu::Queue,
@@ -15,6 +37,7 @@ pub struct GraphicsStuff {
     texture2: wgpu::Texture,
     render_to_texture2: bool,
     output_buffer: wgpu::Buffer,
+    adapter: AdapterDiagnostics,
 }
 
 impl GraphicsStuff {
@@ -40,6 +63,27 @@ impl GraphicsStuff {
             .await
             .with_context(|| "getting device from wgpu adapter")?;
 
+        let info = adapter.get_info();
+        let limits = device.limits();
+        let adapter = AdapterDiagnostics {
+            name: info.name,
+            backend: info.backend.to_str(),
+            device_type: format!("{:?}", info.device_type),
+            driver: info.driver,
+            driver_info: info.driver_info,
+            limits: AdapterLimits {
+                max_texture_dimension_2d: limits.max_texture_dimension_2d,
+                max_buffer_size: limits.max_buffer_size,
+                max_bind_groups: limits.max_bind_groups,
+            },
+        };
+        log::info!(
+            "Simulating on {} ({}, {})",
+            adapter.name,
+            adapter.backend,
+            adapter.device_type
+        );
+
         let shader = device.create_shader_module(wgpu::include_wgsl!("shader.wgsl"));
 
         let fragment_bind_group_layout =
@@ -137,9 +181,14 @@ impl GraphicsStuff {
             texture2,
             render_to_texture2: true,
             output_buffer,
+            adapter,
         })
     }
 
+    pub fn adapter(&self) -> &AdapterDiagnostics {
+        &self.adapter
+    }
+
     /// Sets the contents of the texture that will next be used as a render source.
     pub async fn set_source_texture_contents(&self, data: &[u8]) -> Result<()> {
         let texture = if self.render_to_texture2 {
@@ -173,6 +222,11 @@ impl GraphicsStuff {
         Ok(())
     }
 
+    /// Blocks until the GPU has finished all submitted work.
+    pub fn wait_idle(&self) {
+        self.device.poll(wgpu::Maintain::wait());
+    }
+
     /// Applies the vertex & fragment shaders that advance the state.
     pub fn apply_shader(&mut self) -> Result<()> {
         let mut command_encoder = self
@@ -247,7 +301,7 @@ impl GraphicsStuff {
             .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
         command_encoder.copy_texture_to_buffer(
             wgpu::ImageCopyTexture {
-                texture: &source_texture,
+                texture: source_texture,
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
                 aspect: wgpu::TextureAspect::All,
@@ -0,0 +1,319 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::message::Layer;
+
+use super::zones::Zone;
+use super::{Channel, DRAW_DELTA, MAP_HEIGHT, MAP_WIDTH, STATE_BYTES};
+
+/// Current version of the snapshot format.
+///
+/// - 0: bare RGBA8 PNG without any metadata
+/// - 1: metadata stored as JSON in an iTXt chunk
+/// - 2: protected zones in the metadata
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+/// Keyword of the PNG text chunk holding the snapshot metadata.
+const METADATA_KEYWORD: &str = "spacepaint";
+
+/// How a layer is laid out in the raw state.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FieldEncoding {
+    pub layer: Layer,
+
+    /// Byte offset of the layer within each pixel.
+    pub channel: u8,
+
+    /// Raw value corresponding to a neutral/zero value of the field.
+    pub zero: u8,
+}
+
+/// Parameters the simulation ran with when the snapshot was taken.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SimParams {
+    /// Change applied per brush stroke point.
+    pub draw_delta: i8,
+
+    /// Hash of the shader source, to tell apart worlds simulated with different rules.
+    pub shader_hash: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SnapshotMetadata {
+    pub version: u32,
+
+    /// Number of ticks the world had been simulated for.
+    pub tick: u64,
+
+    /// Seconds since the Unix epoch the snapshot was taken at.
+    pub saved_at: u64,
+
+    pub width: u32,
+    pub height: u32,
+    pub fields: Vec<FieldEncoding>,
+    pub params: SimParams,
+
+    /// Protected zones of the world, missing before version 2.
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+}
+
+impl SnapshotMetadata {
+    /// Metadata for a snapshot of the current build's world at the given tick.
+    pub fn current(tick: u64) -> SnapshotMetadata {
+        SnapshotMetadata {
+            version: SNAPSHOT_VERSION,
+            tick,
+            saved_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            width: MAP_WIDTH as u32,
+            height: MAP_HEIGHT as u32,
+            fields: current_fields(),
+            params: SimParams {
+                draw_delta: DRAW_DELTA,
+                shader_hash: shader_hash(),
+            },
+            zones: Vec::new(),
+        }
+    }
+}
+
+/// A saved world: raw RGBA8 state plus a description of it.
+pub struct WorldSnapshot {
+    pub metadata: SnapshotMetadata,
+    pub data: Vec<u8>,
+}
+
+impl WorldSnapshot {
+    /// Writes the snapshot as an RGBA8 PNG with the metadata in a text chunk.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let file = File::create(path.as_ref())?;
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(file),
+            self.metadata.width,
+            self.metadata.height,
+        );
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.add_itxt_chunk(
+            METADATA_KEYWORD.to_owned(),
+            serde_json::to_string(&self.metadata)?,
+        )?;
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.data)?;
+        writer.finish()?;
+
+        Ok(())
+    }
+
+    /// Reads a snapshot, migrating it to the current version and checking it fits this build's map.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<WorldSnapshot> {
+        let file = File::open(path.as_ref())?;
+        let mut reader = png::Decoder::new(BufReader::new(file)).read_info()?;
+
+        let (color_type, bit_depth) = reader.output_color_type();
+        if color_type != png::ColorType::Rgba || bit_depth != png::BitDepth::Eight {
+            anyhow::bail!("State images must be 8-bit RGBA");
+        }
+
+        let info = reader.info();
+        let (width, height) = (info.width, info.height);
+        let metadata_json = info
+            .utf8_text
+            .iter()
+            .find(|chunk| chunk.keyword == METADATA_KEYWORD)
+            .map(|chunk| chunk.get_text())
+            .transpose()?;
+
+        let metadata = match metadata_json {
+            Some(json) => serde_json::from_str(&json).context("parsing snapshot metadata")?,
+            None => legacy_metadata(width, height),
+        };
+        let metadata = migrate(metadata)?;
+
+        if metadata.width != width || metadata.height != height {
+            anyhow::bail!(
+                "snapshot metadata says {}x{} but image is {width}x{height}",
+                metadata.width,
+                metadata.height
+            );
+        }
+        if width as usize != MAP_WIDTH || height as usize != MAP_HEIGHT {
+            anyhow::bail!("snapshot is {width}x{height}, expected {MAP_WIDTH}x{MAP_HEIGHT}");
+        }
+
+        let mut data = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut data)?;
+        data.truncate(frame.buffer_size());
+        if data.len() != STATE_BYTES {
+            anyhow::bail!(
+                "snapshot has {} bytes of data, expected {STATE_BYTES}",
+                data.len()
+            );
+        }
+
+        if metadata.params.shader_hash != shader_hash() {
+            log::warn!("Snapshot was simulated with a different shader; behaviour may differ");
+        }
+
+        Ok(WorldSnapshot { metadata, data })
+    }
+}
+
+/// Metadata implied by a version 0 (bare PNG) snapshot.
+fn legacy_metadata(width: u32, height: u32) -> SnapshotMetadata {
+    SnapshotMetadata {
+        version: 0,
+        tick: 0,
+        saved_at: 0,
+        width,
+        height,
+        fields: current_fields(),
+        params: SimParams {
+            draw_delta: DRAW_DELTA,
+            shader_hash: 0,
+        },
+        zones: Vec::new(),
+    }
+}
+
+/// Upgrades metadata from older versions of the format, one version at a time.
+fn migrate(mut metadata: SnapshotMetadata) -> Result<SnapshotMetadata> {
+    if metadata.version > SNAPSHOT_VERSION {
+        anyhow::bail!(
+            "snapshot version {} is newer than supported version {SNAPSHOT_VERSION}",
+            metadata.version
+        );
+    }
+
+    while metadata.version < SNAPSHOT_VERSION {
+        match metadata.version {
+            // bare PNGs used the same channel layout, so nothing to convert
+            0 => {
+                log::info!("Migrating version 0 snapshot");
+                metadata.version = 1;
+            }
+            // worlds without zones just have none
+            1 => metadata.version = 2,
+            v => anyhow::bail!("don't know how to migrate snapshot version {v}"),
+        }
+    }
+
+    // the raw data is loaded as-is, so its layout has to match what the simulation expects
+    let expected = current_fields();
+    let layout_matches = metadata.fields.len() == expected.len()
+        && metadata
+            .fields
+            .iter()
+            .zip(&expected)
+            .all(|(a, b)| a.layer == b.layer && a.channel == b.channel && a.zero == b.zero);
+    if !layout_matches {
+        anyhow::bail!(
+            "snapshot field layout {:?} isn't supported",
+            metadata.fields
+        );
+    }
+
+    Ok(metadata)
+}
+
+fn current_fields() -> Vec<FieldEncoding> {
+    [
+        (Layer::Temperature, 127),
+        (Layer::WindX, 127),
+        (Layer::WindY, 127),
+        (Layer::Haze, 0),
+    ]
+    .into_iter()
+    .map(|(layer, zero)| FieldEncoding {
+        layer,
+        channel: Channel::from(layer) as u8,
+        zero,
+    })
+    .collect()
+}
+
+/// FNV-1a hash of the shader source.
+fn shader_hash() -> u64 {
+    include_str!("shader.wgsl")
+        .bytes()
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A bare RGBA8 PNG as saved by version 0, at a path of its own for the test.
+    fn bare_png(name: &str, width: u32, height: u32) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "spacepaint-snapshot-{name}-{}.png",
+            std::process::id()
+        ));
+        let mut encoder = png::Encoder::new(File::create(&path).unwrap(), width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_image_data(&vec![0; width as usize * height as usize * 4])
+            .unwrap();
+        writer.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn migrates_bare_pngs() {
+        let metadata = migrate(legacy_metadata(MAP_WIDTH as u32, MAP_HEIGHT as u32)).unwrap();
+        assert_eq!(metadata.version, SNAPSHOT_VERSION);
+        assert!(metadata.zones.is_empty());
+
+        let path = bare_png("v0", MAP_WIDTH as u32, MAP_HEIGHT as u32);
+        let snapshot = WorldSnapshot::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(snapshot.metadata.version, SNAPSHOT_VERSION);
+        assert_eq!(snapshot.data.len(), STATE_BYTES);
+    }
+
+    #[test]
+    fn migrates_metadata_without_zones() {
+        let mut json = serde_json::to_value(SnapshotMetadata::current(42)).unwrap();
+        json["version"] = 1.into();
+        json.as_object_mut().unwrap().remove("zones");
+
+        let metadata = migrate(serde_json::from_value(json).unwrap()).unwrap();
+        assert_eq!(metadata.version, SNAPSHOT_VERSION);
+        assert_eq!(metadata.tick, 42);
+        assert!(metadata.zones.is_empty());
+    }
+
+    #[test]
+    fn refuses_newer_versions_and_other_layouts() {
+        let mut newer = SnapshotMetadata::current(0);
+        newer.version = SNAPSHOT_VERSION + 1;
+        assert!(migrate(newer).is_err());
+
+        let mut swapped = SnapshotMetadata::current(0);
+        swapped.fields.swap(0, 1);
+        assert!(migrate(swapped).is_err());
+    }
+
+    #[test]
+    fn refuses_images_of_the_wrong_size() {
+        let path = bare_png("small", 16, 8);
+        let loaded = WorldSnapshot::load(&path);
+        std::fs::remove_file(path).unwrap();
+
+        let error = loaded.err().unwrap().to_string();
+        assert!(error.contains("16x8"), "{error}");
+    }
+}
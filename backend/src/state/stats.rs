@@ -0,0 +1,286 @@
+//! Statistics of the state over a region, computed from every map cell within it.
+//!
+//! Cells shrink towards the poles, so each is weighted by the cosine of its latitude.
+
+use anyhow::{Context, Result};
+
+use crate::message::{FieldStats, LatLong, Layer, Region, StatsField};
+
+use super::units::{from_raw, unit};
+use super::{cell_bounds, cell_center, layer_offset, BYTES_PER_PIXEL, MAP_WIDTH};
+
+/// Bins of each field's histogram.
+const HISTOGRAM_BINS: usize = 16;
+
+/// Statistics of a region; `fields` is empty if no cell lies within it.
+pub struct RegionStats {
+    pub cells: u64,
+    pub fields: Vec<FieldStats>,
+}
+
+/// Running sums of a field over the cells seen so far.
+struct Sums {
+    weight: f64,
+    value: f64,
+    squares: f64,
+    min: f64,
+    max: f64,
+}
+
+/// Statistics of `fields` over the cells of full-resolution `data` whose centers lie in `region`.
+pub(super) fn compute(data: &[u8], region: &Region, fields: &[StatsField]) -> RegionStats {
+    let mut cells = 0;
+    let mut sums: Vec<_> = fields
+        .iter()
+        .map(|_| Sums {
+            weight: 0.,
+            value: 0.,
+            squares: 0.,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        })
+        .collect();
+    for_each_cell(data, region, |pixel, weight| {
+        cells += 1;
+        for (field, sums) in fields.iter().zip(&mut sums) {
+            let value = value(*field, pixel);
+            sums.weight += weight;
+            sums.value += weight * value;
+            sums.squares += weight * value * value;
+            sums.min = sums.min.min(value);
+            sums.max = sums.max.max(value);
+        }
+    });
+    if cells == 0 {
+        return RegionStats {
+            cells,
+            fields: Vec::new(),
+        };
+    }
+
+    // bins depend on the range, so they take a second pass
+    let mut histograms = vec![[0.; HISTOGRAM_BINS]; fields.len()];
+    for_each_cell(data, region, |pixel, weight| {
+        for ((field, sums), histogram) in fields.iter().zip(&sums).zip(&mut histograms) {
+            let position = (value(*field, pixel) - sums.min) / (sums.max - sums.min);
+            // a field that's the same everywhere has no range, and lands in the first bin
+            let bin = if position.is_finite() {
+                ((position * HISTOGRAM_BINS as f64) as usize).min(HISTOGRAM_BINS - 1)
+            } else {
+                0
+            };
+            histogram[bin] += weight;
+        }
+    });
+
+    let fields = fields
+        .iter()
+        .zip(sums)
+        .zip(histograms)
+        .map(|((field, sums), histogram)| {
+            let mean = sums.value / sums.weight;
+            let variance = sums.squares / sums.weight - mean * mean;
+            FieldStats {
+                field: *field,
+                unit: field_unit(*field).to_owned(),
+                min: sums.min,
+                max: sums.max,
+                mean,
+                std_dev: variance.max(0.).sqrt(),
+                histogram: histogram
+                    .iter()
+                    .map(|weight| weight / sums.weight)
+                    .collect(),
+            }
+        })
+        .collect();
+
+    RegionStats { cells, fields }
+}
+
+/// Calls `f` with the pixel & area weight of every cell whose center lies in `region`.
+fn for_each_cell(data: &[u8], region: &Region, mut f: impl FnMut(&[u8], f64)) {
+    let (x_start, x_end, y_start, y_end) = cell_bounds(region);
+
+    for y in y_start..y_end {
+        let weight = cell_center(0, y).lat.to_radians().cos();
+        for x in x_start..x_end {
+            if !region.contains(cell_center(x, y)) {
+                continue;
+            }
+
+            let index = (y * MAP_WIDTH + x) * BYTES_PER_PIXEL;
+            f(&data[index..index + BYTES_PER_PIXEL], weight);
+        }
+    }
+}
+
+fn value(field: StatsField, pixel: &[u8]) -> f64 {
+    let layer = |layer| from_raw(layer, pixel[layer_offset(layer)]);
+
+    match field {
+        StatsField::Temperature => layer(Layer::Temperature),
+        StatsField::WindX => layer(Layer::WindX),
+        // the layer points south; stats follow exports & admin fills in pointing north
+        StatsField::WindY => -layer(Layer::WindY),
+        StatsField::WindSpeed => layer(Layer::WindX).hypot(layer(Layer::WindY)),
+        StatsField::Haze => layer(Layer::Haze),
+    }
+}
+
+fn field_unit(field: StatsField) -> &'static str {
+    match field {
+        StatsField::Temperature => unit(Layer::Temperature),
+        StatsField::WindX | StatsField::WindY | StatsField::WindSpeed => unit(Layer::WindX),
+        StatsField::Haze => unit(Layer::Haze),
+    }
+}
+
+/// Parses a field name as given in query strings.
+pub fn parse_field(name: &str) -> Result<StatsField> {
+    match name.trim() {
+        "temperature" => Ok(StatsField::Temperature),
+        "wind_x" => Ok(StatsField::WindX),
+        "wind_y" => Ok(StatsField::WindY),
+        "wind_speed" => Ok(StatsField::WindSpeed),
+        "haze" => Ok(StatsField::Haze),
+        _ => anyhow::bail!(
+            "unknown field {name:?}; expected temperature, wind_x, wind_y, wind_speed or haze"
+        ),
+    }
+}
+
+/// Parses polygon corners given as `lat,long;lat,long;...` in degrees.
+pub fn parse_polygon(corners: &str) -> Result<Region> {
+    let corners = corners
+        .split(';')
+        .map(|corner| {
+            let (lat, long) = corner
+                .split_once(',')
+                .with_context(|| format!("corner {corner:?} should be lat,long"))?;
+            Ok(LatLong {
+                lat: lat.trim().parse()?,
+                long: long.trim().parse()?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()
+        .with_context(|| format!("polygon {corners:?} isn't a list of lat,long corners"))?;
+
+    let region = Region::Polygon(corners);
+    validate_region(&region)?;
+    Ok(region)
+}
+
+/// Checks that a region is usable for statistics, zones or scenarios, however it was given:
+/// coordinates are finite, rectangles aren't inverted & polygons have at least 3 corners.
+pub fn validate_region(region: &Region) -> Result<()> {
+    let corners = match region {
+        Region::Rect(rect) => vec![rect.top_left, rect.bottom_right],
+        Region::Polygon(corners) => corners.clone(),
+    };
+    if let Some(corner) = corners
+        .iter()
+        .find(|corner| !corner.lat.is_finite() || !corner.long.is_finite())
+    {
+        anyhow::bail!("corner {corner:?} isn't a finite latitude & longitude");
+    }
+
+    match region {
+        Region::Rect(rect) => {
+            if rect.top_left.lat <= rect.bottom_right.lat
+                || rect.top_left.long >= rect.bottom_right.long
+            {
+                anyhow::bail!("area {rect:?} is empty, inverted or crosses the antimeridian");
+            }
+        }
+        Region::Polygon(corners) => {
+            if corners.len() < 3 {
+                anyhow::bail!("polygons need at least 3 corners");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Rect;
+    use crate::state::units::to_raw;
+    use crate::state::{MAP_HEIGHT, STATE_BYTES};
+
+    fn rect(north: f64, west: f64, south: f64, east: f64) -> Region {
+        Region::Rect(Rect {
+            top_left: LatLong {
+                lat: north,
+                long: west,
+            },
+            bottom_right: LatLong {
+                lat: south,
+                long: east,
+            },
+        })
+    }
+
+    /// A state with `layer` of every cell set to `value(latitude)`.
+    fn state(layer: Layer, value: impl Fn(f64) -> u8) -> Vec<u8> {
+        let mut data = vec![0; STATE_BYTES];
+        for y in 0..MAP_HEIGHT {
+            let raw = value(cell_center(0, y).lat);
+            for x in 0..MAP_WIDTH {
+                data[(y * MAP_WIDTH + x) * BYTES_PER_PIXEL + layer_offset(layer)] = raw;
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn cells_are_weighted_by_area() {
+        // the band from the equator to 30° holds as much area as the one from 30° to the pole,
+        // though only half as many rows
+        let data = state(Layer::Temperature, |lat| if lat < 30. { 0 } else { 255 });
+        let stats = compute(&data, &rect(90., 0., 0., 10.), &[StatsField::Temperature]);
+        let temperature = &stats.fields[0];
+
+        let (cold, hot) = (
+            from_raw(Layer::Temperature, 0),
+            from_raw(Layer::Temperature, 255),
+        );
+        assert!((temperature.mean - (cold + hot) / 2.).abs() < 0.01 * (hot - cold));
+        assert!((temperature.histogram[0] - 0.5).abs() < 0.01);
+        assert!((temperature.histogram[HISTOGRAM_BINS - 1] - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn histograms_have_16_bins_summing_to_1() {
+        let data = state(Layer::Haze, |lat| (lat + 90.) as u8);
+        let stats = compute(&data, &rect(60., -30., -60., 30.), &[StatsField::Haze]);
+        let histogram = &stats.fields[0].histogram;
+
+        assert_eq!(histogram.len(), 16);
+        assert!((histogram.iter().sum::<f64>() - 1.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn wind_y_is_reported_northward() {
+        // the raw layer points south
+        let data = state(Layer::WindY, |_| to_raw(Layer::WindY, -4.));
+        let stats = compute(&data, &rect(10., 0., 0., 10.), &[StatsField::WindY]);
+
+        assert!((stats.fields[0].mean - 4.).abs() < 1.);
+        assert!(stats.fields[0].mean > 0.);
+    }
+
+    #[test]
+    fn regions_are_validated() {
+        assert!(validate_region(&rect(10., 0., 0., 10.)).is_ok());
+        assert!(validate_region(&rect(0., 0., 10., 10.)).is_err());
+        assert!(validate_region(&rect(10., 10., 0., 0.)).is_err());
+        assert!(validate_region(&rect(f64::NAN, 0., 0., 10.)).is_err());
+
+        assert!(parse_polygon("0,0;10,10;0,20").is_ok());
+        assert!(parse_polygon("0,0;10,10").is_err());
+        assert!(parse_polygon("0,0;NaN,10;0,20").is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::message::ModificationType;
+
+/// Net change a stroke made to the state, per byte of the raw buffer it touched.
+pub struct Stroke {
+    /// Tool the stroke was painted with, which decides the zones it may change.
+    tool: ModificationType,
+    deltas: HashMap<u32, i16>,
+}
+
+impl Stroke {
+    pub fn new(tool: ModificationType) -> Stroke {
+        Stroke {
+            tool,
+            deltas: HashMap::new(),
+        }
+    }
+
+    /// Records that the byte at `index` changed by `delta`.
+    pub fn add(&mut self, index: usize, delta: i16) {
+        if delta != 0 {
+            *self.deltas.entry(index as u32).or_default() += delta;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.values().all(|delta| *delta == 0)
+    }
+
+    /// Roughly how much memory the stroke's deltas take.
+    fn bytes(&self) -> usize {
+        self.deltas.len() * std::mem::size_of::<(u32, i16)>()
+    }
+
+    /// Applies the stroke's deltas to `buffer`, negated if `invert` is set, except to bytes that
+    /// `allowed(tool, index)` refuses.
+    ///
+    /// Values saturate, so returns the change that actually got applied.
+    fn apply(
+        &self,
+        buffer: &mut [u8],
+        invert: bool,
+        mut allowed: impl FnMut(ModificationType, usize) -> bool,
+    ) -> Stroke {
+        let mut applied = Stroke::new(self.tool);
+
+        for (&index, &delta) in self.deltas.iter() {
+            if !allowed(self.tool, index as usize) {
+                continue;
+            }
+
+            let delta = if invert { -delta } else { delta };
+            let before = buffer[index as usize];
+            let after = (before as i16 + delta).clamp(0, u8::MAX as i16) as u8;
+
+            buffer[index as usize] = after;
+            applied.add(index as usize, after as i16 - before as i16);
+        }
+
+        applied
+    }
+
+    /// The stroke that would undo this one.
+    fn inverse(self) -> Stroke {
+        Stroke {
+            tool: self.tool,
+            deltas: self
+                .deltas
+                .into_iter()
+                .map(|(index, delta)| (index, -delta))
+                .collect(),
+        }
+    }
+}
+
+/// A client's undo & redo stacks, most recent stroke last.
+#[derive(Default)]
+struct ClientStrokes {
+    undo: VecDeque<Stroke>,
+    redo: Vec<Stroke>,
+}
+
+impl ClientStrokes {
+    fn bytes(&self) -> usize {
+        self.undo.iter().chain(&self.redo).map(Stroke::bytes).sum()
+    }
+}
+
+/// Recent strokes of every client, so they can be undone & redone.
+pub struct StrokeHistory {
+    clients: HashMap<u64, ClientStrokes>,
+
+    /// Most strokes remembered per client.
+    depth: usize,
+
+    /// Most bytes of strokes remembered per client, so a few huge strokes can't hog memory.
+    max_bytes: usize,
+}
+
+impl StrokeHistory {
+    pub fn new(depth: usize, max_bytes: usize) -> StrokeHistory {
+        StrokeHistory {
+            clients: HashMap::new(),
+            depth,
+            max_bytes,
+        }
+    }
+
+    /// Remembers a freshly applied stroke, which invalidates anything that could be redone.
+    /// The oldest strokes are forgotten to stay within the limits; a stroke that's too big by
+    /// itself can't be undone at all.
+    pub fn record(&mut self, client_id: u64, stroke: Stroke) {
+        if self.depth == 0 || stroke.is_empty() {
+            return;
+        }
+
+        let strokes = self.clients.entry(client_id).or_default();
+        strokes.redo.clear();
+        if stroke.bytes() > self.max_bytes {
+            return;
+        }
+        if strokes.undo.len() == self.depth {
+            strokes.undo.pop_front();
+        }
+        strokes.undo.push_back(stroke);
+        while strokes.bytes() > self.max_bytes {
+            strokes.undo.pop_front();
+        }
+    }
+
+    /// Reverts the client's last stroke on the cells it touched & `allowed` lets it change.
+    /// Returns whether there was one.
+    pub fn undo(
+        &mut self,
+        client_id: u64,
+        buffer: &mut [u8],
+        allowed: impl FnMut(ModificationType, usize) -> bool,
+    ) -> bool {
+        let Some(strokes) = self.clients.get_mut(&client_id) else {
+            return false;
+        };
+        let Some(stroke) = strokes.undo.pop_back() else {
+            return false;
+        };
+
+        // only what was actually reverted can be redone, since the simulation has moved on since
+        let reverted = stroke.apply(buffer, true, allowed);
+        strokes.redo.push(reverted.inverse());
+
+        true
+    }
+
+    /// Re-applies the client's last undone stroke where `allowed` lets it. Returns whether there
+    /// was one.
+    pub fn redo(
+        &mut self,
+        client_id: u64,
+        buffer: &mut [u8],
+        allowed: impl FnMut(ModificationType, usize) -> bool,
+    ) -> bool {
+        let Some(strokes) = self.clients.get_mut(&client_id) else {
+            return false;
+        };
+        let Some(stroke) = strokes.redo.pop() else {
+            return false;
+        };
+
+        let redone = stroke.apply(buffer, false, allowed);
+        strokes.undo.push_back(redone);
+
+        true
+    }
+
+    /// Drops everything remembered about a client, e.g. once it disconnects.
+    pub fn forget(&mut self, client_id: u64) {
+        self.clients.remove(&client_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stroke(cells: usize) -> Stroke {
+        let mut stroke = Stroke::new(ModificationType::Heat);
+        for index in 0..cells {
+            stroke.add(index, 1);
+        }
+        stroke
+    }
+
+    /// How many strokes `undo` can revert.
+    fn undoable(history: &mut StrokeHistory) -> usize {
+        let mut buffer = vec![100; 16];
+        std::iter::from_fn(|| history.undo(1, &mut buffer, |_, _| true).then_some(())).count()
+    }
+
+    #[test]
+    fn oldest_strokes_are_forgotten_past_the_depth() {
+        let mut history = StrokeHistory::new(2, usize::MAX);
+        for _ in 0..3 {
+            history.record(1, stroke(1));
+        }
+        assert_eq!(undoable(&mut history), 2);
+    }
+
+    #[test]
+    fn oldest_strokes_are_forgotten_past_the_byte_limit() {
+        let mut history = StrokeHistory::new(10, stroke(5).bytes());
+        history.record(1, stroke(2));
+        history.record(1, stroke(2));
+        history.record(1, stroke(2));
+        assert_eq!(undoable(&mut history), 2);
+    }
+
+    #[test]
+    fn strokes_over_the_byte_limit_are_not_kept() {
+        let mut history = StrokeHistory::new(10, stroke(4).bytes());
+        history.record(1, stroke(1));
+        history.record(1, stroke(8));
+        assert_eq!(undoable(&mut history), 1);
+    }
+}
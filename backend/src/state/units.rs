@@ -0,0 +1,50 @@
+//! Physical meaning of the raw `u8` channel values.
+
+use crate::message::Layer;
+
+/// Temperature at raw 0, in kelvin (-60 °C).
+pub const TEMPERATURE_MIN_K: f64 = 213.15;
+
+/// Temperature at raw 255, in kelvin (50 °C).
+pub const TEMPERATURE_MAX_K: f64 = 323.15;
+
+/// Raw value of a calm wind component.
+pub const WIND_ZERO: u8 = 127;
+
+/// Wind speed per raw step away from [`WIND_ZERO`], in m/s.
+pub const WIND_MS_PER_STEP: f64 = 0.5;
+
+/// Converts a physical value of the layer (kelvin, m/s, or a 0-1 haze fraction) to its raw value.
+pub fn to_raw(layer: Layer, value: f64) -> u8 {
+    let raw = match layer {
+        Layer::Temperature => {
+            (value - TEMPERATURE_MIN_K) / (TEMPERATURE_MAX_K - TEMPERATURE_MIN_K) * 255.
+        }
+        Layer::WindX | Layer::WindY => WIND_ZERO as f64 + value / WIND_MS_PER_STEP,
+        Layer::Haze => value * 255.,
+    };
+
+    raw.round().clamp(0., u8::MAX as f64) as u8
+}
+
+/// Inverse of [`to_raw`].
+pub fn from_raw(layer: Layer, raw: u8) -> f64 {
+    let raw = raw as f64;
+
+    match layer {
+        Layer::Temperature => {
+            TEMPERATURE_MIN_K + raw / 255. * (TEMPERATURE_MAX_K - TEMPERATURE_MIN_K)
+        }
+        Layer::WindX | Layer::WindY => (raw - WIND_ZERO as f64) * WIND_MS_PER_STEP,
+        Layer::Haze => raw / 255.,
+    }
+}
+
+/// UDUNITS name of the unit [`from_raw`] returns for the layer.
+pub fn unit(layer: Layer) -> &'static str {
+    match layer {
+        Layer::Temperature => "K",
+        Layer::WindX | Layer::WindY => "m s-1",
+        Layer::Haze => "1",
+    }
+}
@@ -0,0 +1,56 @@
+//! Protected zones of the map where painting is disallowed or restricted.
+//!
+//! Zones are kept in the snapshot metadata, so they're saved & restored with the world.
+
+use serde::{Deserialize, Serialize};
+
+use crate::message::{ModificationType, Region, Role};
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Zone {
+    /// Unique name of the zone, shown to clients whose strokes it clips.
+    pub name: String,
+    pub region: Region,
+    pub policy: ZonePolicy,
+}
+
+/// Who may paint what inside a zone.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ZonePolicy {
+    /// Nobody may paint.
+    Locked,
+
+    /// Only these tools may be used.
+    Tools { allowed: Vec<ModificationType> },
+
+    /// Only clients with these roles may paint.
+    Roles { allowed: Vec<Role> },
+}
+
+impl Zone {
+    /// Whether a client with `role` may paint with `tool` inside the zone.
+    pub fn allows(&self, tool: ModificationType, role: Role) -> bool {
+        match &self.policy {
+            ZonePolicy::Locked => false,
+            ZonePolicy::Tools { allowed } => allowed.contains(&tool),
+            ZonePolicy::Roles { allowed } => allowed.contains(&role),
+        }
+    }
+}
+
+/// Parts of a stroke, undo or redo that weren't applied because they fell in protected zones.
+#[derive(Debug, Default)]
+pub struct Rejection {
+    /// Indices of the stroke points whose brush was clipped; always empty for undos & redos.
+    pub points: Vec<usize>,
+
+    /// Names of the zones that clipped it.
+    pub zones: Vec<String>,
+}
+
+impl Rejection {
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty() && self.zones.is_empty()
+    }
+}
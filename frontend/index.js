@@ -4,7 +4,11 @@ import init, {
   Pixel,
   ModificationType,
   update_viewport,
+  request_history,
   do_changes,
+  undo,
+  redo,
+  send_presence,
   rect,
   latlong,
 } from "./png-decoder/pkg/png_decoder.js";
@@ -15,6 +19,23 @@ let mode_view = { view_clouds: false, view_heat: true, view_wind: true };
 let laser_width = 60;
 let objects = [];
 
+// other users' cursors & in-progress strokes, keyed by client ID
+let ghosts = new Map();
+const GHOST_TIMEOUT_MS = 5000;
+const PRESENCE_INTERVAL_MS = 50;
+let last_presence_sent = 0;
+
+// screen pixels covered by each snapshot pixel
+const SNAPSHOT_CELL_PX = 24;
+
+// ID of the viewport following the map; others could be used for e.g. minimaps
+const MAIN_VIEWPORT = 0;
+
+// how far back the history scrubber is set; 0 means live
+let history_seconds_ago = 0;
+let replay_interval = null;
+const HISTORY_SECONDS = 180;
+
 function toggleAboutModal() {
   document.getElementById("modal-backdrop").classList.toggle("hidden");
   document.getElementById("about-modal").classList.toggle("hidden");
@@ -197,7 +218,29 @@ function marchingSquares(field, threshold, location, zoom, zoom_y) {
 let polygons = [];
 let Polygons = [];
 
-function update_map(data, width, area) {
+function update_map(viewport_id, data, width, area) {
+  // live snapshots are ignored while looking at the past
+  if (viewport_id != MAIN_VIEWPORT || history_seconds_ago > 0) {
+    return;
+  }
+
+  drawSnapshot(data, width, area);
+}
+
+function update_history(viewport_id, tick, timestamp_ms, data, width, area) {
+  if (viewport_id != MAIN_VIEWPORT || history_seconds_ago == 0) {
+    return;
+  }
+
+  let label = document.getElementsByClassName("history_slider")[0];
+  label.setAttribute(
+    "title",
+    `Tick ${tick} (${new Date(timestamp_ms).toLocaleTimeString()})`,
+  );
+  drawSnapshot(data, width, area);
+}
+
+function drawSnapshot(data, width, area) {
   for (let P of Polygons) {
     P.remove(map);
   }
@@ -292,10 +335,104 @@ function update_map(data, width, area) {
   }
 }
 
+function degreesPerScreenPixel() {
+  let bounds = map.getBounds();
+  let viewport_width = Math.abs(bounds.getEast() - bounds.getWest());
+  return viewport_width / map.getSize().x;
+}
+
+// Tells the server what area we're looking at, how detailed the snapshots should be
+// and which layers they should carry
+function sendViewport() {
+  let bounds = map.getBounds();
+  let overscan = Math.abs(bounds.getNorth() - bounds.getSouth()) * (1 / 10);
+  let north = bounds.getNorth() + overscan;
+  let west = bounds.getWest() - overscan;
+  let south = bounds.getSouth() - overscan;
+  let east = bounds.getEast() + overscan;
+
+  // one snapshot pixel per SNAPSHOT_CELL_PX screen pixels (the server clamps this)
+  let cell_degrees = degreesPerScreenPixel() * SNAPSHOT_CELL_PX;
+  let width = Math.max(1, Math.round((east - west) / cell_degrees));
+  let height = Math.max(1, Math.round((north - south) / cell_degrees));
+
+  // only subscribe to the layers that are actually being drawn
+  update_viewport(
+    MAIN_VIEWPORT,
+    rect(north, west, south, east),
+    width,
+    height,
+    mode_view.view_heat,
+    mode_view.view_wind,
+    mode_view.view_clouds,
+  );
+}
+
+function isAdditiveTool(tool) {
+  return tool == ModificationType.Heat || tool == ModificationType.Humidify;
+}
+
+function removeGhost(client_id) {
+  let ghost = ghosts.get(client_id);
+  if (ghost !== undefined) {
+    ghost.cursor.remove(map);
+    if (ghost.stroke !== null) {
+      ghost.stroke.remove(map);
+    }
+    clearTimeout(ghost.timeout);
+    ghosts.delete(client_id);
+  }
+}
+
+// Draws another user's cursor and, while they're painting, their stroke so far
+function update_presence(client_id, cursor, tool, painting, brush_size_degrees) {
+  let ghost = ghosts.get(client_id);
+  if (ghost === undefined) {
+    ghost = {
+      cursor: L.circleMarker([cursor.lat, cursor.long], {
+        radius: 5,
+        color: "#fff",
+        fillOpacity: 0.8,
+      }).addTo(map),
+      stroke: null,
+      timeout: null,
+    };
+    ghosts.set(client_id, ghost);
+  }
+
+  let color = "#fff";
+  if (tool !== undefined) {
+    color = isAdditiveTool(tool) ? "#37ff37" : "#ff3737";
+  }
+
+  ghost.cursor.setLatLng([cursor.lat, cursor.long]);
+  ghost.cursor.setStyle({ color: color });
+
+  if (painting) {
+    if (ghost.stroke === null) {
+      ghost.stroke = L.polyline([], { opacity: 0.4 }).addTo(map);
+    }
+    ghost.stroke.addLatLng([cursor.lat, cursor.long]);
+    ghost.stroke.setStyle({
+      color: color,
+      weight: brush_size_degrees / degreesPerScreenPixel(),
+    });
+  } else if (ghost.stroke !== null) {
+    ghost.stroke.remove(map);
+    ghost.stroke = null;
+  }
+
+  // drop ghosts of users that went quiet or left our view
+  clearTimeout(ghost.timeout);
+  ghost.timeout = setTimeout(() => removeGhost(client_id), GHOST_TIMEOUT_MS);
+}
+
 window.addEventListener("DOMContentLoaded", function () {
   map = L.map("map").setView([10, 10], 5);
 
   document.update_map = update_map;
+  document.update_history = update_history;
+  document.update_presence = update_presence;
 
   L.tileLayer("https://tile.openstreetmap.org/{z}/{x}/{y}.png", {
     maxZoom: 19,
@@ -307,10 +444,12 @@ window.addEventListener("DOMContentLoaded", function () {
   let paintMode = false;
   var myPolyline;
 
-  map.on("click", function () {
+  map.on("click", function (e) {
     let curCtrlMode = getCurrentCtrlMode();
     if (curCtrlMode !== null) {
       paintMode = !paintMode;
+      sharePresence(e.latlng, true);
+
       if (paintMode) {
         myPolyline = L.polyline([]).addTo(map);
       } else {
@@ -331,7 +470,41 @@ window.addEventListener("DOMContentLoaded", function () {
     }
   });
 
+  function sharePresence(latlng, force) {
+    let now = Date.now();
+    if (!force && now - last_presence_sent < PRESENCE_INTERVAL_MS) {
+      return;
+    }
+    last_presence_sent = now;
+
+    let tool = getCurrentCtrlMode();
+    send_presence(
+      latlong(latlng.lat, latlng.lng),
+      tool === null ? undefined : tool,
+      paintMode,
+      laser_width * degreesPerScreenPixel(),
+    );
+  }
+
+  // Ctrl+Z undoes the last stroke; Ctrl+Shift+Z or Ctrl+Y redoes it
+  document.addEventListener("keydown", function (e) {
+    if (!(e.ctrlKey || e.metaKey)) {
+      return;
+    }
+    let key = e.key.toLowerCase();
+    if (key == "z" && !e.shiftKey) {
+      undo();
+      e.preventDefault();
+    } else if ((key == "z" && e.shiftKey) || key == "y") {
+      redo();
+      e.preventDefault();
+    }
+  });
+
   map.on("mousemove", function (e) {
+    // painting points are always shared so other users' ghost strokes stay faithful
+    sharePresence(e.latlng, paintMode);
+
     let laser_color = "#fff";
     for (const item in mode) {
       if (mode[item] === true) {
@@ -352,18 +525,7 @@ window.addEventListener("DOMContentLoaded", function () {
     }
   });
 
-  map.on("move", function () {
-    let bounds = map.getBounds();
-    let overscan = Math.abs(bounds.getNorth() - bounds.getSouth()) * (1 / 10);
-    update_viewport(
-      rect(
-        bounds.getNorth() + overscan,
-        bounds.getWest() - overscan,
-        bounds.getSouth() - overscan,
-        bounds.getEast() + overscan,
-      ),
-    );
-  });
+  map.on("move", sendViewport);
 
   // Makes a button for the UI
   function makeButton(
@@ -394,6 +556,7 @@ window.addEventListener("DOMContentLoaded", function () {
   // Toggles the view mode of a button and displays enable vs disabled colors
   function toggleMode_view(mode_var, mode_type, className) {
     mode_var[mode_type] = !mode_var[mode_type];
+    sendViewport();
     let button_html = document.getElementsByClassName(className)[0];
     if (mode_var[mode_type]) {
       button_html.setAttribute("style", "background-color: #3737ff;");
@@ -446,6 +609,37 @@ window.addEventListener("DOMContentLoaded", function () {
       document.getElementsByClassName(sliderClass)[0].childNodes[0].value;
   }
 
+  function showHistory(seconds_ago) {
+    history_seconds_ago = seconds_ago;
+    document.getElementsByClassName("history_slider")[0].childNodes[0].value =
+      HISTORY_SECONDS - seconds_ago;
+    if (seconds_ago > 0) {
+      request_history(MAIN_VIEWPORT, Date.now() - seconds_ago * 1000);
+    }
+  }
+
+  function history_input(sliderClass) {
+    let value =
+      document.getElementsByClassName(sliderClass)[0].childNodes[0].value;
+    showHistory(HISTORY_SECONDS - value);
+  }
+
+  // Plays the history back at double speed until it catches up to live
+  function toggleReplay() {
+    if (replay_interval !== null) {
+      clearInterval(replay_interval);
+      replay_interval = null;
+      return;
+    }
+    replay_interval = setInterval(function () {
+      showHistory(Math.max(0, history_seconds_ago - 1));
+      if (history_seconds_ago == 0) {
+        clearInterval(replay_interval);
+        replay_interval = null;
+      }
+    }, 500);
+  }
+
   // Button to go to about page
   var aboutPage = makeButton(
     "&#9432;",
@@ -540,10 +734,48 @@ window.addEventListener("DOMContentLoaded", function () {
     ["ctrl_slider"],
   );
 
+  // Buttons to take back or re-apply your own strokes
+  var undo_button = makeButton("&#8630;", "Undo stroke", "undo", [], undo, []);
+  var redo_button = makeButton("&#8631;", "Redo stroke", "redo", [], redo, []);
+
+  // Timeline scrubber; all the way right is live
+  var history_slider = makeButton(
+    `<input type="range" min="0" max="${HISTORY_SECONDS}" value="${HISTORY_SECONDS}">`,
+    "Scrub through history",
+    "history_slider",
+    [],
+    history_input,
+    ["history_slider"],
+  );
+  var history_replay = makeButton(
+    "&#9654;",
+    "Replay history",
+    "history_replay",
+    [],
+    toggleReplay,
+    [],
+  );
+  var control_history = makeButton(
+    "&#8634;",
+    "Control history",
+    "ctrl_history",
+    [history_slider, history_replay],
+    toggleSubBar,
+    ["history_slider"],
+  );
+
   // Create main tool bar
   new L.Toolbar2.Control({
     position: "topleft",
-    actions: [aboutPage, laser_view, control_laser, control_laser_width],
+    actions: [
+      aboutPage,
+      laser_view,
+      control_laser,
+      control_laser_width,
+      undo_button,
+      redo_button,
+      control_history,
+    ],
   }).addTo(map);
 
   // Initialize sub tool bars as hidden
@@ -559,6 +791,10 @@ window.addEventListener("DOMContentLoaded", function () {
     document.getElementsByClassName("ctrl_slider")[0].parentElement
       .parentElement;
   sliderSubBarHTML.classList.toggle("hidden");
+  let historySubBarHTML =
+    document.getElementsByClassName("history_slider")[0].parentElement
+      .parentElement;
+  historySubBarHTML.classList.toggle("hidden");
 
   // Initialize display of view state
   for (const item of ["view_clouds", "view_heat", "view_wind"]) {
@@ -579,18 +815,7 @@ window.addEventListener("DOMContentLoaded", function () {
     }
   }
 
-  setTimeout(function () {
-    let bounds = map.getBounds();
-    let overscan = Math.abs(bounds.getNorth() - bounds.getSouth()) * (1 / 10);
-    update_viewport(
-      rect(
-        bounds.getNorth() + overscan,
-        bounds.getWest() - overscan,
-        bounds.getSouth() - overscan,
-        bounds.getEast() + overscan,
-      ),
-    );
-  }, 500);
+  setTimeout(sendViewport, 500);
 
   init();
 });
@@ -30,7 +30,26 @@ extern "C" {
     fn alert(s: &str);
 
     #[wasm_bindgen(js_namespace = document)]
-    fn update_map(data: Vec<Pixel>, width: u32, area: Rect);
+    fn update_map(viewport_id: u32, data: Vec<Pixel>, width: u32, area: Rect);
+
+    #[wasm_bindgen(js_namespace = document)]
+    fn update_history(
+        viewport_id: u32,
+        tick: f64,
+        timestamp_ms: f64,
+        data: Vec<Pixel>,
+        width: u32,
+        area: Rect,
+    );
+
+    #[wasm_bindgen(js_namespace = document)]
+    fn update_presence(
+        client_id: u64,
+        cursor: LatLong,
+        tool: Option<ModificationType>,
+        painting: bool,
+        brush_size_degrees: f64,
+    );
 
     #[wasm_bindgen(js_namespace = console)]
     fn log(s: &str);
@@ -41,8 +60,23 @@ pub fn greet() {}
 
 static CLIENT_ID: OnceLock<u64> = OnceLock::new();
 
+/// Largest snapshot the server said it would send; anything bigger is rejected.
+static MAX_SNAPSHOT_PIXELS: OnceLock<u32> = OnceLock::new();
+
+/// What the server said this client may do.
+static ROLE: OnceLock<Role> = OnceLock::new();
+
+/// Whether the server lets this client paint; modifications from viewers are dropped anyway.
+#[wasm_bindgen]
+pub fn can_paint() -> bool {
+    ROLE.get().is_some_and(|role| *role != Role::Viewer)
+}
+
 #[wasm_bindgen]
 pub fn do_changes(points: Vec<LatLong>, brush_size_degrees: f64, mode: ModificationType) {
+    if !can_paint() {
+        return;
+    }
     send_packet(Packet::Modification {
         tpe: mode,
         points,
@@ -52,14 +86,111 @@ pub fn do_changes(points: Vec<LatLong>, brush_size_degrees: f64, mode: Modificat
 }
 
 #[wasm_bindgen]
-pub fn update_viewport(rect: Rect) {
-    console_log!("rect: {rect:?}");
+pub fn undo() {
+    if !can_paint() {
+        return;
+    }
+    send_packet(Packet::Undo {
+        client_id: *CLIENT_ID.get().unwrap(),
+    })
+}
+
+#[wasm_bindgen]
+pub fn redo() {
+    if !can_paint() {
+        return;
+    }
+    send_packet(Packet::Redo {
+        client_id: *CLIENT_ID.get().unwrap(),
+    })
+}
+
+#[wasm_bindgen]
+pub fn update_viewport(
+    viewport_id: u32,
+    rect: Rect,
+    width: u32,
+    height: u32,
+    temperature: bool,
+    wind: bool,
+    haze: bool,
+) {
+    console_log!("rect {viewport_id}: {rect:?} at {width}x{height}");
+
+    let mut layers = Vec::new();
+    if temperature {
+        layers.push(Layer::Temperature);
+    }
+    if wind {
+        layers.extend([Layer::WindX, Layer::WindY]);
+    }
+    if haze {
+        layers.push(Layer::Haze);
+    }
+
     send_packet(Packet::Viewport {
         area: rect,
         client_id: *CLIENT_ID.get().unwrap(),
+        viewport_id,
+        resolution: Some(Resolution { width, height }),
+        layers,
+    })
+}
+
+/// Asks for the frame of a viewport closest to (but not after) `timestamp_ms` since the Unix epoch.
+#[wasm_bindgen]
+pub fn request_history(viewport_id: u32, timestamp_ms: f64) {
+    send_packet(Packet::HistoryRequest {
+        client_id: *CLIENT_ID.get().unwrap(),
+        viewport_id,
+        at: HistoryPoint::Timestamp(timestamp_ms as u64),
     })
 }
 
+/// Asks for statistics of every field over `area`; the answer is logged to the console.
+#[wasm_bindgen]
+pub fn request_stats(request_id: u32, area: Rect) {
+    send_packet(Packet::StatsRequest {
+        client_id: *CLIENT_ID.get().unwrap(),
+        request_id,
+        area: Region::Rect(area),
+        fields: vec![
+            StatsField::Temperature,
+            StatsField::WindX,
+            StatsField::WindY,
+            StatsField::WindSpeed,
+            StatsField::Haze,
+        ],
+    })
+}
+
+#[wasm_bindgen]
+pub fn close_viewport(viewport_id: u32) {
+    send_packet(Packet::CloseViewport {
+        client_id: *CLIENT_ID.get().unwrap(),
+        viewport_id,
+    })
+}
+
+#[wasm_bindgen]
+pub fn send_presence(
+    cursor: LatLong,
+    tool: Option<ModificationType>,
+    painting: bool,
+    brush_size_degrees: f64,
+) {
+    // presence is best-effort, so don't bother before the server has assigned an ID
+    if let Some(client_id) = CLIENT_ID.get() {
+        send_packet(Packet::Presence {
+            client_id: *client_id,
+            cursor,
+            tool,
+            painting,
+            brush_size_degrees,
+        })
+    }
+}
+
 #[wasm_bindgen]
 pub fn latlong(lat: f64, long: f64) -> LatLong {
     LatLong { lat, long }
@@ -92,7 +223,7 @@ fn send_packet(p: Packet) {
 }
 
 #[wasm_bindgen(getter_with_clone)]
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub enum ModificationType {
     Heat,
     Cool,
@@ -104,6 +235,46 @@ pub enum ModificationType {
 #[derive(Serialize, Deserialize)]
 pub struct PNGFile(pub Vec<u8>);
 
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum Layer {
+    Temperature,
+    WindX,
+    WindY,
+    Haze,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Painter,
+    Admin,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum SnapshotEncoding {
+    Png,
+    Raw { compression: Compression },
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum SnapshotData {
+    Png(PNGFile),
+    Raw {
+        width: u32,
+        height: u32,
+        layers: Vec<Layer>,
+        compression: Compression,
+        planes: Vec<u8>,
+    },
+}
+
 //#[wasm_bindgen(getter_with_clone)]
 #[wasm_bindgen]
 #[derive(Clone, Copy, Serialize, Deserialize, Debug)]
@@ -120,13 +291,69 @@ pub struct Rect {
     pub bottom_right: LatLong,
 }
 
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum Region {
+    Rect(Rect),
+    Polygon(Vec<LatLong>),
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub enum StatsField {
+    Temperature,
+    WindX,
+    WindY,
+    WindSpeed,
+    Haze,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FieldStats {
+    pub field: StatsField,
+    pub unit: String,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub histogram: Vec<f64>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub enum HistoryPoint {
+    Tick(u64),
+    Timestamp(u64),
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum Packet {
     AssignId {
         client_id: u64,
+        max_snapshot_pixels: u32,
+        role: Role,
     },
     Snapshot {
-        data: PNGFile,
+        viewport_id: u32,
+        tick: u64,
+        data: SnapshotData,
+        location: Rect,
+    },
+    HistoryRequest {
+        client_id: u64,
+        viewport_id: u32,
+        at: HistoryPoint,
+    },
+    HistorySnapshot {
+        viewport_id: u32,
+        tick: u64,
+        timestamp_ms: u64,
+        oldest_tick: u64,
+        newest_tick: u64,
+        data: SnapshotData,
         location: Rect,
     },
     Modification {
@@ -135,9 +362,49 @@ pub enum Packet {
         brush_size_degrees: f64,
         client_id: u64,
     },
+    ModificationRejected {
+        points: Vec<LatLong>,
+        zones: Vec<String>,
+    },
+    Undo {
+        client_id: u64,
+    },
+    Redo {
+        client_id: u64,
+    },
     Viewport {
         area: Rect,
         client_id: u64,
+        viewport_id: u32,
+        resolution: Option<Resolution>,
+        layers: Vec<Layer>,
+    },
+    CloseViewport {
+        client_id: u64,
+        viewport_id: u32,
+    },
+    StatsRequest {
+        client_id: u64,
+        request_id: u32,
+        area: Region,
+        fields: Vec<StatsField>,
+    },
+    Stats {
+        request_id: u32,
+        tick: u64,
+        cells: u64,
+        fields: Vec<FieldStats>,
+    },
+    Handshake {
+        client_id: u64,
+        encoding: SnapshotEncoding,
+    },
+    Presence {
+        client_id: u64,
+        cursor: LatLong,
+        tool: Option<ModificationType>,
+        painting: bool,
+        brush_size_degrees: f64,
     },
 }
 
@@ -146,50 +413,97 @@ fn handle_packet(pack: Vec<u8>) -> Option<()> {
     let p = Packet::deserialize(r).ok()?;
 
     match p {
-        Packet::Snapshot { data, location } => {
-            console_log!("got snapshot, {} bytes", data.0.len());
-            let img = match ImageReader::with_format(Cursor::new(data.0), image::ImageFormat::Png)
-                .decode()
-            {
-                Ok(v) => v,
-                Err(e) => {
-                    console_log!("error: {e:?}");
-                    return None;
-                }
-            };
-            console_log!("decoded");
-            if img.color() != ColorType::Rgba8 || img.width() * img.height() > 8192 {
-                console_log!("bad size or color depth");
-                return None;
-            }
-
-            let im = img.as_rgba8().unwrap();
-            console_log!("processing");
-
-            let out = im
-                .pixels()
-                .map(|x| Pixel {
-                    temp: x.0[0],
-                    haze: x.0[3],
-                    wind_x: x.0[1],
-                    wind_y: x.0[2],
-                })
-                .collect();
+        Packet::Snapshot {
+            viewport_id,
+            data,
+            location,
+            ..
+        } => {
+            let (out, width) = decode_snapshot(data)?;
 
             console_log!(
                 "calling update_map im dimensions = {} {}",
-                im.width(),
-                im.height()
+                width,
+                out.len() as u32 / width.max(1)
+            );
+            update_map(viewport_id, out, width, location);
+        }
+        Packet::HistorySnapshot {
+            viewport_id,
+            tick,
+            timestamp_ms,
+            oldest_tick,
+            newest_tick,
+            data,
+            location,
+        } => {
+            let (out, width) = decode_snapshot(data)?;
+
+            console_log!("showing history frame at tick {tick} ({oldest_tick}..={newest_tick})");
+            update_history(
+                viewport_id,
+                tick as f64,
+                timestamp_ms as f64,
+                out,
+                width,
+                location,
             );
-            update_map(out, im.width(), location);
         }
         // NOTE: this should only happen once from the server
-        Packet::AssignId { client_id } => {
-            console_log!("received client id {client_id}");
+        Packet::AssignId {
+            client_id,
+            max_snapshot_pixels,
+            role,
+        } => {
+            console_log!("received client id {client_id} as {role:?}, snapshots up to {max_snapshot_pixels} px");
             CLIENT_ID.set(client_id).unwrap();
+            MAX_SNAPSHOT_PIXELS.set(max_snapshot_pixels).unwrap();
+            ROLE.set(role).unwrap();
+
+            // raw planes skip the PNG encode/decode on both ends
+            send_packet(Packet::Handshake {
+                client_id,
+                encoding: SnapshotEncoding::Raw {
+                    compression: Compression::Lz4,
+                },
+            });
+        }
+        Packet::ModificationRejected { points, zones } => {
+            console_log!(
+                "{} points of the last stroke fell in protected zones {zones:?}",
+                points.len()
+            );
+        }
+        Packet::Stats {
+            request_id,
+            tick,
+            cells,
+            fields,
+        } => {
+            console_log!("stats {request_id} at tick {tick} over {cells} cells:");
+            for stats in fields {
+                console_log!(
+                    "  {:?}: mean {:.2} {} (std dev {:.2}, {:.2} to {:.2})",
+                    stats.field,
+                    stats.mean,
+                    stats.unit,
+                    stats.std_dev,
+                    stats.min,
+                    stats.max
+                );
+            }
+        }
+        Packet::Presence {
+            client_id,
+            cursor,
+            tool,
+            painting,
+            brush_size_degrees,
+        } => {
+            update_presence(client_id, cursor, tool, painting, brush_size_degrees);
         }
         // other packet types are ignored by the client
-        Packet::Viewport { area, client_id } => {
+        Packet::Viewport { .. } | Packet::CloseViewport { .. } | Packet::HistoryRequest { .. } => {
             console_log!("ignoring viewport packet")
         }
         _ => {
@@ -200,6 +514,108 @@ fn handle_packet(pack: Vec<u8>) -> Option<()> {
     Some(())
 }
 
+fn decode_snapshot(data: SnapshotData) -> Option<(Vec<Pixel>, u32)> {
+    match data {
+        SnapshotData::Png(png) => decode_png_snapshot(png),
+        SnapshotData::Raw {
+            width,
+            height,
+            layers,
+            compression,
+            planes,
+        } => decode_raw_snapshot(width, height, &layers, compression, planes),
+    }
+}
+
+fn max_snapshot_pixels() -> u32 {
+    MAX_SNAPSHOT_PIXELS.get().copied().unwrap_or(0)
+}
+
+fn decode_png_snapshot(data: PNGFile) -> Option<(Vec<Pixel>, u32)> {
+    console_log!("got png snapshot, {} bytes", data.0.len());
+    let img = match ImageReader::with_format(Cursor::new(data.0), image::ImageFormat::Png).decode()
+    {
+        Ok(v) => v,
+        Err(e) => {
+            console_log!("error: {e:?}");
+            return None;
+        }
+    };
+    console_log!("decoded");
+    if img.color() != ColorType::Rgba8 || img.width() * img.height() > max_snapshot_pixels() {
+        console_log!("bad size or color depth");
+        return None;
+    }
+
+    let im = img.as_rgba8().unwrap();
+    console_log!("processing");
+
+    let out = im
+        .pixels()
+        .map(|x| Pixel {
+            temp: x.0[0],
+            haze: x.0[3],
+            wind_x: x.0[1],
+            wind_y: x.0[2],
+        })
+        .collect();
+
+    Some((out, im.width()))
+}
+
+fn decode_raw_snapshot(
+    width: u32,
+    height: u32,
+    layers: &[Layer],
+    compression: Compression,
+    planes: Vec<u8>,
+) -> Option<(Vec<Pixel>, u32)> {
+    console_log!("got raw snapshot, {} bytes", planes.len());
+    let pixel_count = width as usize * height as usize;
+    if pixel_count > max_snapshot_pixels() as usize {
+        console_log!("bad size");
+        return None;
+    }
+
+    let planes = match compression {
+        Compression::None => planes,
+        Compression::Lz4 => match lz4_flex::decompress_size_prepended(&planes) {
+            Ok(v) => v,
+            Err(e) => {
+                console_log!("error: {e:?}");
+                return None;
+            }
+        },
+    };
+    if planes.len() != pixel_count * layers.len() {
+        console_log!("plane size mismatch");
+        return None;
+    }
+
+    // layers that weren't sent are left zeroed
+    let mut out = vec![
+        Pixel {
+            temp: 0,
+            haze: 0,
+            wind_x: 0,
+            wind_y: 0,
+        };
+        pixel_count
+    ];
+    for (layer, plane) in layers.iter().zip(planes.chunks_exact(pixel_count)) {
+        for (pixel, value) in out.iter_mut().zip(plane) {
+            match layer {
+                Layer::Temperature => pixel.temp = *value,
+                Layer::WindX => pixel.wind_x = *value,
+                Layer::WindY => pixel.wind_y = *value,
+                Layer::Haze => pixel.haze = *value,
+            }
+        }
+    }
+
+    Some((out, width))
+}
+
 #[derive(Clone)]
 struct WS {
     sock: WebSocket,
@@ -211,7 +627,11 @@ static SOCK: Mutex<Option<WS>> = Mutex::new(None);
 
 #[wasm_bindgen(start)]
 fn start() -> Result<(), JsValue> {
-    let ws = WebSocket::new("/sync")?;
+    // pass the page's query string on, so a `?token=...` link signs the client in
+    let search = web_sys::window()
+        .and_then(|window| window.location().search().ok())
+        .unwrap_or_default();
+    let ws = WebSocket::new(&format!("/sync{search}"))?;
     ws.set_binary_type(web_sys::BinaryType::Blob);
 
     *SOCK.lock().unwrap() = Some(WS { sock: ws.clone() });